@@ -0,0 +1,371 @@
+use crate::oracle::Oracle;
+use crate::rng::Rng;
+use crate::{arbitrage_amount, AdvanceBlock, Deposit, State, Swap, Token, Transition, TransitionError, User};
+
+// Where a submitted transaction goes in the block being built, relative to
+// the pending transactions observed by the agent
+pub enum Slot {
+    End,
+    Before(usize),
+    After(usize),
+}
+
+pub trait Agent {
+    fn user(&self) -> &User;
+
+    // Transactions submitted to the block being built, given the state at the
+    // start of the block, the transactions already pending in it and the
+    // external price feed
+    fn act(&mut self, s: &State, pending: &[Box<dyn Transition>], feed: &Oracle, rng: &mut Rng)
+        -> Vec<(Slot, Box<dyn Transition>)>;
+}
+
+// The state reached by applying the pending transactions, skipping the failing ones
+pub fn simulate(s: &State, pending: &[Box<dyn Transition>]) -> State {
+    let mut s = s.clone();
+    for t in pending {
+        if let Ok(post) = t.apply(&s) {
+            s = post;
+        }
+    }
+    s
+}
+
+// Swaps a random fraction of its balance in a random direction
+pub struct NoiseTrader {
+    user: User,
+    t0: Token,
+    t1: Token,
+    // probability of trading in a block
+    activity: f64,
+    max_fraction: f64,
+}
+
+impl NoiseTrader {
+    pub fn new(user: &User, t0: &Token, t1: &Token, activity: f64, max_fraction: f64) -> Self {
+        assert!(max_fraction > 0.0 && max_fraction <= 1.0);
+        NoiseTrader { user: user.clone(), t0: t0.clone(), t1: t1.clone(), activity, max_fraction }
+    }
+}
+
+impl Agent for NoiseTrader {
+    fn user(&self) -> &User {
+        &self.user
+    }
+
+    fn act(&mut self, s: &State, _pending: &[Box<dyn Transition>], _feed: &Oracle, rng: &mut Rng)
+        -> Vec<(Slot, Box<dyn Transition>)> {
+        if !rng.chance(self.activity) {
+            return Vec::new();
+        }
+        let (tin, tout) = if rng.chance(0.5) { (&self.t0, &self.t1) } else { (&self.t1, &self.t0) };
+        let x = s.get_balance(&self.user, tin) * rng.range(0.0, self.max_fraction);
+        if x <= 0.0 {
+            return Vec::new();
+        }
+        vec![(Slot::End, Box::new(Swap::new(&self.user, tin, tout, x)))]
+    }
+}
+
+// Realigns the pool price with the external feed at the end of the block,
+// as far as it is profitable given the pool fee
+pub struct Arbitrageur {
+    user: User,
+    t0: Token,
+    t1: Token,
+}
+
+impl Arbitrageur {
+    pub fn new(user: &User, t0: &Token, t1: &Token) -> Self {
+        Arbitrageur { user: user.clone(), t0: t0.clone(), t1: t1.clone() }
+    }
+}
+
+impl Agent for Arbitrageur {
+    fn user(&self) -> &User {
+        &self.user
+    }
+
+    fn act(&mut self, s: &State, pending: &[Box<dyn Transition>], feed: &Oracle, _rng: &mut Rng)
+        -> Vec<(Slot, Box<dyn Transition>)> {
+        let s = simulate(s, pending);
+        let r0 = s.get_reserves(&self.t0, &self.t1);
+        let r1 = s.get_reserves(&self.t1, &self.t0);
+        if r0 == 0.0 || r1 == 0.0 {
+            return Vec::new();
+        }
+        let p0 = feed.price(&s, &self.t0);
+        let p1 = feed.price(&s, &self.t1);
+        let fee = s.get_fee(&self.t0, &self.t1);
+        let w0 = arbitrage_amount(p0, p1, r0, r1, fee);
+        let (tin, tout, x) = if w0 > 0.0 {
+            (&self.t0, &self.t1, w0)
+        } else {
+            (&self.t1, &self.t0, arbitrage_amount(p1, p0, r1, r0, fee))
+        };
+        let x = x.min(s.get_balance(&self.user, tin));
+        if x <= 1e-9 {
+            return Vec::new();
+        }
+        vec![(Slot::End, Box::new(Swap::new(&self.user, tin, tout, x)))]
+    }
+}
+
+// Deposits once at the pool ratio, up to the given amounts, and then holds
+pub struct PassiveLP {
+    user: User,
+    t0: Token,
+    v0: f64,
+    t1: Token,
+    v1: f64,
+    done: bool,
+}
+
+impl PassiveLP {
+    pub fn new(user: &User, v0: f64, t0: &Token, v1: f64, t1: &Token) -> Self {
+        PassiveLP { user: user.clone(), t0: t0.clone(), v0, t1: t1.clone(), v1, done: false }
+    }
+}
+
+impl Agent for PassiveLP {
+    fn user(&self) -> &User {
+        &self.user
+    }
+
+    fn act(&mut self, s: &State, pending: &[Box<dyn Transition>], _feed: &Oracle, _rng: &mut Rng)
+        -> Vec<(Slot, Box<dyn Transition>)> {
+        if self.done {
+            return Vec::new();
+        }
+        self.done = true;
+        let s = simulate(s, pending);
+        let r0 = s.get_reserves(&self.t0, &self.t1);
+        let r1 = s.get_reserves(&self.t1, &self.t0);
+        let (v0, v1) = if r0 > 0.0 && r1 > 0.0 {
+            let k = (self.v0 / r0).min(self.v1 / r1);
+            (k * r0, k * r1)
+        } else {
+            (self.v0, self.v1)
+        };
+        vec![(Slot::End, Box::new(Deposit::new(&self.user, v0, &self.t0, v1, &self.t1)))]
+    }
+}
+
+// Sandwiches the largest pending swap of another user: swaps in the same
+// direction right before it, and swaps the proceeds back right after it
+pub struct SandwichBot {
+    user: User,
+    // largest front-running swap, in units of the victim's input token
+    budget: f64,
+}
+
+impl SandwichBot {
+    pub fn new(user: &User, budget: f64) -> Self {
+        SandwichBot { user: user.clone(), budget }
+    }
+
+    // Profit (in the input token) and back-running amount of a front-running swap of x
+    fn sandwich(&self, before: &State, victim: &Swap, x: f64) -> Option<(f64, f64)> {
+        let front = Swap::new(&self.user, &victim.tin, &victim.tout, x);
+        let s1 = front.apply(before).ok()?;
+        let out = s1.get_balance(&self.user, &victim.tout) - before.get_balance(&self.user, &victim.tout);
+        let s2 = victim.apply(&s1).ok()?;
+        let back = Swap::new(&self.user, &victim.tout, &victim.tin, out);
+        let s3 = back.apply(&s2).ok()?;
+        let profit = s3.get_balance(&self.user, &victim.tin) - before.get_balance(&self.user, &victim.tin);
+        Some((profit, out))
+    }
+}
+
+impl Agent for SandwichBot {
+    fn user(&self) -> &User {
+        &self.user
+    }
+
+    fn act(&mut self, s: &State, pending: &[Box<dyn Transition>], _feed: &Oracle, _rng: &mut Rng)
+        -> Vec<(Slot, Box<dyn Transition>)> {
+        let victim = pending.iter().enumerate()
+            .filter_map(|(i, t)| t.downcast_ref::<Swap>().map(|swap| (i, swap)))
+            .filter(|(_, swap)| swap.sender != self.user)
+            .max_by(|(_, a), (_, b)| a.x.total_cmp(&b.x));
+        let (i, victim) = match victim {
+            Some(v) => v,
+            None => return Vec::new(),
+        };
+        let before = simulate(s, &pending[..i]);
+        let budget = self.budget.min(before.get_balance(&self.user, &victim.tin));
+        // grid search of the front-running amount
+        let best = (1..=20).map(|k| budget * k as f64 / 20.0)
+            .filter(|x| *x > 0.0)
+            .filter_map(|x| self.sandwich(&before, victim, x).map(|(profit, out)| (profit, x, out)))
+            .max_by(|a, b| a.0.total_cmp(&b.0));
+        match best {
+            Some((profit, x, out)) if profit > 0.0 => vec![
+                (Slot::Before(i), Box::new(Swap::new(&self.user, &victim.tin, &victim.tout, x)) as Box<dyn Transition>),
+                (Slot::After(i), Box::new(Swap::new(&self.user, &victim.tout, &victim.tin, out))),
+            ],
+            _ => Vec::new(),
+        }
+    }
+}
+
+// A transaction of a simulated block, with the state it led to
+pub struct Step {
+    pub block: u64,
+    pub label: String,
+    pub error: Option<TransitionError>,
+    pub state: State,
+}
+
+pub struct Report {
+    pub trace: Vec<Step>,
+    // net wealth of the user of each agent, initially and after every block
+    pub wealth: Vec<(User, Vec<f64>)>,
+}
+
+// Runs agents block by block: each agent in turn observes the pending
+// transactions and submits its own, then the block is executed
+pub struct Simulation {
+    pub agents: Vec<Box<dyn Agent>>,
+    pub feed: Oracle,
+    pub rng: Rng,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, feed: Oracle) -> Self {
+        Simulation { agents: Vec::new(), feed, rng: Rng::new(seed) }
+    }
+
+    pub fn add_agent<A: Agent + 'static>(&mut self, agent: A) {
+        self.agents.push(Box::new(agent));
+    }
+
+    fn wealth(&self, s: &State, user: &User) -> f64 {
+        s.net_wealth_user(user, &|s, t| self.feed.price(s, t))
+    }
+
+    pub fn run(&mut self, s0: &State, blocks: u64) -> Report {
+        let mut s = s0.clone();
+        let mut trace = Vec::new();
+        let mut wealth: Vec<(User, Vec<f64>)> = self.agents.iter()
+            .map(|a| (a.user().clone(), vec![self.wealth(&s, a.user())]))
+            .collect();
+        for _ in 0..blocks {
+            s = self.step(&s, &mut trace);
+            for (user, curve) in wealth.iter_mut() {
+                curve.push(self.wealth(&s, user));
+            }
+        }
+        Report { trace, wealth }
+    }
+
+    // Builds and executes one block, recording its transactions in the trace
+    pub fn step(&mut self, s: &State, trace: &mut Vec<Step>) -> State {
+        let mut pending: Vec<Box<dyn Transition>> = Vec::new();
+        for agent in self.agents.iter_mut() {
+            let submitted = agent.act(s, &pending, &self.feed, &mut self.rng);
+            pending = place(pending, submitted);
+        }
+        let mut s = s.clone();
+        for t in pending {
+            match t.apply(&s) {
+                Ok(post) => {
+                    trace.push(Step { block: s.block, label: t.to_string(), error: None, state: post.clone() });
+                    s = post;
+                }
+                Err(e) => trace.push(Step { block: s.block, label: t.to_string(), error: Some(e), state: s.clone() }),
+            }
+        }
+        AdvanceBlock::new(1).apply(&s).unwrap()
+    }
+}
+
+// The pending transactions with the submitted ones in their slots
+pub fn place(pending: Vec<Box<dyn Transition>>, submitted: Vec<(Slot, Box<dyn Transition>)>) -> Vec<Box<dyn Transition>> {
+    let mut before: Vec<Vec<Box<dyn Transition>>> = pending.iter().map(|_| Vec::new()).collect();
+    let mut after: Vec<Vec<Box<dyn Transition>>> = pending.iter().map(|_| Vec::new()).collect();
+    let mut end = Vec::new();
+    for (slot, t) in submitted {
+        match slot {
+            Slot::End => end.push(t),
+            Slot::Before(i) => before[i].push(t),
+            Slot::After(i) => after[i].push(t),
+        }
+    }
+    let mut placed = Vec::new();
+    for (i, t) in pending.into_iter().enumerate() {
+        placed.append(&mut before[i]);
+        placed.push(t);
+        placed.append(&mut after[i]);
+    }
+    placed.append(&mut end);
+    placed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreatePool, Curve};
+
+    fn setup() -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 1000.0);
+        s.set_balance(&o, &t1, 1000.0);
+        for name in ["N", "R", "M"] {
+            s.set_balance(&User::new(name), &t0, 100.0);
+            s.set_balance(&User::new(name), &t1, 100.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s).unwrap();
+        let s = Deposit::new(&o, 1000.0, &t0, 1000.0, &t1).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    fn feed(t0: &Token, t1: &Token) -> Oracle {
+        Oracle::Manual(vec![(t0.clone(), 1000.0), (t1.clone(), 1000.0)])
+    }
+
+    #[test]
+    fn runs_are_reproducible_from_the_seed() {
+        let (s, t0, t1) = setup();
+        let run = |seed| {
+            let mut sim = Simulation::new(seed, feed(&t0, &t1));
+            sim.add_agent(NoiseTrader::new(&User::new("N"), &t0, &t1, 0.8, 0.5));
+            let report = sim.run(&s, 10);
+            report.trace.iter().map(|st| st.label.clone()).collect::<Vec<_>>()
+        };
+        assert_eq!(run(1), run(1));
+        assert_ne!(run(1), run(2));
+    }
+
+    #[test]
+    fn arbitrageur_realigns_the_pool_with_the_feed() {
+        let (s, t0, t1) = setup();
+        let mut sim = Simulation::new(0, feed(&t0, &t1));
+        sim.feed.set_price(&t0, 1100.0).unwrap();
+        sim.add_agent(Arbitrageur::new(&User::new("R"), &t0, &t1));
+        let report = sim.run(&s, 1);
+        let s = &report.trace.last().unwrap().state;
+        let price = s.get_reserves(&t1, &t0) / s.get_reserves(&t0, &t1);
+        assert!((price - 1.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sandwich_bot_wraps_the_victim() {
+        let (s, t0, t1) = setup();
+        let victim = Swap::new(&User::new("N"), &t0, &t1, 100.0);
+        let pending: Vec<Box<dyn Transition>> = vec![Box::new(victim)];
+        let mut bot = SandwichBot::new(&User::new("M"), 100.0);
+        let submitted = bot.act(&s, &pending, &feed(&t0, &t1), &mut Rng::new(0));
+        let block = place(pending, submitted);
+        let labels: Vec<String> = block.iter().map(|t| t.to_string()).collect();
+        assert_eq!(labels.len(), 3);
+        assert!(labels[0].starts_with("M:swap(t0,t1,"));
+        assert_eq!(labels[1], "N:swap(t0,t1,100)");
+        assert!(labels[2].starts_with("M:swap(t1,t0,"));
+        let post = simulate(&s, &block);
+        assert!(post.get_balance(&User::new("M"), &t0) > 100.0);
+    }
+}
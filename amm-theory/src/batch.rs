@@ -0,0 +1,274 @@
+use crate::{order_tokens, Curve, State, Swap, Token, Transition, TransitionError, User};
+
+// Frequent batch auction: instead of being applied one after the other, the
+// swaps on a pair between two other transitions of a block are cleared
+// together at a single price. Opposing orders are netted first, and only the
+// imbalance trades against the curve of the pool, so the order of these swaps
+// does not matter and no one trades at a better price than the pool offers.
+
+// Price of t0 in units of t1 clearing the swaps on the pool of t0 and t1:
+// every swap pays the fee on its input, and the net of the remaining inputs
+// trades against the curve at its average price
+pub fn clearing_price(s: &State, t0: &Token, t1: &Token, swaps: &[&Swap]) -> Option<f64> {
+    let amm = s.get_amm(t0, t1)?;
+    let (t0, t1) = (&amm.t0, &amm.t1);
+    let g = 1.0 - amm.fee;
+    let x: f64 = swaps.iter().filter(|sw| sw.tin == *t0).map(|sw| sw.x * g).sum();
+    let y: f64 = swaps.iter().filter(|sw| sw.tin == *t1).map(|sw| sw.x * g).sum();
+    let (r0, r1) = (amm.r0, amm.r1);
+    Some(match amm.curve {
+        Curve::ConstantSum => 1.0,
+        // the pool receives d of the net input and pays its constant-product output
+        Curve::ConstantProduct if x * r1 >= y * r0 => r1 / (r0 + (x * r1 - y * r0) / (r1 + y)),
+        Curve::ConstantProduct => (r1 + (y * r0 - x * r1) / (r0 + x)) / r0,
+    })
+}
+
+// Clears the swaps, all on the same pair, at their clearing price
+pub fn clear(s: &State, swaps: &[&Swap]) -> Result<State, TransitionError> {
+    let first = match swaps.first() {
+        Some(first) => first,
+        None => return Ok(s.clone()),
+    };
+    let mut post = s.clone();
+    if post.get_amm(&first.tin, &first.tout).is_none() {
+        return Err(TransitionError::UnknownPool);
+    }
+    post.execute_virtual_orders(&first.tin, &first.tout);
+    let amm = post.get_amm(&first.tin, &first.tout).unwrap();
+    let (t0, t1) = (amm.t0.clone(), amm.t1.clone());
+    let g = 1.0 - amm.fee;
+    let (mut r0, mut r1) = (amm.r0, amm.r1);
+    let price = clearing_price(&post, &t0, &t1, swaps).unwrap();
+    // the side that only trades against the opposing orders gets the price of
+    // the pool, no better, and the rest of what the other side pays is left
+    // in the pool
+    let spot = match amm.curve {
+        Curve::ConstantSum => 1.0,
+        Curve::ConstantProduct => r1 / r0,
+    };
+
+    for sw in swaps {
+        let out = if sw.tin == t0 { sw.x * g * price.min(spot) } else { sw.x * g / price.max(spot) };
+        let in_balance = post.get_balance(&sw.sender, &sw.tin);
+        post.set_balance(&sw.sender, &sw.tin, in_balance - sw.x);
+        let out_balance = post.get_balance(&sw.sender, &sw.tout);
+        post.set_balance(&sw.sender, &sw.tout, out_balance + out);
+        let cut = match post.protocol_fee_cut(&sw.tin, &sw.tout, sw.x) {
+            Some((treasury, cut)) => {
+                let balance = post.get_balance(&treasury, &sw.tin);
+                post.set_balance(&treasury, &sw.tin, balance + cut);
+                cut
+            }
+            None => 0.0,
+        };
+        if sw.tin == t0 {
+            r0 += sw.x - cut;
+            r1 -= out;
+        } else {
+            r1 += sw.x - cut;
+            r0 -= out;
+        }
+    }
+    if r0 <= 0.0 || r1 <= 0.0 {
+        return Err(TransitionError::InsufficientReserves);
+    }
+    post.set_reserve(&t0, r0, &t1, r1);
+    post.record_price_move(&t0, &t1);
+    Ok(post)
+}
+
+// Clears the pending swaps, the swaps on each pair together, in the order of
+// the first swap on each pair
+fn clear_pending(s: &State, pending: &mut Vec<&Swap>) -> Result<State, TransitionError> {
+    let mut s = s.clone();
+    while let Some(first) = pending.first() {
+        let pair = order_tokens(&first.tin, &first.tout);
+        let (swaps, rest): (Vec<&Swap>, Vec<&Swap>) = pending.iter()
+            .partition(|sw| order_tokens(&sw.tin, &sw.tout) == pair);
+        s = clear(&s, &swaps)?;
+        *pending = rest;
+    }
+    Ok(s)
+}
+
+// Executes a block in batch mode: the swaps between two other transitions are
+// cleared together, pair by pair, and the other transitions are applied in
+// order, so no swap moves across a deposit or a redeem
+pub fn execute_batch(s: &State, block: &[Box<dyn Transition>]) -> Result<State, TransitionError> {
+    let mut s = s.clone();
+    let mut pending: Vec<&Swap> = Vec::new();
+    for t in block {
+        match t.downcast_ref::<Swap>() {
+            Some(swap) => pending.push(swap),
+            None => {
+                s = clear_pending(&s, &mut pending)?;
+                s = t.apply(&s)?;
+            }
+        }
+    }
+    clear_pending(&s, &mut pending)
+}
+
+pub fn execute_sequential(s: &State, block: &[Box<dyn Transition>]) -> Result<State, TransitionError> {
+    let mut s = s.clone();
+    for t in block {
+        s = t.apply(&s)?;
+    }
+    Ok(s)
+}
+
+// Change of the net wealth of a user executing a block in both modes
+pub struct Outcome {
+    pub user: User,
+    pub sequential: f64,
+    pub batch: f64,
+}
+
+pub fn compare(s: &State, block: &[Box<dyn Transition>], f: &dyn Fn(&State, &Token) -> f64)
+    -> Result<Vec<Outcome>, TransitionError> {
+    let sequential = execute_sequential(s, block)?;
+    let batch = execute_batch(s, block)?;
+    Ok(s.wallets.iter()
+        .map(|w| {
+            let before = s.net_wealth_user(&w.user, f);
+            Outcome {
+                user: w.user.clone(),
+                sequential: sequential.net_wealth_user(&w.user, f) - before,
+                batch: batch.net_wealth_user(&w.user, f) - before,
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_mini_transaction, price_oracle, CreatePool, Deposit, Redeem, SFr0};
+
+    fn setup(fee: f64) -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        for name in ["A", "B", "M"] {
+            s.set_balance(&User::new(name), &t0, 50.0);
+            s.set_balance(&User::new(name), &t1, 50.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, fee).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    #[test]
+    fn a_single_swap_clears_as_in_sequence() {
+        let (s, t0, t1) = setup(0.003);
+        let block: Vec<Box<dyn Transition>> = vec![Box::new(Swap::new(&User::new("A"), &t0, &t1, 20.0))];
+        let sequential = execute_sequential(&s, &block).unwrap();
+        let batch = execute_batch(&s, &block).unwrap();
+        assert!((sequential.get_reserves(&t0, &t1) - batch.get_reserves(&t0, &t1)).abs() < 1e-9);
+        assert!((sequential.get_reserves(&t1, &t0) - batch.get_reserves(&t1, &t0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn opposing_orders_are_netted() {
+        let (s, t0, t1) = setup(0.0);
+        let block: Vec<Box<dyn Transition>> = vec![
+            Box::new(Swap::new(&User::new("A"), &t0, &t1, 10.0)),
+            Box::new(Swap::new(&User::new("B"), &t1, &t0, 10.0)),
+        ];
+        let post = execute_batch(&s, &block).unwrap();
+        // matched at the pool price, without touching the pool
+        assert_eq!(post.get_reserves(&t0, &t1), 100.0);
+        assert_eq!(post.get_balance(&User::new("A"), &t1), 60.0);
+        assert!((post.net_wealth(&price_oracle) - s.net_wealth(&price_oracle)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sandwich_does_not_pay_in_a_batch() {
+        let (s, t0, t1) = setup(0.003);
+        let m = User::new("M");
+        let front = Swap::new(&m, &t0, &t1, 20.0);
+        let out = front.apply(&s).unwrap().get_balance(&m, &t1) - 50.0;
+        let block: Vec<Box<dyn Transition>> = vec![
+            Box::new(front),
+            Box::new(Swap::new(&User::new("A"), &t0, &t1, 30.0)),
+            Box::new(Swap::new(&m, &t1, &t0, out)),
+        ];
+        let outcomes = compare(&s, &block, &price_oracle).unwrap();
+        let m = outcomes.iter().find(|o| o.user == m).unwrap();
+        let a = outcomes.iter().find(|o| o.user == User::new("A")).unwrap();
+        assert!(m.batch < 0.0 && m.sequential > m.batch);
+        assert!(a.batch > a.sequential);
+    }
+
+    // The blocks of the mev0 and mev1 scenarios
+    fn mev_blocks() -> Vec<(State, Vec<Box<dyn Transition>>)> {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let (o, a, m) = (User::new("O"), User::new("A"), User::new("M"));
+        let mut blocks = Vec::new();
+
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        s.set_balance(&a, &t0, 20.0);
+        s.set_balance(&m, &t0, 5.9);
+        s.set_balance(&m, &t1, 20.6);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s).unwrap();
+        let block: Vec<Box<dyn Transition>> = vec![
+            Box::new(Deposit::new(&o, 100.0, &t0, 100.0, &t1)),
+            Box::new(Swap::new(&m, &t0, &t1, SFr0(20.0, 15.0, 100.0, 100.0) - 100.0)),
+            Box::new(Swap::new(&a, &t0, &t1, 20.0)),
+            Box::new(Swap::new(&m, &t1, &t0, price_mini_transaction(1000.0, 1000.0, 79.4, 125.9))),
+        ];
+        blocks.push((s, block));
+
+        let mut s = State::new();
+        for u in [&o, &a, &m] {
+            s.set_balance(u, &t0, 100.0);
+            s.set_balance(u, &t1, 100.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s).unwrap();
+        let block: Vec<Box<dyn Transition>> = vec![
+            Box::new(Deposit::new(&o, 100.0, &t0, 100.0, &t1)),
+            Box::new(Swap::new(&m, &t1, &t0, 100.0 - SFr0(40.0, 35.0, 100.0, 100.0))),
+            Box::new(Swap::new(&a, &t0, &t1, 40.0)),
+            Box::new(Swap::new(&m, &t1, &t0, 38.3)),
+            Box::new(Deposit::new(&a, 30.0, &t0, 40.0, &t1)),
+            Box::new(Swap::new(&m, &t0, &t1, price_mini_transaction(1000.0, 1000.0, 117.0, 155.0))),
+            Box::new(Redeem::new(&a, &t0, &t1, 10.0)),
+        ];
+        blocks.push((s, block));
+        blocks
+    }
+
+    #[test]
+    fn mev_blocks_do_not_pay_in_a_batch() {
+        for (s, block) in mev_blocks() {
+            let outcomes = compare(&s, &block, &price_oracle).unwrap();
+            let m = outcomes.iter().find(|o| o.user == User::new("M")).unwrap();
+            assert!(m.sequential > 0.0);
+            assert!(m.batch <= 1e-6);
+        }
+    }
+
+    #[test]
+    fn swaps_are_not_cleared_across_a_deposit() {
+        let (s, t0, t1) = setup(0.0);
+        let (a, m) = (User::new("A"), User::new("M"));
+        let block: Vec<Box<dyn Transition>> = vec![
+            Box::new(Swap::new(&m, &t0, &t1, 10.0)),
+            Box::new(Deposit::new(&a, 40.0, &t0, 40.0, &t1)),
+            Box::new(Swap::new(&m, &t1, &t0, 5.0)),
+        ];
+        // each swap is alone between the deposit and the ends of the block
+        let batch = execute_batch(&s, &block).unwrap();
+        let sequential = execute_sequential(&s, &block).unwrap();
+        assert!((batch.get_reserves(&t0, &t1) - sequential.get_reserves(&t0, &t1)).abs() < 1e-9);
+        assert!((batch.get_balance(&m, &t0) - sequential.get_balance(&m, &t0)).abs() < 1e-9);
+        assert!((batch.get_balance(&a, &Token::mint(&t0, &t1)) - sequential.get_balance(&a, &Token::mint(&t0, &t1))).abs() < 1e-9);
+    }
+}
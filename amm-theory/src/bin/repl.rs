@@ -0,0 +1,315 @@
+use std::io::{self, BufRead, Write};
+
+use amm_theory::oracle::Oracle;
+use amm_theory::*;
+
+const HELP: &str = "\
+token <t>...                   declare atomic tokens
+user <u>...                    declare users
+balance <u> <v> <t>            set the balance of u in t to v
+pool <t0> <t1> [cp|cs] [fee]   create a pool
+dep <u> <v0> <t0> <v1> <t1>    deposit
+swap <u> <tin> <tout> <x>      swap x units of tin
+rdm <u> <t0> <t1> <v>          redeem v LP tokens
+tick [n]                       advance n blocks
+show [digits]                  print the state
+quote <tin> <tout> <x>         quote a swap, without making it
+price <t> <other> <p>          amount to move the pool price of t to p
+oracle external|spot <t>|manual <t>=<p>...
+wealth                         net wealth of every user under the oracle
+undo                           revert the last change
+save <file>                    save the session as a scenario file
+load <file>                    replay a scenario file
+A:swap(t0,t1,20), ...          any transition in the notation of the paper
+help, quit";
+
+#[derive(Clone)]
+struct Session {
+    tokens: Vec<Token>,
+    users: Vec<User>,
+    state: State,
+    oracle: Oracle,
+    // the commands that changed the session, with the session before each
+    // one, so that they can be undone and saved
+    history: Vec<(String, Session)>,
+    // the files being loaded, innermost last, to reject a file loading itself
+    loading: Vec<std::path::PathBuf>,
+}
+
+impl Session {
+    fn new() -> Self {
+        Session {
+            tokens: Vec::new(),
+            users: Vec::new(),
+            state: State::new(),
+            oracle: Oracle::External(price_oracle),
+            history: Vec::new(),
+            loading: Vec::new(),
+        }
+    }
+
+    fn token(&self, name: &str) -> Result<Token, String> {
+        let t = Token::Atomic(String::from(name));
+        if self.tokens.contains(&t) { Ok(t) } else { Err(format!("unknown token {}", name)) }
+    }
+
+    fn user(&self, name: &str) -> Result<User, String> {
+        let u = User::new(name);
+        if self.users.contains(&u) { Ok(u) } else { Err(format!("unknown user {}", name)) }
+    }
+
+    // Runs a command, returning what it prints
+    fn exec(&mut self, line: &str) -> Result<String, String> {
+        let line = line.split('#').next().unwrap().trim();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let number = |i: usize| -> Result<f64, String> {
+            let w = words.get(i).ok_or("missing argument")?;
+            w.parse().map_err(|_| format!("not a number: {}", w))
+        };
+        let word = |i: usize| -> Result<&str, String> { words.get(i).copied().ok_or(String::from("missing argument")) };
+
+        // the snapshot leaves out the history, which undo restores separately
+        let history = std::mem::take(&mut self.history);
+        let pre = self.clone();
+        self.history = history;
+        let transition: Box<dyn Transition> = match words.first().copied() {
+            None => return Ok(String::new()),
+            Some("help") => return Ok(String::from(HELP)),
+            Some("token") => {
+                for name in &words[1..] {
+                    let t = Token::Atomic(String::from(*name));
+                    if !self.tokens.contains(&t) {
+                        self.tokens.push(t);
+                    }
+                }
+                self.history.push((String::from(line), pre));
+                return Ok(String::new());
+            }
+            Some("user") => {
+                for name in &words[1..] {
+                    let u = User::new(name);
+                    if !self.users.contains(&u) {
+                        self.users.push(u);
+                    }
+                }
+                self.history.push((String::from(line), pre));
+                return Ok(String::new());
+            }
+            Some("balance") => {
+                let (u, v, t) = (self.user(word(1)?)?, number(2)?, self.token(word(3)?)?);
+                self.state.set_balance(&u, &t, v);
+                self.history.push((String::from(line), pre));
+                return Ok(format!("{:.1}", self.state));
+            }
+            Some("pool") => {
+                let (t0, t1) = (self.token(word(1)?)?, self.token(word(2)?)?);
+                let curve = match words.get(3).copied() {
+                    None | Some("cp") => Curve::ConstantProduct,
+                    Some("cs") => Curve::ConstantSum,
+                    Some(c) => return Err(format!("unknown curve {}", c)),
+                };
+                let fee = if words.len() > 4 { number(4)? } else { 0.0 };
+                if t0 == t1 || !(0.0..1.0).contains(&fee) {
+                    return Err(String::from("invalid pool"));
+                }
+                Box::new(CreatePool::new(&t0, &t1, curve, fee))
+            }
+            Some("dep") => {
+                let (u, v0, t0) = (self.user(word(1)?)?, number(2)?, self.token(word(3)?)?);
+                let (v1, t1) = (number(4)?, self.token(word(5)?)?);
+                if v0 <= 0.0 || v1 <= 0.0 {
+                    return Err(String::from("amounts must be positive"));
+                }
+                Box::new(Deposit::new(&u, v0, &t0, v1, &t1))
+            }
+            Some("swap") => {
+                let (u, tin, tout, x) = (self.user(word(1)?)?, self.token(word(2)?)?, self.token(word(3)?)?, number(4)?);
+                Box::new(Swap::new(&u, &tin, &tout, x))
+            }
+            Some("rdm") => {
+                let (u, t0, t1, v) = (self.user(word(1)?)?, self.token(word(2)?)?, self.token(word(3)?)?, number(4)?);
+                if v <= 0.0 {
+                    return Err(String::from("amounts must be positive"));
+                }
+                Box::new(Redeem::new(&u, &t0, &t1, v))
+            }
+            Some("tick") => {
+                let n = if words.len() > 1 { number(1)? as u64 } else { 1 };
+                if n == 0 {
+                    return Err(String::from("blocks must be positive"));
+                }
+                Box::new(AdvanceBlock::new(n))
+            }
+            Some("show") => {
+                let digits = if words.len() > 1 { number(1)? as usize } else { 1 };
+                return Ok(format!("block {}: {:.*}", self.state.block, digits, self.state));
+            }
+            Some("quote") => {
+                let (tin, tout, x) = (self.token(word(1)?)?, self.token(word(2)?)?, number(3)?);
+                let s = &self.state;
+                let out = s.quote_out(&tin, &tout, x).ok_or("no pool")?;
+                let spot = s.spot_price(&tin, &tout).ok_or("empty pool")?;
+                let impact = s.price_impact(&tin, &tout, x).ok_or("amounts must be positive")?;
+                return Ok(format!("out {} {}, spot {} -> {}, impact {:.4}%", out, tout,
+                    spot, s.price_after(&tin, &tout, x).unwrap(), impact));
+            }
+            Some("price") => {
+                let (t, other, p) = (self.token(word(1)?)?, self.token(word(2)?)?, number(3)?);
+                return match self.state.amount_to_price(&t, &other, p) {
+                    Some((tin, x)) => Ok(format!("swap {} {}", x, tin)),
+                    None => Err(String::from("price not reachable")),
+                };
+            }
+            Some("oracle") => {
+                self.oracle = match word(1)? {
+                    "external" => Oracle::External(price_oracle),
+                    "spot" => Oracle::Spot(self.token(word(2)?)?),
+                    "manual" => {
+                        let mut prices = Vec::new();
+                        for w in &words[2..] {
+                            let (t, p) = w.split_once('=').ok_or(format!("expected <t>=<p>: {}", w))?;
+                            prices.push((self.token(t)?, p.parse().map_err(|_| format!("not a number: {}", p))?));
+                        }
+                        Oracle::Manual(prices)
+                    }
+                    o => return Err(format!("unknown oracle {}", o)),
+                };
+                self.history.push((String::from(line), pre));
+                return Ok(String::new());
+            }
+            Some("wealth") => {
+                let f = |s: &State, t: &Token| self.oracle.price(s, t);
+                let mut out: Vec<String> = self.users.iter()
+                    .map(|u| format!("{}\t{}", u, self.state.net_wealth_user(u, &f)))
+                    .collect();
+                out.push(format!("total\t{}", self.state.net_wealth(&f)));
+                return Ok(out.join("\n"));
+            }
+            Some("undo") => {
+                let (command, pre) = self.history.pop().ok_or("nothing to undo")?;
+                let history = std::mem::take(&mut self.history);
+                *self = pre;
+                self.history = history;
+                return Ok(format!("undone: {}", command));
+            }
+            Some("save") => {
+                let path = word(1)?;
+                let text: String = self.history.iter().map(|(command, _)| format!("{}\n", command)).collect();
+                std::fs::write(path, text).map_err(|e| e.to_string())?;
+                return Ok(format!("saved {} commands to {}", self.history.len(), path));
+            }
+            Some("load") => {
+                let path = std::fs::canonicalize(word(1)?).map_err(|e| e.to_string())?;
+                if self.loading.contains(&path) {
+                    return Err(format!("{} loads itself", path.display()));
+                }
+                let text = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+                self.loading.push(path);
+                let mut out = Vec::new();
+                let mut result = Ok(());
+                for (i, command) in text.lines().enumerate() {
+                    match self.exec(command) {
+                        Ok(printed) if printed.is_empty() => {}
+                        Ok(printed) => out.push(printed),
+                        Err(e) => {
+                            result = Err(format!("line {}: {}", i + 1, e));
+                            break;
+                        }
+                    }
+                }
+                self.loading.pop();
+                return result.map(|_| out.join("\n"));
+            }
+            //transitions can also be written in the notation of the paper
+            Some(_) if line.contains('(') => notation::parse_transition(line).map_err(|e| format!("{:?}", e))?,
+            Some(c) => return Err(format!("unknown command {} (try help)", c)),
+        };
+
+        self.state = transition.apply(&pre.state).map_err(|e| format!("{} failed: {:?}", transition, e))?;
+        self.history.push((String::from(line), pre));
+        Ok(format!("{}\t{:.1}", transition, self.state))
+    }
+}
+
+fn main() {
+    let mut session = Session::new();
+    if let Some(path) = std::env::args().nth(1) {
+        match session.exec(&format!("load {}", path)) {
+            Ok(out) => println!("{}", out),
+            Err(e) => eprintln!("error: {}", e),
+        }
+    }
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line).unwrap() == 0 || line.trim() == "quit" {
+            break;
+        }
+        match session.exec(&line) {
+            Ok(out) if out.is_empty() => {}
+            Ok(out) => println!("{}", out),
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(session: &mut Session, commands: &[&str]) {
+        for command in commands {
+            session.exec(command).unwrap();
+        }
+    }
+
+    #[test]
+    fn sandwich_session() {
+        let mut session = Session::new();
+        run(&mut session, &[
+            "token t0 t1", "user O A M",
+            "balance O 100 t0", "balance O 100 t1", "balance A 20 t0", "balance M 10 t0",
+            "pool t0 t1 cp 0.003", "dep O 100 t0 100 t1",
+        ]);
+        assert!(session.exec("quote t0 t1 20").unwrap().starts_with("out 16.6"));
+        assert!(session.exec("swap A t2 t1 20").unwrap_err().contains("unknown token t2"));
+        run(&mut session, &["M:swap(t0,t1,10)", "swap A t0 t1 20"]);
+        let a = session.state.get_balance(&User::new("A"), &Token::Atomic(String::from("t1")));
+        assert!(session.exec("undo").unwrap().ends_with("swap A t0 t1 20"));
+        assert_eq!(session.state.get_balance(&User::new("A"), &Token::Atomic(String::from("t1"))), 0.0);
+        session.exec("swap A t0 t1 20").unwrap();
+        assert_eq!(session.state.get_balance(&User::new("A"), &Token::Atomic(String::from("t1"))), a);
+        let total = session.exec("wealth").unwrap();
+        let total: f64 = total.rsplit('\t').next().unwrap().parse().unwrap();
+        assert!((total - 230000.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn saved_sessions_replay() {
+        let mut session = Session::new();
+        run(&mut session, &[
+            "token t0 t1", "user O", "balance O 100 t0", "balance O 100 t1", "pool t0 t1",
+            "dep O 100 t0 100 t1", "oracle manual t0=2 t1=1", "show", "user B", "undo",
+        ]);
+        assert!(session.exec("balance B 1 t0").unwrap_err().contains("unknown user"));
+        let path = std::env::temp_dir().join("amm-theory-repl-test.txt");
+        let path = path.to_str().unwrap();
+        session.exec(&format!("save {}", path)).unwrap();
+
+        let mut replayed = Session::new();
+        replayed.exec(&format!("load {}", path)).unwrap();
+        assert_eq!(replayed.state.to_string(), session.state.to_string());
+        assert_eq!(replayed.exec("wealth").unwrap(), session.exec("wealth").unwrap());
+        assert!(session.exec("wealth").unwrap().starts_with("O\t299.99"));
+        assert!(session.history.iter().all(|(_, pre)| pre.history.is_empty()));
+
+        // a file loading itself fails instead of recursing forever
+        let looping = std::env::temp_dir().join("amm-theory-repl-loop.txt");
+        std::fs::write(&looping, format!("token t0\nload {}\n", looping.display())).unwrap();
+        let mut session = Session::new();
+        assert!(session.exec(&format!("load {}", looping.display())).unwrap_err().contains("loads itself"));
+        assert!(session.loading.is_empty());
+    }
+}
@@ -0,0 +1,304 @@
+use std::fmt;
+
+use crate::agent::{Agent, Arbitrageur};
+use crate::oracle::Oracle;
+use crate::rng::Rng;
+use crate::{AdvanceBlock, State, Swap, Token, Transition, TransitionError, User};
+
+// Two chains, each with its own state, advancing block by block together.
+// A bridge moves tokens of a user from one chain to the other: the tokens
+// leave the source chain at once, minus a fee paid to the operator of the
+// bridge there, and arrive `delay` blocks later. Tokens with the same name
+// are the same asset on both chains.
+#[derive(Clone)]
+pub struct World {
+    pub chains: [State; 2],
+    pub in_flight: Vec<InFlight>,
+    pub delay: u64,
+    pub fee: f64,
+    pub operator: User,
+}
+
+#[derive(Clone)]
+pub struct InFlight {
+    pub user: User,
+    pub token: Token,
+    pub v: f64,
+    pub to: usize,
+    pub arrival: u64,
+}
+
+impl World {
+    pub fn new(chain0: &State, chain1: &State, delay: u64, fee: f64, operator: &User) -> Self {
+        assert!((0.0..1.0).contains(&fee));
+        World { chains: [chain0.clone(), chain1.clone()], in_flight: Vec::new(), delay, fee, operator: operator.clone() }
+    }
+
+    pub fn block(&self) -> u64 {
+        self.chains[0].block
+    }
+
+    // Applies a transition of the given chain
+    pub fn apply(&self, chain: usize, t: &dyn Transition) -> Result<World, TransitionError> {
+        let mut post = self.clone();
+        post.chains[chain] = t.apply(&self.chains[chain])?;
+        Ok(post)
+    }
+
+    // Advances both chains by a block, delivering the tokens that arrive
+    pub fn tick(&self) -> World {
+        let mut post = self.clone();
+        for chain in post.chains.iter_mut() {
+            *chain = AdvanceBlock::new(1).apply(chain).unwrap();
+        }
+        let block = post.block();
+        let (arrived, in_flight): (Vec<InFlight>, Vec<InFlight>) = post.in_flight.into_iter().partition(|t| t.arrival <= block);
+        post.in_flight = in_flight;
+        for t in arrived {
+            let balance = post.chains[t.to].get_balance(&t.user, &t.token);
+            post.chains[t.to].set_balance(&t.user, &t.token, balance + t.v);
+        }
+        post
+    }
+
+    // Value of the tokens in flight to the user, at the prices of their
+    // destination chain
+    pub fn in_flight_value(&self, user: &User, f: &dyn Fn(&State, &Token) -> f64) -> f64 {
+        self.in_flight.iter().filter(|t| t.user == *user).map(|t| t.v * f(&self.chains[t.to], &t.token)).sum()
+    }
+
+    pub fn net_wealth_user(&self, user: &User, f: &dyn Fn(&State, &Token) -> f64) -> f64 {
+        self.chains.iter().map(|s| s.net_wealth_user(user, f)).sum::<f64>() + self.in_flight_value(user, f)
+    }
+}
+
+impl fmt::Display for World {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = f.precision().unwrap_or(1);
+        write!(f, "0: {:.*} || 1: {:.*}", p, self.chains[0], p, self.chains[1])?;
+        for t in &self.in_flight {
+            write!(f, " || {}:{:.*}:{}->{}@{}", t.user, p, t.v, t.token, t.to, t.arrival)?;
+        }
+        Ok(())
+    }
+}
+
+// Sends v tokens of the sender from the given chain to the other one
+pub struct Bridge {
+    sender: User,
+    token: Token,
+    v: f64,
+    from: usize,
+}
+
+impl Bridge {
+    pub fn new(sender: &User, token: &Token, v: f64, from: usize) -> Self {
+        assert!(v > 0.0 && from < 2);
+        Bridge { sender: sender.clone(), token: token.clone(), v, from }
+    }
+
+    pub fn apply(&self, w: &World) -> Result<World, TransitionError> {
+        let mut post = w.clone();
+        let s = &mut post.chains[self.from];
+        let balance = s.get_balance(&self.sender, &self.token);
+        if balance < self.v {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        s.set_balance(&self.sender, &self.token, balance - self.v);
+        let fee = self.v * w.fee;
+        let operator = s.get_balance(&w.operator, &self.token);
+        s.set_balance(&w.operator, &self.token, operator + fee);
+        post.in_flight.push(InFlight {
+            user: self.sender.clone(),
+            token: self.token.clone(),
+            v: self.v - fee,
+            to: 1 - self.from,
+            arrival: w.block() + w.delay,
+        });
+        Ok(post)
+    }
+}
+
+impl fmt::Display for Bridge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:bridge({}:{},{}->{})", self.sender, self.v, self.token, self.from, 1 - self.from)
+    }
+}
+
+// Trades the price gap of the pools of t0 and t1 on the two chains with the
+// inventory it holds on both: it buys t0 where it is cheap and sells it where
+// it is dear, moving both pools to the geometric mean of their prices, then
+// bridges what it bought back to where it sold, to restore its inventory
+pub struct CrossArbitrageur {
+    user: User,
+    t0: Token,
+    t1: Token,
+    // relative price gap below which it does not trade
+    min_gap: f64,
+}
+
+impl CrossArbitrageur {
+    pub fn new(user: &User, t0: &Token, t1: &Token, min_gap: f64) -> Self {
+        CrossArbitrageur { user: user.clone(), t0: t0.clone(), t1: t1.clone(), min_gap }
+    }
+
+    pub fn user(&self) -> &User {
+        &self.user
+    }
+
+    // The swap on each chain, if the gap is wide enough
+    pub fn trades(&self, w: &World) -> Vec<(usize, Swap)> {
+        let prices: Vec<Option<f64>> = w.chains.iter().map(|s| s.spot_price(&self.t0, &self.t1)).collect();
+        let (p0, p1) = match (prices[0], prices[1]) {
+            (Some(p0), Some(p1)) if p0 > 0.0 && p1 > 0.0 => (p0, p1),
+            _ => return Vec::new(),
+        };
+        let (cheap, dear) = if p1 > p0 { (0, 1) } else { (1, 0) };
+        let (lo, hi) = (p0.min(p1), p0.max(p1));
+        if hi < lo * (1.0 + self.min_gap) {
+            return Vec::new();
+        }
+        let target = (lo * hi).sqrt();
+        let mut trades = Vec::new();
+        for chain in [cheap, dear] {
+            let s = &w.chains[chain];
+            if let Some((tin, x)) = s.amount_to_price(&self.t0, &self.t1, target) {
+                let x = x.min(s.get_balance(&self.user, &tin));
+                let tout = if tin == self.t0 { &self.t1 } else { &self.t0 };
+                if x > 1e-9 {
+                    trades.push((chain, Swap::new(&self.user, &tin, tout, x)));
+                }
+            }
+        }
+        trades
+    }
+}
+
+pub struct Report {
+    // gains of the trades, at the external price when they were made
+    pub captured: f64,
+    pub bridge_fees: f64,
+    // change of value of the initial inventory, had it been held
+    pub hold_pnl: f64,
+    // gains and losses of the inventory, in flight or not, as the external
+    // price moves, in excess of holding it: what the above do not explain
+    pub inventory_pnl: f64,
+    pub total: f64,
+    pub max_in_flight: f64,
+}
+
+// Runs the world for the given number of blocks. At every block the external
+// price of t0 (in units of t1, whose price is 1) moves by a lognormal step of
+// the given volatility, the local arbitrageur realigns the pool of chain 0
+// with it, and the cross-chain arbitrageur trades the gap with chain 1.
+pub fn run(
+    w: &World,
+    arb: &CrossArbitrageur,
+    local: &mut Arbitrageur,
+    price: f64,
+    volatility: f64,
+    blocks: u64,
+    rng: &mut Rng,
+) -> (World, Report) {
+    let (t0, t1) = (arb.t0.clone(), arb.t1.clone());
+    let mut feed = Oracle::Manual(vec![(t0.clone(), price), (t1.clone(), 1.0)]);
+    let mut price = price;
+    let mut w = w.clone();
+    let initial = w.net_wealth_user(&arb.user, &|s, t| feed.price(s, t));
+    let held = w.clone();
+    let mut report = Report { captured: 0.0, bridge_fees: 0.0, hold_pnl: 0.0, inventory_pnl: 0.0, total: 0.0, max_in_flight: 0.0 };
+
+    for _ in 0..blocks {
+        price *= (volatility * rng.normal() - volatility * volatility / 2.0).exp();
+        feed.set_price(&t0, price).unwrap();
+        let f = |s: &State, t: &Token| feed.price(s, t);
+        for (_, t) in local.act(&w.chains[0], &[], &feed, rng) {
+            if let Ok(post) = w.apply(0, t.as_ref()) {
+                w = post;
+            }
+        }
+
+        let before = w.net_wealth_user(&arb.user, &f);
+        let mut bought = Vec::new();
+        for (chain, swap) in arb.trades(&w) {
+            let received = w.chains[chain].get_balance(&arb.user, &swap.tout);
+            if let Ok(post) = w.apply(chain, &swap) {
+                bought.push((chain, swap.tout.clone(), post.chains[chain].get_balance(&arb.user, &swap.tout) - received));
+                w = post;
+            }
+        }
+        let traded = w.net_wealth_user(&arb.user, &f);
+        report.captured += traded - before;
+        for (chain, token, v) in bought {
+            if v > 0.0 {
+                w = Bridge::new(&arb.user, &token, v, chain).apply(&w).unwrap();
+            }
+        }
+        report.bridge_fees += traded - w.net_wealth_user(&arb.user, &f);
+        report.max_in_flight = report.max_in_flight.max(w.in_flight_value(&arb.user, &f));
+        w = w.tick();
+    }
+
+    report.total = w.net_wealth_user(&arb.user, &|s, t| feed.price(s, t)) - initial;
+    report.hold_pnl = held.net_wealth_user(&arb.user, &|s, t| feed.price(s, t)) - initial;
+    report.inventory_pnl = report.total - report.captured + report.bridge_fees - report.hold_pnl;
+    (w, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreatePool, Curve, Deposit};
+
+    fn chain(r0: f64, r1: f64) -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let x = User::new("X");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, r0);
+        s.set_balance(&o, &t1, r1);
+        s.set_balance(&x, &t0, 100.0);
+        s.set_balance(&x, &t1, 100.0);
+        s.set_balance(&User::new("L"), &t0, 1e6);
+        s.set_balance(&User::new("L"), &t1, 1e6);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        let s = Deposit::new(&o, r0, &t0, r1, &t1).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    #[test]
+    fn bridged_tokens_arrive_after_the_delay() {
+        let (s, t0, _) = chain(100.0, 100.0);
+        let (x, operator) = (User::new("X"), User::new("B"));
+        let w = World::new(&s, &s, 3, 0.01, &operator);
+        let w = Bridge::new(&x, &t0, 50.0, 0).apply(&w).unwrap();
+        assert_eq!(w.chains[0].get_balance(&operator, &t0), 0.5);
+        let f = |_: &State, _: &Token| 1.0;
+        assert_eq!(w.net_wealth_user(&x, &f), 400.0 - 50.0 + 49.5);
+        let w = w.tick().tick();
+        assert_eq!(w.chains[1].get_balance(&x, &t0), 100.0);
+        let w = w.tick();
+        assert!(w.in_flight.is_empty());
+        assert_eq!(w.chains[1].get_balance(&x, &t0), 149.5);
+        assert!(matches!(Bridge::new(&x, &t0, 60.0, 0).apply(&w), Err(TransitionError::InsufficientBalance)));
+    }
+
+    #[test]
+    fn arbitrage_closes_the_gap_between_chains() {
+        let (s0, t0, t1) = chain(100.0, 100.0);
+        let (s1, _, _) = chain(100.0, 120.0);
+        let x = User::new("X");
+        let w = World::new(&s0, &s1, 5, 0.001, &User::new("B"));
+        let arb = CrossArbitrageur::new(&x, &t0, &t1, 0.01);
+        let mut local = Arbitrageur::new(&User::new("L"), &t0, &t1);
+        let (post, report) = run(&w, &arb, &mut local, 1.0, 0.0, 10, &mut Rng::new(0));
+        let p1 = post.chains[1].spot_price(&t0, &t1).unwrap();
+        assert!((p1 - 1.0).abs() < 0.02);
+        assert!(report.captured > 0.0 && report.bridge_fees > 0.0 && report.max_in_flight > 0.0);
+        assert!(post.in_flight.is_empty());
+        // with a constant price, the inventory neither gains nor loses
+        assert!(report.inventory_pnl.abs() < 1e-9 && report.hold_pnl == 0.0);
+        assert!((report.total - (report.captured - report.bridge_fees)).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::HashSet;
+use std::thread;
+
+use crate::oracle::Oracle;
+use crate::{Deposit, FeeMode, Redeem, State, Swap, Token, Transition, User};
+
+// A reachable state violating the property, with the labels of the
+// transitions leading to it
+pub struct Counterexample {
+    pub trace: Vec<String>,
+    pub state: State,
+}
+
+pub struct Report {
+    // distinct states reached, the initial one included
+    pub explored: usize,
+    pub counterexamples: Vec<Counterexample>,
+}
+
+// Values are compared up to this precision when deduplicating states
+const PRECISION: f64 = 1e-9;
+
+// A state as the sorted list of its labelled values, quantized to PRECISION
+// (exactly up to about 1e29): states reached by different sequences are
+// explored once, and distinct states are never merged
+pub type Key = Vec<(String, i128)>;
+
+fn quantize(v: f64) -> i128 {
+    (v / PRECISION).round() as i128
+}
+
+// Canonical key of a state, invariant under the order of wallets, balances,
+// pools and positions, with every field read by a transition
+pub fn state_key(s: &State) -> Key {
+    let mut key: Key = Vec::new();
+    let mut value = |label: String, v: f64| key.push((label, quantize(v)));
+    for w in &s.wallets {
+        for b in w.balances.iter().filter(|b| b.value != 0.0) {
+            value(format!("{}.{}", w.user, b.token), b.value);
+        }
+    }
+    for a in &s.amms {
+        let pool = Token::mint(&a.t0, &a.t1);
+        value(format!("{}.{}", pool, a.t0), a.r0);
+        value(format!("{}.{}", pool, a.t1), a.r1);
+        value(format!("{}.fee", pool), a.fee);
+        value(format!("{}.curve.{}", pool, a.curve), 1.0);
+        value(format!("{}.locked", pool), a.locked);
+        if let Some(pf) = &a.protocol_fee {
+            let mode = if pf.mode == FeeMode::Minted { "minted" } else { "tokens" };
+            value(format!("{}.protocol_fee.{}.{}", pool, pf.treasury, mode), pf.fraction);
+            value(format!("{}.k_last", pool), pf.k_last);
+        }
+        if let Some(df) = &a.dynamic_fee {
+            value(format!("{}.dynfee.min", pool), df.min);
+            value(format!("{}.dynfee.max", pool), df.max);
+            value(format!("{}.dynfee.sensitivity", pool), df.sensitivity);
+            value(format!("{}.dynfee.window", pool), df.window as f64);
+            value(format!("{}.dynfee.last_price", pool), df.last_price);
+            for bar in &df.history {
+                value(format!("{}.dynfee.{}.open", pool, bar.block), bar.open);
+                value(format!("{}.dynfee.{}.close", pool, bar.block), bar.close);
+                value(format!("{}.dynfee.{}.variance", pool, bar.block), bar.variance);
+            }
+        }
+    }
+    let l = &s.lending;
+    value(String::from("lending.collateral_factor"), l.collateral_factor);
+    value(String::from("lending.liquidation_bonus"), l.liquidation_bonus);
+    match &l.oracle {
+        Oracle::External(f) => value(format!("oracle.external.{:p}", *f as *const ()), 1.0),
+        Oracle::Spot(numeraire) => value(format!("oracle.spot.{}", numeraire), 1.0),
+        Oracle::Manual(prices) => {
+            for (t, p) in prices {
+                value(format!("oracle.manual.{}", t), *p);
+            }
+        }
+    }
+    if let Some(r) = &l.reporter {
+        value(format!("oracle.reporter.{}", r), 1.0);
+    }
+    for m in &l.markets {
+        value(format!("market.{}.rate", m.token), m.rate);
+        value(format!("market.{}.cash", m.token), m.cash);
+        value(format!("market.{}.shares", m.token), m.shares);
+        value(format!("market.{}.scaled_borrows", m.token), m.scaled_borrows);
+        value(format!("market.{}.borrow_index", m.token), m.borrow_index);
+        value(format!("market.{}.last_block", m.token), m.last_block as f64);
+    }
+    for p in &l.positions {
+        value(format!("position.{}.{}.shares", p.user, p.token), p.shares);
+        value(format!("position.{}.{}.scaled_debt", p.user, p.token), p.scaled_debt);
+    }
+    for f in &s.rewards.farms {
+        value(format!("farm.{}.{}.rate", f.lp_token, f.reward), f.rate);
+        value(format!("farm.{}.staked", f.lp_token), f.staked);
+        value(format!("farm.{}.acc_per_share", f.lp_token), f.acc_per_share);
+        value(format!("farm.{}.last_block", f.lp_token), f.last_block as f64);
+    }
+    for p in &s.rewards.stakes {
+        value(format!("stake.{}.{}.amount", p.user, p.lp_token), p.amount);
+        value(format!("stake.{}.{}.acc_checkpoint", p.user, p.lp_token), p.acc_checkpoint);
+        value(format!("stake.{}.{}.unclaimed", p.user, p.lp_token), p.unclaimed);
+    }
+    for o in &s.twamm.orders {
+        value(format!("order.{}.{}.{}.{}", o.id, o.owner, o.tin, o.tout), o.rate);
+        value(format!("order.{}.start", o.id), o.start as f64);
+        value(format!("order.{}.end", o.id), o.end as f64);
+    }
+    for (t0, t1, block) in &s.twamm.settled {
+        value(format!("settled.{}", Token::mint(t0, t1)), *block as f64);
+    }
+    value(String::from("twamm.next_id"), s.twamm.next_id as f64);
+    value(String::from("block"), s.block as f64);
+    key.sort();
+    key
+}
+
+// Swaps, deposits (at the pool ratio) and redeems of the given amounts by the
+// given users, on every pool, as far as their balances allow
+pub fn grid_moves(users: &[User], amounts: &[f64]) -> impl Fn(&State) -> Vec<Box<dyn Transition>> + Sync {
+    let users = users.to_vec();
+    let amounts = amounts.to_vec();
+    move |s: &State| {
+        let mut moves: Vec<Box<dyn Transition>> = Vec::new();
+        for u in &users {
+            for amm in &s.amms {
+                let (t0, t1) = (&amm.t0, &amm.t1);
+                let lp_balance = s.get_balance(u, &Token::mint(t0, t1));
+                for &x in &amounts {
+                    if amm.r0 > 0.0 {
+                        if s.get_balance(u, t0) >= x {
+                            moves.push(Box::new(Swap::new(u, t0, t1, x)));
+                        }
+                        if s.get_balance(u, t1) >= x {
+                            moves.push(Box::new(Swap::new(u, t1, t0, x)));
+                        }
+                    }
+                    let v1 = if amm.r0 > 0.0 { x * amm.r1 / amm.r0 } else { x };
+                    if s.get_balance(u, t0) >= x && s.get_balance(u, t1) >= v1 {
+                        moves.push(Box::new(Deposit::new(u, x, t0, v1, t1)));
+                    }
+                    if lp_balance > x {
+                        moves.push(Box::new(Redeem::new(u, t0, t1, x)));
+                    }
+                }
+                if lp_balance > 0.0 {
+                    moves.push(Box::new(Redeem::new(u, t0, t1, lp_balance)));
+                }
+            }
+        }
+        moves
+    }
+}
+
+fn has_negative_balance(s: &State) -> bool {
+    s.wallets.iter().any(|w| w.balances.iter().any(|b| b.value < -PRECISION))
+}
+
+// Explores breadth-first all the sequences of at most depth moves from s0,
+// checking property(s0, s) on every distinct reachable state s. Each level is
+// expanded in parallel on the given number of threads.
+pub fn check(
+    s0: &State,
+    depth: usize,
+    moves: &(dyn Fn(&State) -> Vec<Box<dyn Transition>> + Sync),
+    property: &(dyn Fn(&State, &State) -> bool + Sync),
+    threads: usize,
+) -> Report {
+    let mut visited = HashSet::new();
+    visited.insert(state_key(s0));
+    let mut counterexamples = Vec::new();
+    if !property(s0, s0) {
+        counterexamples.push(Counterexample { trace: Vec::new(), state: s0.clone() });
+    }
+    let mut frontier: Vec<(State, Vec<String>)> = vec![(s0.clone(), Vec::new())];
+
+    for _ in 0..depth {
+        let chunk = frontier.len().div_ceil(threads.max(1)).max(1);
+        let children: Vec<(Key, State, Vec<String>)> = thread::scope(|scope| {
+            let handles: Vec<_> = frontier.chunks(chunk)
+                .map(|part| scope.spawn(move || expand(part, moves)))
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+        });
+
+        frontier = Vec::new();
+        for (key, s, trace) in children {
+            if !visited.insert(key) {
+                continue;
+            }
+            if !property(s0, &s) {
+                counterexamples.push(Counterexample { trace: trace.clone(), state: s.clone() });
+            }
+            frontier.push((s, trace));
+        }
+    }
+    Report { explored: visited.len(), counterexamples }
+}
+
+fn expand(part: &[(State, Vec<String>)], moves: &(dyn Fn(&State) -> Vec<Box<dyn Transition>> + Sync))
+    -> Vec<(Key, State, Vec<String>)> {
+    let mut children = Vec::new();
+    for (s, trace) in part {
+        for t in moves(s) {
+            if let Ok(post) = t.apply(s) {
+                if has_negative_balance(&post) {
+                    continue;
+                }
+                let mut trace = trace.clone();
+                trace.push(t.to_string());
+                children.push((state_key(&post), post, trace));
+            }
+        }
+    }
+    children
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_oracle, CreatePool, Curve};
+
+    fn setup() -> (State, User, User) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let a = User::new("A");
+        let m = User::new("M");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        s.set_balance(&a, &t0, 20.0);
+        s.set_balance(&m, &t0, 20.0);
+        s.set_balance(&m, &t1, 20.0);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        (s, a, m)
+    }
+
+    #[test]
+    fn key_ignores_the_order_of_wallets() {
+        let t0 = Token::Atomic(String::from("t0"));
+        let (a, b) = (User::new("A"), User::new("B"));
+        let mut s1 = State::new();
+        s1.set_balance(&a, &t0, 1.0);
+        s1.set_balance(&b, &t0, 2.0);
+        let mut s2 = State::new();
+        s2.set_balance(&b, &t0, 2.0);
+        s2.set_balance(&a, &t0, 1.0);
+        assert_eq!(state_key(&s1), state_key(&s2));
+        s2.set_balance(&a, &t0, 1.5);
+        assert_ne!(state_key(&s1), state_key(&s2));
+    }
+
+    #[test]
+    fn key_tells_apart_large_values_and_pool_settings() {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let a = User::new("A");
+        let mut s1 = State::new();
+        s1.set_balance(&a, &t0, 1e12);
+        let mut s2 = s1.clone();
+        s2.set_balance(&a, &t0, 1e12 + 1.0);
+        assert_ne!(state_key(&s1), state_key(&s2));
+        let cp = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s1).unwrap();
+        let cs = CreatePool::new(&t0, &t1, Curve::ConstantSum, 0.003).apply(&s1).unwrap();
+        let cheap = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.001).apply(&s1).unwrap();
+        assert_ne!(state_key(&cp), state_key(&cs));
+        assert_ne!(state_key(&cp), state_key(&cheap));
+    }
+
+    #[test]
+    fn wealth_is_conserved() {
+        let (s0, a, m) = setup();
+        let moves = grid_moves(&[a, m], &[10.0, 20.0]);
+        // up to the LP rounding absorbed by the locked liquidity
+        let conserved = |s0: &State, s: &State| {
+            let w0 = s0.net_wealth(&price_oracle);
+            (s.net_wealth(&price_oracle) - w0).abs() < 1e-9 * w0
+        };
+        let report = check(&s0, 2, &moves, &conserved, 4);
+        assert!(report.explored > 1);
+        assert!(report.counterexamples.is_empty());
+    }
+
+    #[test]
+    fn sandwich_falsifies_no_profit_from_swaps() {
+        let (s0, a, m) = setup();
+        let moves = grid_moves(&[a, m.clone()], &[10.0, 20.0]);
+        let no_profit = |s0: &State, s: &State| s.net_wealth_user(&m, &price_oracle) <= s0.net_wealth_user(&m, &price_oracle) + 1e-6;
+        let report = check(&s0, 3, &moves, &no_profit, 4);
+        assert!(!report.counterexamples.is_empty());
+        // the same counterexamples are found sequentially
+        assert_eq!(check(&s0, 3, &moves, &no_profit, 1).counterexamples.len(), report.counterexamples.len());
+    }
+}
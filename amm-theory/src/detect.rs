@@ -0,0 +1,335 @@
+use crate::agent::simulate;
+use crate::{order_tokens, Deposit, Redeem, State, Swap, Token, Transition, User, LP_UNIT};
+
+// Detection of MEV in a recorded sequence of transitions:
+// - sandwiches: a user swaps on a pair, others swap in the same direction,
+//   then the user swaps back in the opposite direction
+// - back-runs: a user swaps right after another user's swap on the same pair,
+//   in the opposite direction, moving the price back and gaining from it
+// - JIT liquidity: a user deposits into a pool and redeems from it around the
+//   swaps of others
+// The value of each pattern is attributed against the counterfactual sequence
+// without the attacker's transitions of the pattern, failing ones skipped.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Sandwich,
+    Backrun,
+    Jit,
+}
+
+#[derive(Clone)]
+pub struct Pattern {
+    pub kind: Kind,
+    pub attacker: User,
+    // indices of the attacker's transitions and of the swaps they target
+    pub attack: Vec<usize>,
+    pub targets: Vec<usize>,
+    // the swappers targeted, or for JIT the LPs already in the pool; for a
+    // back-run only the targets losing from it
+    pub victims: Vec<User>,
+}
+
+pub struct Attribution {
+    // gain of the attacker with respect to the counterfactual
+    pub extracted: f64,
+    // loss of each victim with respect to the counterfactual
+    pub losses: Vec<(User, f64)>,
+}
+
+// The pool a transition trades on or provides liquidity to, if any
+fn pair(t: &dyn Transition) -> Option<(Token, Token)> {
+    let (t0, t1) = if let Some(swap) = t.downcast_ref::<Swap>() {
+        (&swap.tin, &swap.tout)
+    } else if let Some(deposit) = t.downcast_ref::<Deposit>() {
+        (&deposit.t0, &deposit.t1)
+    } else if let Some(redeem) = t.downcast_ref::<Redeem>() {
+        (&redeem.t0, &redeem.t1)
+    } else {
+        return None;
+    };
+    let (t0, t1) = order_tokens(t0, t1);
+    Some((t0.clone(), t1.clone()))
+}
+
+fn swaps(txs: &[Box<dyn Transition>]) -> Vec<(usize, &Swap)> {
+    txs.iter().enumerate().filter_map(|(i, t)| t.downcast_ref::<Swap>().map(|s| (i, s))).collect()
+}
+
+fn same_pair(a: &Swap, b: &Swap) -> bool {
+    a.tin == b.tin && a.tout == b.tout || a.tin == b.tout && a.tout == b.tin
+}
+
+// Distance of the prices of the pair in the states reached after the first
+// m and the first n transitions, on a log scale so either token can be the
+// unit
+fn price_move(s0: &State, txs: &[Box<dyn Transition>], t0: &Token, t1: &Token, m: usize, n: usize) -> f64 {
+    let price = |s: &State| s.get_reserves(t1, t0) / s.get_reserves(t0, t1);
+    (price(&simulate(s0, &txs[..n])) / price(&simulate(s0, &txs[..m]))).ln().abs()
+}
+
+fn distinct(users: impl Iterator<Item = User>) -> Vec<User> {
+    let mut distinct: Vec<User> = Vec::new();
+    for u in users {
+        if !distinct.contains(&u) {
+            distinct.push(u);
+        }
+    }
+    distinct
+}
+
+// Every swap is paired with the first swap back of the same user on the same
+// pair; it is a sandwich if others swapped in between in the same direction
+fn sandwiches(txs: &[Box<dyn Transition>]) -> Vec<Pattern> {
+    let swaps = swaps(txs);
+    let mut patterns = Vec::new();
+    for (n, (i, front)) in swaps.iter().enumerate() {
+        let back = swaps[n + 1..].iter()
+            .find(|(_, s)| s.sender == front.sender && s.tin == front.tout && s.tout == front.tin);
+        let (k, _) = match back {
+            Some(back) => back,
+            None => continue,
+        };
+        let targets: Vec<(usize, &Swap)> = swaps[n + 1..].iter()
+            .filter(|(j, s)| j < k && s.sender != front.sender && s.tin == front.tin && s.tout == front.tout)
+            .copied()
+            .collect();
+        if !targets.is_empty() {
+            patterns.push(Pattern {
+                kind: Kind::Sandwich,
+                attacker: front.sender.clone(),
+                attack: vec![*i, *k],
+                targets: targets.iter().map(|(j, _)| *j).collect(),
+                victims: distinct(targets.iter().map(|(_, s)| s.sender.clone())),
+            });
+        }
+    }
+    patterns
+}
+
+// Swaps following, on their pair, a swap of another user in the opposite
+// direction, unless they close a sandwich or follow one. The swap must bring
+// the price closer to where the other swap found it, and gain with respect to
+// the counterfactual without it.
+fn backruns(s0: &State, txs: &[Box<dyn Transition>], sandwiches: &[Pattern], f: &dyn Fn(&State, &Token) -> f64)
+    -> Vec<Pattern> {
+    let mut patterns = Vec::new();
+    for (k, back) in swaps(txs) {
+        if sandwiches.iter().any(|p| p.attack[1] == k) {
+            continue;
+        }
+        let pool = pair(txs[k].as_ref());
+        let previous = txs[..k].iter().enumerate().rev().find(|(_, t)| pair(t.as_ref()) == pool);
+        let target = previous.and_then(|(j, t)| t.downcast_ref::<Swap>().map(|s| (j, s)));
+        if let Some((j, target)) = target {
+            if sandwiches.iter().any(|p| p.attack[1] == j) {
+                continue;
+            }
+            if target.sender == back.sender || !same_pair(target, back) || target.tin != back.tout {
+                continue;
+            }
+            if price_move(s0, txs, &back.tin, &back.tout, j, k + 1) >= price_move(s0, txs, &back.tin, &back.tout, j, k) {
+                continue;
+            }
+            let mut pattern = Pattern {
+                kind: Kind::Backrun,
+                attacker: back.sender.clone(),
+                attack: vec![k],
+                targets: vec![j],
+                victims: vec![target.sender.clone()],
+            };
+            let a = attribute(s0, txs, &pattern, f);
+            if a.extracted <= 0.0 {
+                continue;
+            }
+            pattern.victims = a.losses.into_iter().filter(|(_, l)| *l > 0.0).map(|(v, _)| v).collect();
+            patterns.push(pattern);
+        }
+    }
+    patterns
+}
+
+// Every deposit is paired with the next redeem of the same user from the same
+// pool; it is JIT liquidity if others swapped in between and the redeem takes
+// back what was minted. The victims are the other LPs of the pool at the deposit.
+fn jits(s0: &State, txs: &[Box<dyn Transition>]) -> Vec<Pattern> {
+    let mut patterns = Vec::new();
+    for (i, t) in txs.iter().enumerate() {
+        let deposit = match t.downcast_ref::<Deposit>() {
+            Some(d) => d,
+            None => continue,
+        };
+        let pool = pair(t.as_ref());
+        let redeem = txs[i + 1..].iter().position(|t| {
+            t.downcast_ref::<Redeem>().is_some_and(|r| r.sender == deposit.sender) && pair(t.as_ref()) == pool
+        });
+        let k = match redeem {
+            Some(n) => i + 1 + n,
+            None => continue,
+        };
+        let targets: Vec<usize> = swaps(&txs[..k]).into_iter()
+            .filter(|(j, s)| *j > i && s.sender != deposit.sender && pair(txs[*j].as_ref()) == pool)
+            .map(|(j, _)| j)
+            .collect();
+        if targets.is_empty() {
+            continue;
+        }
+        let before = simulate(s0, &txs[..i]);
+        let lp_token = Token::mint(&deposit.t0, &deposit.t1);
+        // a partial redeem is not JIT, the user remaining an LP
+        let after = simulate(&before, &txs[i..=k]);
+        if after.get_balance(&deposit.sender, &lp_token) > before.get_balance(&deposit.sender, &lp_token) + LP_UNIT {
+            continue;
+        }
+        let victims = before.wallets.iter()
+            .filter(|w| w.user != deposit.sender && w.get_balance(&lp_token) > 0.0)
+            .map(|w| w.user.clone())
+            .collect();
+        patterns.push(Pattern { kind: Kind::Jit, attacker: deposit.sender.clone(), attack: vec![i, k], targets, victims });
+    }
+    patterns
+}
+
+// The patterns of the sequence, in the order of their first transition, the
+// gains of the back-runs valued with the given prices
+pub fn detect(s0: &State, txs: &[Box<dyn Transition>], f: &dyn Fn(&State, &Token) -> f64) -> Vec<Pattern> {
+    let sandwiches = sandwiches(txs);
+    let mut patterns = backruns(s0, txs, &sandwiches, f);
+    patterns.extend(sandwiches);
+    patterns.extend(jits(s0, txs));
+    patterns.sort_by_key(|p| p.attack[0]);
+    patterns
+}
+
+// The state reached without the transitions at the given indices, skipping
+// the failing ones
+pub fn counterfactual(s0: &State, txs: &[Box<dyn Transition>], removed: &[usize]) -> State {
+    let mut s = s0.clone();
+    for (i, t) in txs.iter().enumerate() {
+        if removed.contains(&i) {
+            continue;
+        }
+        if let Ok(post) = t.apply(&s) {
+            s = post;
+        }
+    }
+    s
+}
+
+// Value extracted by the pattern and losses of its victims at the end of the
+// sequence, with respect to the counterfactual without the attack
+pub fn attribute(s0: &State, txs: &[Box<dyn Transition>], pattern: &Pattern, f: &dyn Fn(&State, &Token) -> f64) -> Attribution {
+    let actual = simulate(s0, txs);
+    let without = counterfactual(s0, txs, &pattern.attack);
+    Attribution {
+        extracted: actual.net_wealth_user(&pattern.attacker, f) - without.net_wealth_user(&pattern.attacker, f),
+        losses: pattern.victims.iter()
+            .map(|v| (v.clone(), without.net_wealth_user(v, f) - actual.net_wealth_user(v, f)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_mini_transaction, price_oracle, CreatePool, Curve, SFr0};
+
+    fn setup() -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let mut s = State::new();
+        for name in ["O", "V", "W", "M", "B", "J"] {
+            s.set_balance(&User::new(name), &t0, 100.0);
+            s.set_balance(&User::new(name), &t1, 100.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        let s = Deposit::new(&User::new("O"), 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    #[test]
+    fn sandwich_and_backrun() {
+        let (s0, t0, t1) = setup();
+        let (v, w, m, b) = (User::new("V"), User::new("W"), User::new("M"), User::new("B"));
+        let txs: Vec<Box<dyn Transition>> = vec![
+            Box::new(Swap::new(&m, &t0, &t1, 10.0)),
+            Box::new(Swap::new(&v, &t0, &t1, 20.0)),
+            Box::new(Swap::new(&m, &t1, &t0, 10.0)),
+            Box::new(Swap::new(&w, &t0, &t1, 15.0)),
+            Box::new(Swap::new(&b, &t1, &t0, 8.0)),
+        ];
+        let patterns = detect(&s0, &txs, &price_oracle);
+        let kinds: Vec<Kind> = patterns.iter().map(|p| p.kind).collect();
+        assert_eq!(kinds, vec![Kind::Sandwich, Kind::Backrun]);
+        let sandwich = &patterns[0];
+        assert!(sandwich.attacker == m && sandwich.victims.len() == 1 && sandwich.victims[0] == v);
+        assert_eq!((sandwich.attack.clone(), sandwich.targets.clone()), (vec![0, 2], vec![1]));
+        // W's swap follows M's, which closes the sandwich, and B back-runs W,
+        // who swapped before and loses nothing
+        assert!(patterns[1].attacker == b && patterns[1].victims.is_empty());
+        assert_eq!((patterns[1].attack.clone(), patterns[1].targets.clone()), (vec![4], vec![3]));
+
+        let a = attribute(&s0, &txs, sandwich, &price_oracle);
+        assert!(a.extracted > 0.0 && a.losses[0].1 > 0.0);
+        // without M, V gets more t1 for the same t0
+        let without = counterfactual(&s0, &txs, &sandwich.attack);
+        let actual = simulate(&s0, &txs);
+        let more = without.get_balance(&v, &t1) - actual.get_balance(&v, &t1);
+        assert!((a.losses[0].1 - 1000.0 * more).abs() < 1e-6);
+    }
+
+    #[test]
+    fn jit_liquidity_dilutes_the_lps() {
+        let (s0, t0, t1) = setup();
+        let (o, v, j) = (User::new("O"), User::new("V"), User::new("J"));
+        let s1 = Deposit::new(&j, 100.0, &t0, 100.0, &t1).apply(&s0).unwrap();
+        let minted = s1.get_balance(&j, &Token::mint(&t0, &t1));
+        let txs: Vec<Box<dyn Transition>> = vec![
+            Box::new(Deposit::new(&j, 100.0, &t0, 100.0, &t1)),
+            Box::new(Swap::new(&v, &t0, &t1, 50.0)),
+            Box::new(Redeem::new(&j, &t0, &t1, minted)),
+        ];
+        let patterns = detect(&s0, &txs, &price_oracle);
+        assert_eq!(patterns.len(), 1);
+        let jit = &patterns[0];
+        assert_eq!((jit.kind, jit.attack.clone(), jit.targets.clone()), (Kind::Jit, vec![0, 2], vec![1]));
+        assert!(jit.victims.len() == 1 && jit.victims[0] == o);
+        // O loses what J takes, and what V gains from the better price of the
+        // deeper pool
+        let a = attribute(&s0, &txs, jit, &price_oracle);
+        let gain_v = attribute(&s0, &txs, &Pattern { victims: vec![v], ..jit.clone() }, &price_oracle).losses[0].1;
+        assert!(a.extracted > 0.0 && gain_v < 0.0);
+        // up to the LP rounding
+        assert!((a.losses[0].1 - a.extracted + gain_v).abs() < 1e-9 * s0.net_wealth(&price_oracle));
+    }
+
+    #[test]
+    fn the_victim_of_mev1_is_not_an_attacker() {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let (o, a, m) = (User::new("O"), User::new("A"), User::new("M"));
+        let mut s0 = State::new();
+        for u in [&o, &a, &m] {
+            s0.set_balance(u, &t0, 100.0);
+            s0.set_balance(u, &t1, 100.0);
+        }
+        let s0 = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s0).unwrap();
+        let txs: Vec<Box<dyn Transition>> = vec![
+            Box::new(Deposit::new(&o, 100.0, &t0, 100.0, &t1)),
+            Box::new(Swap::new(&m, &t1, &t0, 100.0 - SFr0(40.0, 35.0, 100.0, 100.0))),
+            Box::new(Swap::new(&a, &t0, &t1, 40.0)),
+            Box::new(Swap::new(&m, &t1, &t0, 38.3)),
+            Box::new(Deposit::new(&a, 30.0, &t0, 40.0, &t1)),
+            Box::new(Swap::new(&m, &t0, &t1, price_mini_transaction(1000.0, 1000.0, 117.0, 155.0))),
+            Box::new(Redeem::new(&a, &t0, &t1, 10.0)),
+        ];
+        let patterns = detect(&s0, &txs, &price_oracle);
+        assert!(!patterns.is_empty());
+        for p in &patterns {
+            assert!(p.attacker != a);
+            let attribution = attribute(&s0, &txs, p, &price_oracle);
+            assert!(attribution.extracted > 0.0);
+            assert!(attribution.losses.iter().all(|(_, l)| *l > 0.0));
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+
+use crate::agent::Simulation;
+use crate::{State, Token, Transition, TransitionError, User};
+
+// Dynamic swap fee: at every block boundary the fee of the pool is set from
+// the volatility of its price over the last blocks, within bounds. The pool
+// records for every block in which it was swapped the prices and the
+// realized variance of the block, the sum of the squared log price moves of
+// each swap, so that large swaps and price swings raise the fee even when the
+// price comes back by the end of the block.
+#[derive(Clone)]
+pub struct DynamicFee {
+    pub min: f64,
+    pub max: f64,
+    // fee added per unit of per-block volatility
+    pub sensitivity: f64,
+    // number of past blocks the volatility is measured on
+    pub window: u64,
+    pub history: Vec<PriceBar>,
+    // the spot price after the last recorded move
+    pub last_price: f64,
+}
+
+#[derive(Clone)]
+pub struct PriceBar {
+    pub block: u64,
+    pub open: f64,
+    pub close: f64,
+    pub variance: f64,
+}
+
+impl DynamicFee {
+    // Per-block volatility of the log price over the window before the block,
+    // blocks without swaps counting as still
+    pub fn volatility(&self, block: u64) -> f64 {
+        let from = block.saturating_sub(self.window);
+        let variance: f64 = self.history.iter().filter(|b| b.block >= from && b.block < block).map(|b| b.variance).sum();
+        (variance / self.window as f64).sqrt()
+    }
+
+    pub fn fee_at(&self, block: u64) -> f64 {
+        (self.min + self.sensitivity * self.volatility(block)).clamp(self.min, self.max)
+    }
+}
+
+impl State {
+    // Records the move of the price of the pool since the last one, in the
+    // bar of the current block. Called after every swap.
+    pub fn record_price_move(&mut self, t0: &Token, t1: &Token) {
+        self.record_price_move_at(t0, t1, self.block);
+    }
+
+    // The same in the bar of the given block, for the virtual orders executed
+    // in past blocks
+    pub(crate) fn record_price_move_at(&mut self, t0: &Token, t1: &Token, block: u64) {
+        let amm = match self.get_amm_mut(t0, t1) {
+            Some(amm) => amm,
+            None => return,
+        };
+        let price = match amm.spot_price(&amm.t0) {
+            Some(price) => price,
+            None => return,
+        };
+        let df = match amm.dynamic_fee.as_mut() {
+            Some(df) => df,
+            None => return,
+        };
+        let moved = (price / df.last_price).ln().powi(2);
+        match df.history.last_mut() {
+            Some(bar) if bar.block == block => {
+                bar.close = price;
+                bar.variance += moved;
+            }
+            _ => df.history.push(PriceBar { block, open: df.last_price, close: price, variance: moved }),
+        }
+        df.last_price = price;
+        // bars out of every future window are dropped
+        let from = block.saturating_sub(df.window);
+        df.history.retain(|b| b.block >= from);
+    }
+
+    // Sets the fee of every pool with a dynamic fee for the current block.
+    // Called at every block boundary.
+    pub fn update_dynamic_fees(&mut self) {
+        let block = self.block;
+        for amm in self.amms.iter_mut() {
+            if let Some(df) = &amm.dynamic_fee {
+                amm.fee = df.fee_at(block);
+            }
+        }
+    }
+}
+
+// Governance decision switching a pool to a dynamic fee between min and max,
+// starting from min with an empty history
+pub struct SetDynamicFee {
+    t0: Token,
+    t1: Token,
+    min: f64,
+    max: f64,
+    sensitivity: f64,
+    window: u64,
+}
+
+impl SetDynamicFee {
+    pub fn new(t0: &Token, t1: &Token, min: f64, max: f64, sensitivity: f64, window: u64) -> Self {
+        assert!(0.0 <= min && min <= max && max < 1.0 && sensitivity >= 0.0 && window > 0);
+        SetDynamicFee { t0: t0.clone(), t1: t1.clone(), min, max, sensitivity, window }
+    }
+}
+
+impl fmt::Display for SetDynamicFee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "gov:dynfee({},{},{},{},{},{})", self.t0, self.t1, self.min, self.max, self.sensitivity, self.window)
+    }
+}
+
+impl Transition for SetDynamicFee {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        let amm = post.get_amm_mut(&self.t0, &self.t1).ok_or(TransitionError::UnknownPool)?;
+        let last_price = amm.spot_price(&amm.t0).ok_or(TransitionError::InsufficientReserves)?;
+        amm.fee = self.min;
+        amm.dynamic_fee = Some(DynamicFee {
+            min: self.min,
+            max: self.max,
+            sensitivity: self.sensitivity,
+            window: self.window,
+            history: Vec::new(),
+            last_price,
+        });
+        Ok(post)
+    }
+}
+
+pub struct Report {
+    // the fee of the pool at every block
+    pub fees: Vec<f64>,
+    // net wealth of the LP minus that of holding its initial tokens
+    pub lp_vs_hold: f64,
+    // gain of the arbitrageur, i.e. the loss of the LP to arbitrage
+    pub arbitrage: f64,
+    // gain of the other agents
+    pub traders: f64,
+}
+
+// Runs the agents of the simulation on the pool of t0 and t1, with the
+// external price of t0 (in units of the numeraire t1) following a random walk
+// with the given volatility at every block. Wealth is valued at the final price.
+pub fn run(
+    s0: &State,
+    sim: &mut Simulation,
+    t0: &Token,
+    t1: &Token,
+    lp: &User,
+    arbitrageur: &User,
+    volatility: &[f64],
+) -> (State, Report) {
+    let mut price = sim.feed.price(s0, t0);
+    let mut s = s0.clone();
+    let mut trace = Vec::new();
+    let mut fees = Vec::new();
+    for sigma in volatility {
+        price *= (sigma * sim.rng.normal() - sigma * sigma / 2.0).exp();
+        sim.feed.set_price(t0, price).expect("the feed must be a manual oracle");
+        fees.push(s.get_fee(t0, t1));
+        s = sim.step(&s, &mut trace);
+    }
+
+    let feed = sim.feed.clone();
+    let f = |s: &State, t: &Token| feed.price(s, t);
+    // valuing the initial state at the final price compares with holding
+    let gain = |u: &User| s.net_wealth_user(u, &f) - s0.net_wealth_user(u, &f);
+    let traders = sim.agents.iter()
+        .map(|a| a.user())
+        .filter(|u| *u != lp && *u != arbitrageur)
+        .map(gain)
+        .sum();
+    let (lp_vs_hold, arbitrage) = (gain(lp), gain(arbitrageur));
+    (s, Report { fees, lp_vs_hold, arbitrage, traders })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::{Arbitrageur, NoiseTrader};
+    use crate::oracle::Oracle;
+    use crate::{AdvanceBlock, CreatePool, Curve, Deposit, Swap};
+
+    fn setup() -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 1000.0);
+        s.set_balance(&o, &t1, 1000.0);
+        for name in ["A", "N"] {
+            s.set_balance(&User::new(name), &t0, 1000.0);
+            s.set_balance(&User::new(name), &t1, 1000.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        let s = Deposit::new(&o, 1000.0, &t0, 1000.0, &t1).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    #[test]
+    fn fee_follows_the_volatility_of_the_last_blocks() {
+        let (s, t0, t1) = setup();
+        let a = User::new("A");
+        let s = SetDynamicFee::new(&t0, &t1, 0.001, 0.01, 0.1, 10).apply(&s).unwrap();
+        assert_eq!(s.get_fee(&t0, &t1), 0.001);
+        // a swap and its reverse leave the price where it was, but count
+        let s = Swap::new(&a, &t0, &t1, 50.0).apply(&s).unwrap();
+        let moved = s.spot_price(&t0, &t1).unwrap().ln();
+        let out = s.get_balance(&a, &t1) - 1000.0;
+        let s = Swap::new(&a, &t1, &t0, out).apply(&s).unwrap();
+        let back = s.spot_price(&t0, &t1).unwrap().ln() - moved;
+        assert_eq!(s.get_fee(&t0, &t1), 0.001);
+        let s = AdvanceBlock::new(1).apply(&s).unwrap();
+        let df = s.get_amm(&t0, &t1).unwrap().dynamic_fee.clone().unwrap();
+        assert_eq!(df.history.len(), 1);
+        assert!((df.volatility(1) - ((moved * moved + back * back) / 10.0).sqrt()).abs() < 1e-12);
+        let fee = s.get_fee(&t0, &t1);
+        assert!((fee - (0.001 + 0.1 * df.volatility(1))).abs() < 1e-12);
+        // bounded above, and back to the minimum once out of the window
+        let s = Swap::new(&a, &t0, &t1, 900.0).apply(&s).unwrap();
+        let s = AdvanceBlock::new(1).apply(&s).unwrap();
+        assert_eq!(s.get_fee(&t0, &t1), 0.01);
+        let s = AdvanceBlock::new(11).apply(&s).unwrap();
+        assert_eq!(s.get_fee(&t0, &t1), 0.001);
+    }
+
+    #[test]
+    fn fixed_and_dynamic_pools_in_a_volatile_market() {
+        let (s, t0, t1) = setup();
+        let (o, a, n) = (User::new("O"), User::new("A"), User::new("N"));
+        let dynamic = SetDynamicFee::new(&t0, &t1, 0.001, 0.05, 0.15, 10).apply(&s).unwrap();
+        let volatility: Vec<f64> = (0..200).map(|b| if b < 100 { 0.001 } else { 0.03 }).collect();
+        let simulate = |s: &State| {
+            let mut sim = Simulation::new(7, Oracle::Manual(vec![(t0.clone(), 1.0), (t1.clone(), 1.0)]));
+            sim.add_agent(NoiseTrader::new(&n, &t0, &t1, 0.5, 0.01));
+            sim.add_agent(Arbitrageur::new(&a, &t0, &t1));
+            run(s, &mut sim, &t0, &t1, &o, &a, &volatility).1
+        };
+        let (fixed, dynamic) = (simulate(&s), simulate(&dynamic));
+        assert!(fixed.fees.iter().all(|f| *f == 0.003));
+        // calm blocks are cheap, volatile ones expensive
+        assert!(dynamic.fees[50] < 0.003 && dynamic.fees[150] > 0.003);
+        // what the LP loses with respect to holding, the others gain
+        for r in [&fixed, &dynamic] {
+            assert!((r.lp_vs_hold + r.arbitrage + r.traders).abs() < 1e-3);
+        }
+        assert!(dynamic.arbitrage < fixed.arbitrage);
+    }
+}
@@ -0,0 +1,311 @@
+use std::fmt;
+
+use crate::agent::{simulate, Agent, Simulation, Slot};
+use crate::lending::{Liquidate, Withdraw};
+use crate::oracle::Oracle;
+use crate::rng::Rng;
+use crate::{State, Swap, Token, Transition, TransitionError, User};
+
+// Stepwise external price feed. The market price moves at every block, but
+// consumers on chain, here the lending market, only see the prices published
+// by a reporter at the start of a block, when the market moved by more than
+// a deviation threshold from the last published price or when a heartbeat
+// elapsed. In between, they read stale prices: positions can be underwater
+// at the market price without being liquidatable, until the next update,
+// which backrunners race to follow with their liquidations.
+
+// Publishes the market price of a token to the feed read by the lending
+// market, which must be an updatable Oracle::Manual
+pub struct UpdatePrice {
+    reporter: User,
+    token: Token,
+    price: f64,
+}
+
+impl UpdatePrice {
+    pub fn new(reporter: &User, token: &Token, price: f64) -> Self {
+        assert!(price > 0.0);
+        UpdatePrice { reporter: reporter.clone(), token: token.clone(), price }
+    }
+}
+
+impl fmt::Display for UpdatePrice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:update({},{})", self.reporter, self.token, self.price)
+    }
+}
+
+impl Transition for UpdatePrice {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        if pre.lending.reporter.as_ref() != Some(&self.reporter) {
+            return Err(TransitionError::Unauthorized);
+        }
+        let mut post = pre.clone();
+        post.lending.oracle.set_price(&self.token, self.price)?;
+        Ok(post)
+    }
+}
+
+// Publishes the prices of the external feed first in the block, when they
+// deviate from the published ones by more than the threshold or when the
+// heartbeat elapsed. Must be the first agent of the simulation.
+pub struct Reporter {
+    user: User,
+    tokens: Vec<Token>,
+    deviation: f64,
+    heartbeat: u64,
+    last: Option<u64>,
+}
+
+impl Reporter {
+    pub fn new(user: &User, tokens: &[Token], deviation: f64, heartbeat: u64) -> Self {
+        assert!(deviation >= 0.0 && heartbeat > 0);
+        Reporter { user: user.clone(), tokens: tokens.to_vec(), deviation, heartbeat, last: None }
+    }
+}
+
+impl Agent for Reporter {
+    fn user(&self) -> &User {
+        &self.user
+    }
+
+    fn act(&mut self, s: &State, pending: &[Box<dyn Transition>], feed: &Oracle, _rng: &mut Rng)
+        -> Vec<(Slot, Box<dyn Transition>)> {
+        let due = self.last.is_none_or(|last| s.block >= last + self.heartbeat);
+        let updates: Vec<Box<dyn Transition>> = self.tokens.iter()
+            .filter(|t| {
+                let (market, published) = (feed.price(s, t), s.lending.oracle.price(s, t));
+                due || (market / published - 1.0).abs() > self.deviation
+            })
+            .map(|t| Box::new(UpdatePrice::new(&self.user, t, feed.price(s, t))) as Box<dyn Transition>)
+            .collect();
+        if !updates.is_empty() {
+            self.last = Some(s.block);
+        }
+        let slot = || if pending.is_empty() { Slot::End } else { Slot::Before(0) };
+        updates.into_iter().map(|t| (slot(), t)).collect()
+    }
+}
+
+// Follows every price update with the liquidations it enables: repays the
+// whole debt of each position turned unhealthy and withdraws the collateral
+// seized, selling it on the pool for the debt token when this beats holding
+// it, as long as the whole pays at the market price
+pub struct Backrunner {
+    user: User,
+}
+
+impl Backrunner {
+    pub fn new(user: &User) -> Self {
+        Backrunner { user: user.clone() }
+    }
+
+    // The liquidation of the debt of the borrower with the collateral, and
+    // the state it leads to
+    fn liquidation(&self, s: &State, borrower: &User, debt_token: &Token, collateral: &Token, f: &dyn Fn(&State, &Token) -> f64)
+        -> Option<(Vec<Box<dyn Transition>>, State)> {
+        let debt = s.lending.get_debt(borrower, debt_token, s.block);
+        let supplied = s.lending.get_supplied(&self.user, collateral, s.block);
+        let liquidate = Liquidate::new(&self.user, borrower, debt_token, debt, collateral);
+        let post = liquidate.apply(s).ok()?;
+        let seized = post.lending.get_supplied(&self.user, collateral, post.block) - supplied;
+        let mut steps: Vec<Box<dyn Transition>> = vec![Box::new(liquidate)];
+        let mut post = post;
+        if seized > 0.0 {
+            let withdraw = Withdraw::new(&self.user, collateral, seized);
+            post = withdraw.apply(&post).ok()?;
+            steps.push(Box::new(withdraw));
+            let sell = Swap::new(&self.user, collateral, debt_token, seized);
+            if let Ok(sold) = sell.apply(&post) {
+                if sold.net_wealth_user(&self.user, f) > post.net_wealth_user(&self.user, f) {
+                    post = sold;
+                    steps.push(Box::new(sell));
+                }
+            }
+        }
+        Some((steps, post))
+    }
+}
+
+impl Agent for Backrunner {
+    fn user(&self) -> &User {
+        &self.user
+    }
+
+    fn act(&mut self, s: &State, pending: &[Box<dyn Transition>], feed: &Oracle, _rng: &mut Rng)
+        -> Vec<(Slot, Box<dyn Transition>)> {
+        let i = match pending.iter().rposition(|t| t.downcast_ref::<UpdatePrice>().is_some()) {
+            Some(i) => i,
+            None => return Vec::new(),
+        };
+        let mut s = simulate(s, &pending[..=i]);
+        let f = |s: &State, t: &Token| feed.price(s, t);
+        let mut borrowers: Vec<User> = Vec::new();
+        for p in s.lending.positions.iter().filter(|p| p.scaled_debt > 0.0 && p.user != self.user) {
+            if !borrowers.contains(&p.user) {
+                borrowers.push(p.user.clone());
+            }
+        }
+        let mut txs = Vec::new();
+        for b in borrowers {
+            let positions = s.lending.positions.clone();
+            let debts = positions.iter().filter(|p| p.user == b && p.scaled_debt > 0.0);
+            for d in debts {
+                for c in positions.iter().filter(|p| p.user == b && p.shares > 0.0 && p.token != d.token) {
+                    if s.health_factor(&b) >= 1.0 {
+                        continue;
+                    }
+                    if let Some((steps, post)) = self.liquidation(&s, &b, &d.token, &c.token, &f) {
+                        if post.net_wealth_user(&self.user, &f) > s.net_wealth_user(&self.user, &f) {
+                            txs.extend(steps);
+                            s = post;
+                        }
+                    }
+                }
+            }
+        }
+        txs.into_iter().map(|t| (Slot::After(i), t)).collect()
+    }
+}
+
+// Debt not covered by the collateral of its borrower, at the given prices:
+// the loss of the lenders if the borrowers walk away
+pub fn bad_debt(s: &State, f: &dyn Fn(&State, &Token) -> f64) -> f64 {
+    let mut users: Vec<&User> = Vec::new();
+    for p in &s.lending.positions {
+        if !users.contains(&&p.user) {
+            users.push(&p.user);
+        }
+    }
+    users.iter()
+        .map(|u| -s.position_value(u, f))
+        .filter(|v| *v > 0.0)
+        .fold(0.0, |total, v| total + v)
+}
+
+pub struct Report {
+    pub updates: usize,
+    pub liquidations: usize,
+    // mean relative gap between the published and the market price of the
+    // token, at the end of every block
+    pub staleness: f64,
+    // gain of the backrunner from its liquidations
+    pub extracted: f64,
+    pub bad_debt: f64,
+    // the largest bad debt at the end of a block
+    pub peak_bad_debt: f64,
+}
+
+// Runs the simulation with the market price of the token following the
+// given path, one price per block. The backrunner's gains are valued at the
+// market price of the block they are made in.
+pub fn run(s0: &State, sim: &mut Simulation, token: &Token, backrunner: &User, prices: &[f64]) -> (State, Report) {
+    let mut s = s0.clone();
+    let mut trace = Vec::new();
+    let (mut gap, mut extracted, mut peak) = (0.0, 0.0, 0.0f64);
+    for price in prices {
+        sim.feed.set_price(token, *price).expect("the feed must be a manual oracle");
+        let before = s.net_wealth_user(backrunner, &|s, t| sim.feed.price(s, t));
+        s = sim.step(&s, &mut trace);
+        extracted += s.net_wealth_user(backrunner, &|s, t| sim.feed.price(s, t)) - before;
+        gap += (s.lending.oracle.price(&s, token) / price - 1.0).abs();
+        peak = peak.max(bad_debt(&s, &|s, t| sim.feed.price(s, t)));
+    }
+    let count = |op: &str| trace.iter().filter(|step| step.error.is_none() && step.label.contains(op)).count();
+    let report = Report {
+        updates: count(":update("),
+        liquidations: count(":liquidate("),
+        staleness: gap / prices.len() as f64,
+        extracted,
+        bad_debt: bad_debt(&s, &|s, t| sim.feed.price(s, t)),
+        peak_bad_debt: peak,
+    };
+    (s, report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Arbitrageur;
+    use crate::lending::{Borrow, Supply};
+    use crate::{CreatePool, Curve, Deposit};
+
+    fn setup() -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 1000.0);
+        s.set_balance(&o, &t1, 2000.0);
+        s.set_balance(&User::new("M"), &t1, 1000.0);
+        s.set_balance(&User::new("A"), &t0, 1000.0);
+        s.set_balance(&User::new("A"), &t1, 1000.0);
+        s.lending.oracle = Oracle::Manual(vec![(t0.clone(), 1.0), (t1.clone(), 1.0)]);
+        s.lending.reporter = Some(User::new("R"));
+        s.lending.add_market(&t0, 0.0);
+        s.lending.add_market(&t1, 0.0);
+        let mut s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        s = Deposit::new(&o, 1000.0, &t0, 1000.0, &t1).apply(&s).unwrap();
+        s = Supply::new(&o, &t1, 1000.0).apply(&s).unwrap();
+        for (name, ltv) in [("B1", 0.6), ("B2", 0.7), ("B3", 0.74)] {
+            let b = User::new(name);
+            s.set_balance(&b, &t0, 100.0);
+            s = Supply::new(&b, &t0, 100.0).apply(&s).unwrap();
+            s = Borrow::new(&b, &t1, 100.0 * ltv).apply(&s).unwrap();
+        }
+        (s, t0, t1)
+    }
+
+    fn simulation(t0: &Token, t1: &Token, deviation: f64, heartbeat: u64) -> Simulation {
+        let mut sim = Simulation::new(0, Oracle::Manual(vec![(t0.clone(), 1.0), (t1.clone(), 1.0)]));
+        sim.add_agent(Reporter::new(&User::new("R"), std::slice::from_ref(t0), deviation, heartbeat));
+        sim.add_agent(Backrunner::new(&User::new("M")));
+        sim.add_agent(Arbitrageur::new(&User::new("A"), t0, t1));
+        sim
+    }
+
+    #[test]
+    fn updates_feed_the_lending_market() {
+        let (s, t0, _) = setup();
+        let r = User::new("R");
+        let post = UpdatePrice::new(&r, &t0, 0.9).apply(&s).unwrap();
+        assert_eq!(post.lending.oracle.price(&post, &t0), 0.9);
+        assert!(post.health_factor(&User::new("B3")) < 1.0);
+        let mut spot = s.clone();
+        spot.lending.oracle = Oracle::Spot(t0.clone());
+        assert!(matches!(UpdatePrice::new(&r, &t0, 0.9).apply(&spot), Err(TransitionError::UnknownFeed)));
+        let m = User::new("M");
+        assert!(matches!(UpdatePrice::new(&m, &t0, 0.9).apply(&s), Err(TransitionError::Unauthorized)));
+    }
+
+    #[test]
+    fn liquidations_backrun_the_updates() {
+        let (s, t0, t1) = setup();
+        let m = User::new("M");
+        let prices: Vec<f64> = (1..=30).map(|b| 0.98f64.powi(b)).collect();
+        let mut sim = simulation(&t0, &t1, 0.0, 1);
+        let (_, fresh) = run(&s, &mut sim, &t0, &m, &prices);
+        let mut trace = Vec::new();
+        let mut sim = simulation(&t0, &t1, 0.0, 1);
+        let mut post = s.clone();
+        for price in &prices {
+            sim.feed.set_price(&t0, *price).unwrap();
+            post = sim.step(&post, &mut trace);
+        }
+        // every liquidation comes right after an update
+        for (i, _) in trace.iter().enumerate().filter(|(_, step)| step.label.starts_with("M:liquidate(")) {
+            let before = trace[..i].iter().rev().find(|step| !step.label.starts_with("M:")).unwrap();
+            assert!(before.label.starts_with("R:update("));
+        }
+        assert_eq!(fresh.liquidations, 3);
+        // at the market price, the liquidations earn the bonus on the debt
+        assert!((fresh.extracted - 0.05 * 204.0).abs() < 1e-6 && fresh.peak_bad_debt == 0.0);
+
+        // a feed published every 20 blocks lets the positions sink
+        // underwater before they can be liquidated
+        let mut sim = simulation(&t0, &t1, 1.0, 20);
+        let (_, stale) = run(&s, &mut sim, &t0, &m, &prices);
+        assert!(stale.updates < fresh.updates && stale.staleness > fresh.staleness);
+        assert!(stale.bad_debt > 0.0 && stale.extracted < fresh.extracted);
+    }
+}
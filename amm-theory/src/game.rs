@@ -0,0 +1,259 @@
+use std::collections::HashSet;
+
+use crate::agent::simulate;
+use crate::{State, Token, Transition, User};
+
+// Finite games between users of a shared state. Each player picks one of
+// its candidate strategies, a sequence of transitions; the sequences are
+// interleaved round-robin in the order of the players (the first step of
+// every player, then the second, ...), so that e.g. a sandwich [front, back]
+// of a first player wraps the swap of a second one. Failing transitions are
+// skipped, and the payoff of a player is the change of its net wealth.
+
+pub struct Strategy {
+    pub label: String,
+    pub steps: Vec<Box<dyn Transition>>,
+}
+
+impl Strategy {
+    pub fn new(label: &str, steps: Vec<Box<dyn Transition>>) -> Self {
+        Strategy { label: String::from(label), steps }
+    }
+}
+
+pub struct Player {
+    pub user: User,
+    pub strategies: Vec<Strategy>,
+}
+
+pub struct Game {
+    pub s0: State,
+    pub players: Vec<Player>,
+}
+
+impl Game {
+    pub fn new(s0: &State) -> Self {
+        Game { s0: s0.clone(), players: Vec::new() }
+    }
+
+    pub fn add_player(&mut self, user: &User, strategies: Vec<Strategy>) {
+        assert!(!strategies.is_empty());
+        self.players.push(Player { user: user.clone(), strategies });
+    }
+
+    // The state reached when every player plays its strategy in the profile
+    pub fn play(&self, profile: &[usize]) -> State {
+        let chosen: Vec<&Strategy> = self.players.iter().zip(profile).map(|(p, i)| &p.strategies[*i]).collect();
+        let rounds = chosen.iter().map(|st| st.steps.len()).max().unwrap_or(0);
+        let mut s = self.s0.clone();
+        for round in 0..rounds {
+            for st in &chosen {
+                if let Some(t) = st.steps.get(round) {
+                    s = simulate(&s, std::slice::from_ref(t));
+                }
+            }
+        }
+        s
+    }
+
+    pub fn label(&self, profile: &[usize]) -> String {
+        self.players.iter().zip(profile)
+            .map(|(p, i)| format!("{}={}", p.user, p.strategies[*i].label))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    // Payoffs of every profile, with wealth valued by f
+    pub fn payoffs(&self, f: &dyn Fn(&State, &Token) -> f64) -> Payoffs {
+        let sizes: Vec<usize> = self.players.iter().map(|p| p.strategies.len()).collect();
+        let initial: Vec<f64> = self.players.iter().map(|p| self.s0.net_wealth_user(&p.user, f)).collect();
+        let values = profiles(&sizes)
+            .map(|profile| {
+                let s = self.play(&profile);
+                self.players.iter().zip(&initial).map(|(p, w)| s.net_wealth_user(&p.user, f) - w).collect()
+            })
+            .collect();
+        Payoffs { sizes, values }
+    }
+}
+
+// All the profiles, the strategy of the last player varying fastest
+fn profiles(sizes: &[usize]) -> impl Iterator<Item = Vec<usize>> + '_ {
+    let total: usize = sizes.iter().product();
+    (0..total).map(move |mut index| {
+        let mut profile = vec![0; sizes.len()];
+        for (i, size) in sizes.iter().enumerate().rev() {
+            profile[i] = index % size;
+            index /= size;
+        }
+        profile
+    })
+}
+
+// Deviations gaining less than this are not improvements
+const TOLERANCE: f64 = 1e-9;
+
+pub struct Payoffs {
+    pub sizes: Vec<usize>,
+    // the payoff of every player, for every profile in the order of profiles
+    pub values: Vec<Vec<f64>>,
+}
+
+pub struct Dynamics {
+    // the profiles visited, the starting one included
+    pub path: Vec<Vec<usize>>,
+    // whether a profile where no player wants to move was reached
+    pub converged: bool,
+}
+
+impl Payoffs {
+    fn index(&self, profile: &[usize]) -> usize {
+        profile.iter().zip(&self.sizes).fold(0, |index, (i, size)| index * size + i)
+    }
+
+    pub fn get(&self, profile: &[usize]) -> &[f64] {
+        &self.values[self.index(profile)]
+    }
+
+    pub fn profiles(&self) -> impl Iterator<Item = Vec<usize>> + '_ {
+        profiles(&self.sizes)
+    }
+
+    pub fn welfare(&self, profile: &[usize]) -> f64 {
+        self.get(profile).iter().sum()
+    }
+
+    // The best strategy of the player against the others in the profile,
+    // keeping the current one unless another is strictly better
+    pub fn best_response(&self, player: usize, profile: &[usize]) -> usize {
+        let mut deviation = profile.to_vec();
+        let mut best = (profile[player], self.get(profile)[player] + TOLERANCE);
+        for i in 0..self.sizes[player] {
+            deviation[player] = i;
+            let payoff = self.get(&deviation)[player];
+            if payoff > best.1 {
+                best = (i, payoff);
+            }
+        }
+        best.0
+    }
+
+    pub fn is_equilibrium(&self, profile: &[usize]) -> bool {
+        (0..self.sizes.len()).all(|player| self.best_response(player, profile) == profile[player])
+    }
+
+    pub fn nash_equilibria(&self) -> Vec<Vec<usize>> {
+        self.profiles().filter(|p| self.is_equilibrium(p)).collect()
+    }
+
+    // Players take turns switching to their best response, until none of
+    // them wants to, or the dynamics cycle: the path then ends where the
+    // cycle closes
+    pub fn best_response_dynamics(&self, start: &[usize]) -> Dynamics {
+        let mut profile = start.to_vec();
+        let mut path = vec![profile.clone()];
+        let mut seen = HashSet::new();
+        let mut idle = 0;
+        let mut player = 0;
+        while idle < self.sizes.len() {
+            if !seen.insert((profile.clone(), player)) {
+                return Dynamics { path, converged: false };
+            }
+            let best = self.best_response(player, &profile);
+            if best == profile[player] {
+                idle += 1;
+            } else {
+                profile[player] = best;
+                path.push(profile.clone());
+                idle = 0;
+            }
+            player = (player + 1) % self.sizes.len();
+        }
+        Dynamics { path, converged: true }
+    }
+
+    // The profile maximizing the sum of the payoffs
+    pub fn optimum(&self) -> (Vec<usize>, f64) {
+        self.profiles()
+            .map(|p| {
+                let w = self.welfare(&p);
+                (p, w)
+            })
+            .fold((Vec::new(), f64::NEG_INFINITY), |best, (p, w)| if w > best.1 { (p, w) } else { best })
+    }
+
+    // Welfare lost in the profile with respect to the cooperative optimum
+    pub fn welfare_loss(&self, profile: &[usize]) -> f64 {
+        self.optimum().1 - self.welfare(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::oracle::Oracle;
+    use crate::{CreatePool, Curve, Deposit, Swap};
+
+    // Two traders both value t1 above the pool price: each buying more is
+    // individually better, but they push the price against each other
+    fn buyers() -> (Game, Oracle) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        for name in ["A", "B"] {
+            s.set_balance(&User::new(name), &t0, 50.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        let mut game = Game::new(&s);
+        for name in ["A", "B"] {
+            let u = User::new(name);
+            game.add_player(&u, vec![
+                Strategy::new("none", Vec::new()),
+                Strategy::new("small", vec![Box::new(Swap::new(&u, &t0, &t1, 5.0))]),
+                Strategy::new("large", vec![Box::new(Swap::new(&u, &t0, &t1, 20.0))]),
+            ]);
+        }
+        (game, Oracle::Manual(vec![(t0, 1.0), (t1, 1.3)]))
+    }
+
+    #[test]
+    fn profiles_interleave_the_strategies() {
+        let (game, feed) = buyers();
+        let payoffs = game.payoffs(&|s, t| feed.price(s, t));
+        assert_eq!(payoffs.values.len(), 9);
+        assert_eq!(payoffs.get(&[0, 0]), &[0.0, 0.0]);
+        // the first player trades at the better price
+        let both = payoffs.get(&[2, 2]);
+        assert!(both[0] > both[1]);
+        assert_eq!(game.label(&[1, 2]), "A=small B=large");
+        // and moves the price against the second one
+        assert!(payoffs.get(&[2, 2])[1] < payoffs.get(&[0, 2])[1]);
+    }
+
+    #[test]
+    fn equilibria_and_welfare_loss() {
+        let (game, feed) = buyers();
+        let payoffs = game.payoffs(&|s, t| feed.price(s, t));
+        let equilibria = payoffs.nash_equilibria();
+        assert!(!equilibria.is_empty());
+        for eq in &equilibria {
+            for player in 0..2 {
+                for i in 0..3 {
+                    let mut deviation = eq.clone();
+                    deviation[player] = i;
+                    assert!(payoffs.get(&deviation)[player] <= payoffs.get(eq)[player] + 1e-9);
+                }
+            }
+            assert!(payoffs.welfare_loss(eq) >= 0.0);
+        }
+        let dynamics = payoffs.best_response_dynamics(&[0, 0]);
+        assert!(dynamics.converged && equilibria.contains(dynamics.path.last().unwrap()));
+        let (optimum, welfare) = payoffs.optimum();
+        assert!(payoffs.profiles().all(|p| payoffs.welfare(&p) <= welfare));
+        assert_eq!(payoffs.welfare_loss(&optimum), 0.0);
+    }
+}
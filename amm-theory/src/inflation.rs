@@ -0,0 +1,125 @@
+use crate::{Deposit, Donate, State, Token, Transition, User};
+
+// A donation that inflated the value of the LP tokens of a pool
+pub struct InflationAlert {
+    // index of the donation in the trace
+    pub step: usize,
+    pub donor: User,
+    pub lp_token: Token,
+    // value of one LP token before and after the donation
+    pub share_price_before: f64,
+    pub share_price_after: f64,
+    // later depositors in the pool, with the value they lost to the rounding
+    // of the LP tokens they were minted
+    pub victims: Vec<(User, f64)>,
+    // change of the net wealth of the donor, from before the donation to
+    // the end of the trace
+    pub donor_gain: f64,
+}
+
+// Value of one LP token of the pool of t0 and t1
+fn share_price(s: &State, t0: &Token, t1: &Token, f: &dyn Fn(&State, &Token) -> f64) -> f64 {
+    let supply = s.token_supply(&Token::mint(t0, t1));
+    if supply == 0.0 {
+        return 0.0;
+    }
+    (s.get_reserves(t0, t1) * f(s, t0) + s.get_reserves(t1, t0) * f(s, t1)) / supply
+}
+
+// Replays the trace from s0, flagging the donations that multiply the value
+// of the LP tokens of a pool at least by the given factor (first-depositor
+// inflation attacks), and the deposits in the same pool that follow them
+pub fn detect_inflation(s0: &State, trace: &[Box<dyn Transition>], f: &dyn Fn(&State, &Token) -> f64, factor: f64)
+    -> Vec<InflationAlert> {
+    let mut alerts: Vec<(InflationAlert, f64)> = Vec::new();
+    let mut s = s0.clone();
+    for (i, t) in trace.iter().enumerate() {
+        let post = match t.apply(&s) {
+            Ok(post) => post,
+            Err(_) => continue,
+        };
+        if let Some(donation) = t.downcast_ref::<Donate>() {
+            let before = share_price(&s, &donation.t0, &donation.t1, f);
+            let after = share_price(&post, &donation.t0, &donation.t1, f);
+            if before > 0.0 && after >= before * factor {
+                let alert = InflationAlert {
+                    step: i,
+                    donor: donation.sender.clone(),
+                    lp_token: Token::mint(&donation.t0, &donation.t1),
+                    share_price_before: before,
+                    share_price_after: after,
+                    victims: Vec::new(),
+                    donor_gain: 0.0,
+                };
+                alerts.push((alert, s.net_wealth_user(&donation.sender, f)));
+            }
+        }
+        if let Some(deposit) = t.downcast_ref::<Deposit>() {
+            let lp_token = Token::mint(&deposit.t0, &deposit.t1);
+            for (alert, _) in alerts.iter_mut().filter(|(a, _)| a.lp_token == lp_token && a.donor != deposit.sender) {
+                let minted = post.get_balance(&deposit.sender, &lp_token) - s.get_balance(&deposit.sender, &lp_token);
+                let paid = deposit.v0 * f(&s, &deposit.t0) + deposit.v1 * f(&s, &deposit.t1);
+                let loss = paid - minted * share_price(&post, &deposit.t0, &deposit.t1, f);
+                alert.victims.push((deposit.sender.clone(), loss));
+            }
+        }
+        s = post;
+    }
+    alerts.into_iter()
+        .map(|(mut alert, wealth_before)| {
+            alert.donor_gain = s.net_wealth_user(&alert.donor, f) - wealth_before;
+            alert
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_oracle, CreatePool, Curve, Redeem, TransitionError, LP_UNIT, MINIMUM_LIQUIDITY};
+
+    #[test]
+    fn donation_after_a_dust_deposit_is_flagged() {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let m = User::new("M");
+        let a = User::new("A");
+        let mut s0 = State::new();
+        s0.set_balance(&m, &t0, 200.0);
+        s0.set_balance(&m, &t1, 200.0);
+        s0.set_balance(&a, &t0, 100.0);
+        s0.set_balance(&a, &t1, 100.0);
+        let dust = MINIMUM_LIQUIDITY + LP_UNIT;
+        let trace: Vec<Box<dyn Transition>> = vec![
+            Box::new(CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0)),
+            Box::new(Deposit::new(&m, dust, &t0, dust, &t1)),
+            Box::new(Donate::new(&m, 100.0, &t0, 100.0, &t1)),
+            Box::new(Deposit::new(&a, 100.0, &t0, 100.0, &t1)),
+            Box::new(Redeem::new(&m, &t0, &t1, 2.0 * dust - MINIMUM_LIQUIDITY)),
+        ];
+        let alerts = detect_inflation(&s0, &trace, &price_oracle, 2.0);
+        assert_eq!(alerts.len(), 1);
+        let alert = &alerts[0];
+        assert_eq!(alert.step, 2);
+        assert!(alert.share_price_after > 1e6 * alert.share_price_before);
+        assert_eq!(alert.victims.len(), 1);
+        let (victim, loss) = &alert.victims[0];
+        // the victim only loses the rounding of its LP tokens, worth less than
+        // one LP_UNIT at the inflated share price
+        assert!(*victim == a && *loss > 0.0 && *loss < LP_UNIT * alert.share_price_after);
+        // the locked liquidity is about half of the supply when the donation
+        // lands, so it keeps about half of the donation: the attack does not pay
+        let donation = 100.0 * price_oracle(&s0, &t0) + 100.0 * price_oracle(&s0, &t1);
+        assert!((alert.donor_gain + 0.5 * donation).abs() < 0.01 * donation);
+    }
+
+    #[test]
+    fn pools_of_lp_tokens_are_rejected() {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&State::new()).unwrap();
+        let lp = Token::mint(&t0, &t1);
+        assert!(matches!(CreatePool::new(&lp, &t0, Curve::ConstantProduct, 0.0).apply(&s), Err(TransitionError::NonAtomicToken)));
+        assert!(matches!(CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s), Err(TransitionError::PoolExists)));
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Sub};
+
+use crate::{lp_round, Curve, Deposit, FeeMode, Redeem, State, Swap, Token, Transition, TransitionError, User,
+    MINIMUM_LIQUIDITY};
+
+// Interval backend: balances, reserves and LP supplies as intervals [lo, hi]
+// enclosing their value in exact real arithmetic. Every operation rounds its
+// bounds outwards by one ulp, so that the f64 rounding of a sequence of
+// transitions is bounded, and a gain whose interval contains zero cannot be
+// told apart from an artifact of rounding.
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Interval {
+    pub lo: f64,
+    pub hi: f64,
+}
+
+impl Interval {
+    pub fn new(lo: f64, hi: f64) -> Self {
+        assert!(lo <= hi);
+        Interval { lo, hi }
+    }
+
+    pub fn point(v: f64) -> Self {
+        Interval { lo: v, hi: v }
+    }
+
+    fn outward(lo: f64, hi: f64) -> Self {
+        Interval { lo: lo.next_down(), hi: hi.next_up() }
+    }
+
+    pub fn width(&self) -> f64 {
+        self.hi - self.lo
+    }
+
+    pub fn contains(&self, v: f64) -> bool {
+        self.lo <= v && v <= self.hi
+    }
+
+    pub fn min(self, other: Interval) -> Interval {
+        Interval { lo: self.lo.min(other.lo), hi: self.hi.min(other.hi) }
+    }
+}
+
+impl Add for Interval {
+    type Output = Interval;
+    fn add(self, other: Interval) -> Interval {
+        Interval::outward(self.lo + other.lo, self.hi + other.hi)
+    }
+}
+
+impl Sub for Interval {
+    type Output = Interval;
+    fn sub(self, other: Interval) -> Interval {
+        Interval::outward(self.lo - other.hi, self.hi - other.lo)
+    }
+}
+
+impl Mul for Interval {
+    type Output = Interval;
+    fn mul(self, other: Interval) -> Interval {
+        let products = [self.lo * other.lo, self.lo * other.hi, self.hi * other.lo, self.hi * other.hi];
+        Interval::outward(products.iter().copied().fold(f64::INFINITY, f64::min),
+            products.iter().copied().fold(f64::NEG_INFINITY, f64::max))
+    }
+}
+
+impl Div for Interval {
+    type Output = Interval;
+    fn div(self, other: Interval) -> Interval {
+        if other.contains(0.0) {
+            return Interval { lo: f64::NEG_INFINITY, hi: f64::INFINITY };
+        }
+        self * Interval::outward(1.0 / other.hi, 1.0 / other.lo)
+    }
+}
+
+impl fmt::Display for Interval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match f.precision() {
+            Some(p) => write!(f, "[{:.*}, {:.*}]", p, self.lo, p, self.hi),
+            None => write!(f, "[{}, {}]", self.lo, self.hi),
+        }
+    }
+}
+
+// A state with the balances, reserves and LP supplies of its pools enclosed
+// in intervals, along with the f64 state they enclose. Only swaps, deposits
+// and redeems are supported, on pools without long-term orders nor protocol
+// fees minted as LP tokens.
+#[derive(Clone)]
+pub struct Enclosure {
+    pub state: State,
+    values: HashMap<String, Interval>,
+}
+
+impl Enclosure {
+    pub fn new(s: &State) -> Self {
+        let mut values = HashMap::new();
+        for w in &s.wallets {
+            for b in &w.balances {
+                values.insert(format!("{}.{}", w.user, b.token), Interval::point(b.value));
+            }
+        }
+        for amm in &s.amms {
+            let lp_token = Token::mint(&amm.t0, &amm.t1);
+            values.insert(format!("{}.{}", lp_token, amm.t0), Interval::point(amm.r0));
+            values.insert(format!("{}.{}", lp_token, amm.t1), Interval::point(amm.r1));
+            values.insert(format!("{}.supply", lp_token), Interval::point(s.token_supply(&lp_token)));
+        }
+        Enclosure { state: s.clone(), values }
+    }
+
+    fn value(&self, key: String) -> Interval {
+        self.values.get(&key).copied().unwrap_or(Interval::point(0.0))
+    }
+
+    pub fn balance(&self, u: &User, t: &Token) -> Interval {
+        self.value(format!("{}.{}", u, t))
+    }
+
+    pub fn reserve(&self, t: &Token, other: &Token) -> Interval {
+        self.value(format!("{}.{}", Token::mint(t, other), t))
+    }
+
+    pub fn supply(&self, lp_token: &Token) -> Interval {
+        self.value(format!("{}.supply", lp_token))
+    }
+
+    fn set_balance(&mut self, u: &User, t: &Token, v: Interval) {
+        self.values.insert(format!("{}.{}", u, t), v);
+    }
+
+    fn set_reserve(&mut self, t: &Token, other: &Token, v: Interval) {
+        self.values.insert(format!("{}.{}", Token::mint(t, other), t), v);
+    }
+
+    fn set_supply(&mut self, lp_token: &Token, v: Interval) {
+        self.values.insert(format!("{}.supply", lp_token), v);
+    }
+
+    fn check_pool(&self, t0: &Token, t1: &Token) -> Result<(), TransitionError> {
+        let amm = self.state.get_amm(t0, t1).ok_or(TransitionError::UnknownPool)?;
+        let minted = amm.protocol_fee.as_ref().is_some_and(|pf| pf.mode == FeeMode::Minted && pf.fraction > 0.0);
+        let orders = self.state.twamm.orders.iter().any(|o| Token::mint(&o.tin, &o.tout) == Token::mint(t0, t1));
+        if minted || orders {
+            return Err(TransitionError::Unimplemented);
+        }
+        Ok(())
+    }
+
+    // The output and the reserve left are computed in forms where every
+    // reserve occurs once, so that their intervals stay tight
+    fn swap(&mut self, t: &Swap) -> Result<(), TransitionError> {
+        self.check_pool(&t.tin, &t.tout)?;
+        let amm = self.state.get_amm(&t.tin, &t.tout).unwrap();
+        let (rin, rout) = (self.reserve(&t.tin, &t.tout), self.reserve(&t.tout, &t.tin));
+        let x = Interval::point(t.x);
+        let xg = x * (Interval::point(1.0) - Interval::point(amm.fee));
+        let (out, post_rout) = match amm.curve {
+            Curve::ConstantProduct => (rout * xg / (rin + xg), rout * rin / (rin + xg)),
+            Curve::ConstantSum => (xg, rout - xg),
+        };
+        if out.hi >= rout.lo {
+            return Err(TransitionError::InsufficientReserves);
+        }
+        let mut post_rin = rin + x;
+        if let Some(pf) = amm.protocol_fee.as_ref().filter(|pf| pf.mode == FeeMode::Tokens) {
+            let cut = x * Interval::point(amm.fee) * Interval::point(pf.fraction);
+            let treasury = pf.treasury.clone();
+            self.set_balance(&treasury, &t.tin, self.balance(&treasury, &t.tin) + cut);
+            post_rin = post_rin - cut;
+        }
+        self.set_balance(&t.sender, &t.tin, self.balance(&t.sender, &t.tin) - x);
+        self.set_balance(&t.sender, &t.tout, self.balance(&t.sender, &t.tout) + out);
+        self.set_reserve(&t.tin, &t.tout, post_rin);
+        self.set_reserve(&t.tout, &t.tin, post_rout);
+        Ok(())
+    }
+
+    // LP tokens are rounded down to LP_UNIT like in Deposit, which is exact
+    // on the bounds. The first deposit locks the minimum liquidity.
+    fn deposit(&mut self, t: &Deposit) -> Result<(), TransitionError> {
+        self.check_pool(&t.t0, &t.t1)?;
+        let lp_token = Token::mint(&t.t0, &t.t1);
+        let (v0, v1) = (Interval::point(t.v0), Interval::point(t.v1));
+        let (r0, r1) = (self.reserve(&t.t0, &t.t1), self.reserve(&t.t1, &t.t0));
+        let supply = self.supply(&lp_token);
+        let first = self.state.token_supply(&lp_token) == 0.0;
+        let (minted, locked) = if first {
+            (v0 + v1 - Interval::point(MINIMUM_LIQUIDITY), Interval::point(MINIMUM_LIQUIDITY))
+        } else {
+            (supply * (v0 / r0).min(v1 / r1), Interval::point(0.0))
+        };
+        let minted = Interval { lo: lp_round(minted.lo), hi: lp_round(minted.hi) };
+        self.set_balance(&t.sender, &t.t0, self.balance(&t.sender, &t.t0) - v0);
+        self.set_balance(&t.sender, &t.t1, self.balance(&t.sender, &t.t1) - v1);
+        self.set_balance(&t.sender, &lp_token, self.balance(&t.sender, &lp_token) + minted);
+        self.set_reserve(&t.t0, &t.t1, r0 + v0);
+        self.set_reserve(&t.t1, &t.t0, r1 + v1);
+        self.set_supply(&lp_token, supply + minted + locked);
+        Ok(())
+    }
+
+    fn redeem(&mut self, t: &Redeem) -> Result<(), TransitionError> {
+        self.check_pool(&t.t0, &t.t1)?;
+        let lp_token = Token::mint(&t.t0, &t.t1);
+        let v = Interval::point(t.v);
+        let supply = self.supply(&lp_token);
+        let left = supply - v;
+        for (a, b) in [(&t.t0, &t.t1), (&t.t1, &t.t0)] {
+            let r = self.reserve(a, b);
+            self.set_balance(&t.sender, a, self.balance(&t.sender, a) + r * v / supply);
+            self.set_reserve(a, b, r * left / supply);
+        }
+        self.set_balance(&t.sender, &lp_token, self.balance(&t.sender, &lp_token) - v);
+        self.set_supply(&lp_token, left);
+        Ok(())
+    }
+
+    // The enclosure after the transition, which is applied to the f64 state
+    // too and fails when it does
+    pub fn apply(&self, t: &dyn Transition) -> Result<Enclosure, TransitionError> {
+        let mut post = self.clone();
+        if let Some(swap) = t.downcast_ref::<Swap>() {
+            post.swap(swap)?;
+        } else if let Some(deposit) = t.downcast_ref::<Deposit>() {
+            post.deposit(deposit)?;
+        } else if let Some(redeem) = t.downcast_ref::<Redeem>() {
+            post.redeem(redeem)?;
+        } else {
+            return Err(TransitionError::Unimplemented);
+        }
+        post.state = t.apply(&self.state)?;
+        Ok(post)
+    }
+
+    // Value of the wallet of u: atomic tokens at their price by f in the f64
+    // state, LP tokens at the value of their share of the reserves. Lending
+    // positions, stakes and orders are left out, as the transitions leave them
+    // alone.
+    pub fn wealth(&self, u: &User, f: &dyn Fn(&State, &Token) -> f64) -> Interval {
+        let mut sum = Interval::point(0.0);
+        let wallet = match self.state.wallets.iter().find(|w| w.user == *u) {
+            Some(w) => w,
+            None => return sum,
+        };
+        for b in &wallet.balances {
+            let balance = self.balance(u, &b.token);
+            let value = match &b.token {
+                Token::Atomic(_) => balance * Interval::point(f(&self.state, &b.token)),
+                Token::Minted(d0, d1) => {
+                    let (t0, t1) = (Token::Atomic(d0.clone()), Token::Atomic(d1.clone()));
+                    let price = |t: &Token| Interval::point(f(&self.state, t));
+                    let reserves = self.reserve(&t0, &t1) * price(&t0) + self.reserve(&t1, &t0) * price(&t1);
+                    balance * reserves / self.supply(&b.token)
+                }
+            };
+            sum = sum + value;
+        }
+        sum
+    }
+}
+
+pub struct Gain {
+    pub user: User,
+    // gain in f64, and its enclosure
+    pub value: f64,
+    pub interval: Interval,
+}
+
+impl Gain {
+    // Whether the sign of the gain is certain despite rounding
+    pub fn robust(&self) -> bool {
+        self.interval.lo > 0.0 || self.interval.hi < 0.0
+    }
+}
+
+// Gains of the users of s0 over the sequence, as computed in f64 and enclosed
+pub fn gains(s0: &State, txs: &[Box<dyn Transition>], f: &dyn Fn(&State, &Token) -> f64)
+    -> Result<(Enclosure, Vec<Gain>), TransitionError> {
+    let e0 = Enclosure::new(s0);
+    let mut e = e0.clone();
+    for t in txs {
+        e = e.apply(t.as_ref())?;
+    }
+    let gains = s0.wallets.iter()
+        .map(|w| Gain {
+            user: w.user.clone(),
+            value: e.state.net_wealth_user(&w.user, f) - s0.net_wealth_user(&w.user, f),
+            interval: e.wealth(&w.user, f) - e0.wealth(&w.user, f),
+        })
+        .collect();
+    Ok((e, gains))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_oracle, CreatePool};
+
+    #[test]
+    fn operations_round_outwards() {
+        let (a, b) = (Interval::point(0.1), Interval::point(0.2));
+        let sum = a + b;
+        assert!(sum.lo < 0.1 + 0.2 && 0.1 + 0.2 < sum.hi);
+        assert!(sum.width() < 1e-15);
+        let q = Interval::point(1.0) / Interval::point(3.0);
+        assert!(q.lo < 1.0 / 3.0 && 1.0 / 3.0 < q.hi);
+        assert_eq!((a - a).lo.signum(), -1.0);
+        assert_eq!(Interval::new(2.0, 3.0) * Interval::new(-1.0, 1.0), Interval::outward(-3.0, 3.0));
+        assert_eq!((a / Interval::new(-1.0, 1.0)).hi, f64::INFINITY);
+    }
+
+    fn setup(fee: f64) -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let mut s = State::new();
+        for name in ["O", "A", "M"] {
+            s.set_balance(&User::new(name), &t0, 100.0);
+            s.set_balance(&User::new(name), &t1, 100.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, fee).apply(&s).unwrap();
+        let s = Deposit::new(&User::new("O"), 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    #[test]
+    fn sandwich_profit_is_robust() {
+        let (s0, t0, t1) = setup(0.003);
+        let (o, a, m) = (User::new("O"), User::new("A"), User::new("M"));
+        let txs: Vec<Box<dyn Transition>> = vec![
+            Box::new(Swap::new(&m, &t0, &t1, 10.0)),
+            Box::new(Swap::new(&a, &t0, &t1, 20.0)),
+            Box::new(Swap::new(&m, &t1, &t0, 9.0)),
+            Box::new(Redeem::new(&o, &t0, &t1, 50.0)),
+        ];
+        let (e, gains) = gains(&s0, &txs, &price_oracle).unwrap();
+        for g in &gains {
+            assert!(g.robust() && g.interval.contains(g.value) && g.interval.width() < 1e-6);
+        }
+        assert!(gains[2].user == m && gains[2].value > 0.0);
+        // the enclosures are tight around the f64 state
+        let r0 = e.reserve(&t0, &t1);
+        assert!(r0.contains(e.state.get_reserves(&t0, &t1)) && r0.width() < 1e-12);
+        assert!(e.balance(&o, &Token::mint(&t0, &t1)).contains(e.state.get_balance(&o, &Token::mint(&t0, &t1))));
+    }
+
+    #[test]
+    fn round_trip_gain_is_an_artifact() {
+        // without fee, swapping back the output gives exactly the input back
+        let (s0, t0, t1) = setup(0.0);
+        let a = User::new("A");
+        let out = Swap::new(&a, &t0, &t1, 7.3).apply(&s0).unwrap().get_balance(&a, &t1) - 100.0;
+        let txs: Vec<Box<dyn Transition>> = vec![
+            Box::new(Swap::new(&a, &t0, &t1, 7.3)),
+            Box::new(Swap::new(&a, &t1, &t0, out)),
+        ];
+        let (_, gains) = gains(&s0, &txs, &price_oracle).unwrap();
+        let g = gains.iter().find(|g| g.user == a).unwrap();
+        assert!(!g.robust() && g.interval.contains(0.0));
+        assert!(g.value.abs() < 1e-9);
+        // other transitions are not supported
+        let e = Enclosure::new(&s0);
+        assert!(matches!(e.apply(&crate::AdvanceBlock::new(1)), Err(TransitionError::Unimplemented)));
+    }
+}
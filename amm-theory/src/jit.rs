@@ -0,0 +1,195 @@
+use crate::agent::{simulate, Agent, Slot};
+use crate::oracle::Oracle;
+use crate::rng::Rng;
+use crate::{Deposit, Redeem, State, Swap, Token, Transition, TransitionError, User};
+
+// Just-in-time liquidity: deposit right before a large swap and redeem right
+// after, taking a share of its fee (and of its price impact) from the LPs
+// already in the pool.
+//
+// Depositing m times the reserves gives a share m/(1+m) of the pool. Valued at
+// the pool price before the swap, a swap of x units of a token of reserve r
+// leaves the pool x*(fee*R + g*x)/(R + g*x) units of that token, with
+// R = (1+m)*r and g = 1-fee: a deeper pool gives the swapper a better price,
+// so the gain of the JIT LP is bounded unless the fee dominates.
+
+// Multiple of the reserves to deposit maximizing the gain of the JIT LP,
+// or None when the gain grows with the deposit
+pub fn optimal_jit_multiple(r_in: f64, x: f64, fee: f64) -> Option<f64> {
+    let c = x * (1.0 - fee);
+    let a = c * (1.0 - fee) - fee * r_in;
+    if a <= 0.0 {
+        return None;
+    }
+    let depth = c * (r_in + (r_in * r_in + a * r_in).sqrt()) / a;
+    Some(depth / r_in - 1.0)
+}
+
+// Gain of the JIT LP predicted by the formula above, in units of the input token
+pub fn jit_gain(r_in: f64, x: f64, fee: f64, multiple: f64) -> f64 {
+    let depth = (1.0 + multiple) * r_in;
+    let c = x * (1.0 - fee);
+    multiple / (1.0 + multiple) * x * (fee * depth + c) / (depth + c)
+}
+
+// The deposit and redeem of a JIT LP adding multiple times the reserves of
+// the pool of the victim's swap
+pub fn jit_transitions(s: &State, attacker: &User, victim: &Swap, multiple: f64)
+    -> Result<(Deposit, Redeem), TransitionError> {
+    let amm = s.get_amm(&victim.tin, &victim.tout).ok_or(TransitionError::UnknownPool)?;
+    let (t0, t1) = (amm.t0.clone(), amm.t1.clone());
+    let deposit = Deposit::new(attacker, multiple * amm.r0, &t0, multiple * amm.r1, &t1);
+    let lp_token = Token::mint(&t0, &t1);
+    let minted = deposit.apply(s)?.get_balance(attacker, &lp_token) - s.get_balance(attacker, &lp_token);
+    Ok((deposit, Redeem::new(attacker, &t0, &t1, minted)))
+}
+
+pub struct JitReport {
+    pub attacker_gain: f64,
+    // gain of the LPs already in the pool, with and without the attack
+    pub lp_gain: f64,
+    pub lp_gain_without: f64,
+    // output of the victim's swap, with and without the attack
+    pub victim_out: f64,
+    pub victim_out_without: f64,
+}
+
+// Splits the value of the victim's swap between the JIT LP and the LPs
+// already in the pool, comparing with the swap alone
+pub fn jit_report(s: &State, attacker: &User, victim: &Swap, multiple: f64, f: &dyn Fn(&State, &Token) -> f64)
+    -> Result<JitReport, TransitionError> {
+    let lp_token = Token::mint(&victim.tin, &victim.tout);
+    let lps: Vec<User> = s.wallets.iter()
+        .filter(|w| w.user != *attacker && w.get_balance(&lp_token) > 0.0)
+        .map(|w| w.user.clone())
+        .collect();
+    let lp_wealth = |s: &State| lps.iter().map(|u| s.net_wealth_user(u, f)).sum::<f64>();
+    let received = |s0: &State, s1: &State| s1.get_balance(&victim.sender, &victim.tout) - s0.get_balance(&victim.sender, &victim.tout);
+
+    let without = victim.apply(s)?;
+    let (deposit, redeem) = jit_transitions(s, attacker, victim, multiple)?;
+    let s1 = deposit.apply(s)?;
+    let s2 = victim.apply(&s1)?;
+    let s3 = redeem.apply(&s2)?;
+    Ok(JitReport {
+        attacker_gain: s3.net_wealth_user(attacker, f) - s.net_wealth_user(attacker, f),
+        lp_gain: lp_wealth(&s3) - lp_wealth(s),
+        lp_gain_without: lp_wealth(&without) - lp_wealth(s),
+        victim_out: received(&s1, &s2),
+        victim_out_without: received(s, &without),
+    })
+}
+
+// Provides liquidity around the largest pending swap of at least min_size,
+// with the optimal deposit capped by its balances
+pub struct JitBot {
+    user: User,
+    min_size: f64,
+}
+
+impl JitBot {
+    pub fn new(user: &User, min_size: f64) -> Self {
+        JitBot { user: user.clone(), min_size }
+    }
+}
+
+impl Agent for JitBot {
+    fn user(&self) -> &User {
+        &self.user
+    }
+
+    fn act(&mut self, s: &State, pending: &[Box<dyn Transition>], feed: &Oracle, _rng: &mut Rng)
+        -> Vec<(Slot, Box<dyn Transition>)> {
+        let victim = pending.iter().enumerate()
+            .filter_map(|(i, t)| t.downcast_ref::<Swap>().map(|swap| (i, swap)))
+            .filter(|(_, swap)| swap.sender != self.user && swap.x >= self.min_size)
+            .max_by(|(_, a), (_, b)| a.x.total_cmp(&b.x));
+        let (i, victim) = match victim {
+            Some(v) => v,
+            None => return Vec::new(),
+        };
+        let before = simulate(s, &pending[..i]);
+        let amm = match before.get_amm(&victim.tin, &victim.tout) {
+            Some(amm) if amm.r0 > 0.0 && amm.r1 > 0.0 => amm,
+            _ => return Vec::new(),
+        };
+        let affordable = (before.get_balance(&self.user, &amm.t0) / amm.r0)
+            .min(before.get_balance(&self.user, &amm.t1) / amm.r1);
+        let multiple = optimal_jit_multiple(amm.get_reserves(&victim.tin), victim.x, amm.fee)
+            .map_or(affordable, |m| m.min(affordable));
+        if multiple <= 0.0 {
+            return Vec::new();
+        }
+        match jit_report(&before, &self.user, victim, multiple, &|s, t| feed.price(s, t)) {
+            Ok(report) if report.attacker_gain > 0.0 => {
+                let (deposit, redeem) = jit_transitions(&before, &self.user, victim, multiple).unwrap();
+                vec![
+                    (Slot::Before(i), Box::new(deposit) as Box<dyn Transition>),
+                    (Slot::After(i), Box::new(redeem)),
+                ]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::place;
+    use crate::{price_oracle, CreatePool, Curve};
+
+    fn setup() -> (State, Swap, User) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let a = User::new("A");
+        let j = User::new("J");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        s.set_balance(&a, &t0, 100.0);
+        s.set_balance(&j, &t0, 1000.0);
+        s.set_balance(&j, &t1, 1000.0);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        (s, Swap::new(&a, &t0, &t1, 100.0), j)
+    }
+
+    #[test]
+    fn optimal_multiple_maximizes_the_gain() {
+        let (s, victim, j) = setup();
+        let m = optimal_jit_multiple(100.0, 100.0, 0.003).unwrap();
+        let gain = |m: f64| jit_report(&s, &j, &victim, m, &price_oracle).unwrap().attacker_gain;
+        // valued at 1000 per token, up to the LP rounding
+        assert!((gain(m) - 1000.0 * jit_gain(100.0, 100.0, 0.003, m)).abs() < 1e-3);
+        assert!(gain(m) > gain(m * 0.8) && gain(m) > gain(m * 1.2));
+        // a small swap pays mostly fees: the deeper the better
+        assert!(optimal_jit_multiple(100.0, 0.1, 0.003).is_none());
+    }
+
+    #[test]
+    fn gain_is_split_with_the_existing_lps() {
+        let (s, victim, j) = setup();
+        let r = jit_report(&s, &j, &victim, 1.5, &price_oracle).unwrap();
+        assert!(r.attacker_gain > 0.0 && r.lp_gain > 0.0 && r.lp_gain < r.lp_gain_without);
+        assert!(r.victim_out > r.victim_out_without);
+        // what the LPs lose goes to the attacker and to the victim
+        let victim_gain = 1000.0 * (r.victim_out - r.victim_out_without);
+        assert!((r.attacker_gain + r.lp_gain + victim_gain - r.lp_gain_without).abs() < 1e-3);
+    }
+
+    #[test]
+    fn bot_wraps_the_victim_swap() {
+        let (s, victim, j) = setup();
+        let pending: Vec<Box<dyn Transition>> = vec![Box::new(victim)];
+        let mut bot = JitBot::new(&j, 10.0);
+        let submitted = bot.act(&s, &pending, &Oracle::External(price_oracle), &mut Rng::new(0));
+        let block = place(pending, submitted);
+        let labels: Vec<String> = block.iter().map(|t| t.to_string()).collect();
+        assert_eq!(labels.len(), 3);
+        assert!(labels[0].starts_with("J:dep(") && labels[2].starts_with("J:rdm("));
+        let post = simulate(&s, &block);
+        assert!(post.net_wealth_user(&j, &price_oracle) > s.net_wealth_user(&j, &price_oracle));
+    }
+}
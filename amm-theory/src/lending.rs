@@ -0,0 +1,474 @@
+use std::fmt;
+
+use crate::oracle::Oracle;
+use crate::{price_oracle, State, Token, Transition, TransitionError, User};
+
+// A lending market for a single token. Suppliers own shares of the market,
+// whose value grows as borrowers pay interest. Debts are stored scaled by the
+// borrow index, which compounds `rate` at every block.
+#[derive(Clone)]
+pub struct Market {
+    pub token: Token,
+    pub rate: f64,
+    pub cash: f64,
+    pub shares: f64,
+    pub scaled_borrows: f64,
+    pub borrow_index: f64,
+    pub last_block: u64,
+}
+
+impl Market {
+    fn new(token: &Token, rate: f64) -> Self {
+        assert!(rate >= 0.0);
+        Market {
+            token: token.clone(),
+            rate,
+            cash: 0.0,
+            shares: 0.0,
+            scaled_borrows: 0.0,
+            borrow_index: 1.0,
+            last_block: 0,
+        }
+    }
+
+    pub fn borrow_index_at(&self, block: u64) -> f64 {
+        let elapsed = block.saturating_sub(self.last_block);
+        self.borrow_index * (1.0 + self.rate).powf(elapsed as f64)
+    }
+
+    pub fn borrows_at(&self, block: u64) -> f64 {
+        self.scaled_borrows * self.borrow_index_at(block)
+    }
+
+    // Tokens redeemable for one share
+    pub fn exchange_rate_at(&self, block: u64) -> f64 {
+        if self.shares == 0.0 {
+            return 1.0;
+        }
+        (self.cash + self.borrows_at(block)) / self.shares
+    }
+
+    fn accrue(&mut self, block: u64) {
+        self.borrow_index = self.borrow_index_at(block);
+        self.last_block = block;
+    }
+}
+
+impl fmt::Display for Market {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = f.precision().unwrap_or(1);
+        write!(f, "L{{{:.*}:{} {:.*}:debt}}", p, self.cash, self.token, p, self.scaled_borrows * self.borrow_index)
+    }
+}
+
+#[derive(Clone)]
+pub struct Position {
+    pub user: User,
+    pub token: Token,
+    pub shares: f64,
+    pub scaled_debt: f64,
+}
+
+#[derive(Clone)]
+pub struct Lending {
+    pub markets: Vec<Market>,
+    pub positions: Vec<Position>,
+    // a user can borrow up to this fraction of the value it supplied,
+    // and is liquidated when its debt exceeds it
+    pub collateral_factor: f64,
+    // extra collateral seized by a liquidator, as a fraction of the repaid debt
+    pub liquidation_bonus: f64,
+    pub oracle: Oracle,
+    // the only user allowed to publish prices to a manual oracle
+    pub reporter: Option<User>,
+}
+
+impl Lending {
+    pub fn new() -> Self {
+        Lending {
+            markets: Vec::new(),
+            positions: Vec::new(),
+            collateral_factor: 0.75,
+            liquidation_bonus: 0.05,
+            oracle: Oracle::External(price_oracle),
+            reporter: None,
+        }
+    }
+
+    pub fn add_market(&mut self, token: &Token, rate: f64) {
+        assert!(self.get_market(token).is_none());
+        self.markets.push(Market::new(token, rate));
+    }
+
+    pub fn get_market(&self, token: &Token) -> Option<&Market> {
+        self.markets.iter().find(|m| m.token == *token)
+    }
+
+    fn get_market_mut(&mut self, token: &Token) -> Option<&mut Market> {
+        self.markets.iter_mut().find(|m| m.token == *token)
+    }
+
+    pub fn get_cash(&self, token: &Token) -> f64 {
+        self.get_market(token).map_or(0.0, |m| m.cash)
+    }
+
+    fn get_position(&self, user: &User, token: &Token) -> Option<&Position> {
+        self.positions.iter().find(|p| p.user == *user && p.token == *token)
+    }
+
+    fn get_position_mut(&mut self, user: &User, token: &Token) -> &mut Position {
+        let i = match self.positions.iter().position(|p| p.user == *user && p.token == *token) {
+            Some(i) => i,
+            None => {
+                self.positions.push(Position {
+                    user: user.clone(),
+                    token: token.clone(),
+                    shares: 0.0,
+                    scaled_debt: 0.0,
+                });
+                self.positions.len() - 1
+            }
+        };
+        &mut self.positions[i]
+    }
+
+    // Tokens supplied by a user, interest included
+    pub fn get_supplied(&self, user: &User, token: &Token, block: u64) -> f64 {
+        match (self.get_position(user, token), self.get_market(token)) {
+            (Some(p), Some(m)) => p.shares * m.exchange_rate_at(block),
+            _ => 0.0,
+        }
+    }
+
+    // Tokens owed by a user, interest included
+    pub fn get_debt(&self, user: &User, token: &Token, block: u64) -> f64 {
+        match (self.get_position(user, token), self.get_market(token)) {
+            (Some(p), Some(m)) => p.scaled_debt * m.borrow_index_at(block),
+            _ => 0.0,
+        }
+    }
+}
+
+impl Default for Lending {
+    fn default() -> Self {
+        Lending::new()
+    }
+}
+
+impl State {
+    // Borrowing capacity of a user, valued with the market oracle
+    pub fn collateral_value(&self, user: &User) -> f64 {
+        let l = &self.lending;
+        l.positions.iter().filter(|p| p.user == *user)
+            .map(|p| l.get_supplied(user, &p.token, self.block) * l.oracle.price(self, &p.token))
+            .sum::<f64>() * l.collateral_factor
+    }
+
+    pub fn debt_value(&self, user: &User) -> f64 {
+        let l = &self.lending;
+        l.positions.iter().filter(|p| p.user == *user)
+            .map(|p| l.get_debt(user, &p.token, self.block) * l.oracle.price(self, &p.token))
+            .sum()
+    }
+
+    // A position with health factor below 1 can be liquidated
+    pub fn health_factor(&self, user: &User) -> f64 {
+        let debt = self.debt_value(user);
+        if debt == 0.0 {
+            return f64::INFINITY;
+        }
+        self.collateral_value(user) / debt
+    }
+
+    // Supplied tokens minus debt, valued with the given price function
+    pub fn position_value(&self, user: &User, f: &dyn Fn(&State, &Token) -> f64) -> f64 {
+        let l = &self.lending;
+        l.positions.iter().filter(|p| p.user == *user)
+            .map(|p| (l.get_supplied(user, &p.token, self.block) - l.get_debt(user, &p.token, self.block)) * f(self, &p.token))
+            .sum()
+    }
+}
+
+// Supplies v tokens to the market, which can be lent out and count as collateral
+pub struct Supply {
+    sender: User,
+    token: Token,
+    v: f64,
+}
+
+impl Supply {
+    pub fn new(sender: &User, token: &Token, v: f64) -> Self {
+        assert!(v > 0.0);
+        Supply { sender: sender.clone(), token: token.clone(), v }
+    }
+}
+
+impl fmt::Display for Supply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:supply({}:{})", self.sender, self.v, self.token)
+    }
+}
+
+impl Transition for Supply {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        let block = post.block;
+        let balance = post.get_balance(&self.sender, &self.token);
+        if balance < self.v {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        let market = post.lending.get_market_mut(&self.token).ok_or(TransitionError::UnknownMarket)?;
+        market.accrue(block);
+        let shares = self.v / market.exchange_rate_at(block);
+        market.cash += self.v;
+        market.shares += shares;
+        post.lending.get_position_mut(&self.sender, &self.token).shares += shares;
+        post.set_balance(&self.sender, &self.token, balance - self.v);
+        Ok(post)
+    }
+}
+
+// Withdraws v supplied tokens, provided the position stays healthy
+pub struct Withdraw {
+    sender: User,
+    token: Token,
+    v: f64,
+}
+
+impl Withdraw {
+    pub fn new(sender: &User, token: &Token, v: f64) -> Self {
+        assert!(v > 0.0);
+        Withdraw { sender: sender.clone(), token: token.clone(), v }
+    }
+}
+
+impl fmt::Display for Withdraw {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:withdraw({}:{})", self.sender, self.v, self.token)
+    }
+}
+
+impl Transition for Withdraw {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        let block = post.block;
+        if post.lending.get_supplied(&self.sender, &self.token, block) < self.v {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        let market = post.lending.get_market_mut(&self.token).ok_or(TransitionError::UnknownMarket)?;
+        if market.cash < self.v {
+            return Err(TransitionError::InsufficientReserves);
+        }
+        market.accrue(block);
+        let shares = self.v / market.exchange_rate_at(block);
+        market.cash -= self.v;
+        market.shares -= shares;
+        post.lending.get_position_mut(&self.sender, &self.token).shares -= shares;
+        let balance = post.get_balance(&self.sender, &self.token);
+        post.set_balance(&self.sender, &self.token, balance + self.v);
+        if post.health_factor(&self.sender) < 1.0 {
+            return Err(TransitionError::Undercollateralized);
+        }
+        Ok(post)
+    }
+}
+
+// Borrows v tokens against the sender's supplied collateral
+pub struct Borrow {
+    sender: User,
+    token: Token,
+    v: f64,
+}
+
+impl Borrow {
+    pub fn new(sender: &User, token: &Token, v: f64) -> Self {
+        assert!(v > 0.0);
+        Borrow { sender: sender.clone(), token: token.clone(), v }
+    }
+}
+
+impl fmt::Display for Borrow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:borrow({}:{})", self.sender, self.v, self.token)
+    }
+}
+
+impl Transition for Borrow {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        let block = post.block;
+        let market = post.lending.get_market_mut(&self.token).ok_or(TransitionError::UnknownMarket)?;
+        if market.cash < self.v {
+            return Err(TransitionError::InsufficientReserves);
+        }
+        market.accrue(block);
+        let scaled = self.v / market.borrow_index;
+        market.cash -= self.v;
+        market.scaled_borrows += scaled;
+        post.lending.get_position_mut(&self.sender, &self.token).scaled_debt += scaled;
+        let balance = post.get_balance(&self.sender, &self.token);
+        post.set_balance(&self.sender, &self.token, balance + self.v);
+        if post.health_factor(&self.sender) < 1.0 {
+            return Err(TransitionError::Undercollateralized);
+        }
+        Ok(post)
+    }
+}
+
+// Repays up to v tokens of the sender's debt
+pub struct Repay {
+    sender: User,
+    token: Token,
+    v: f64,
+}
+
+impl Repay {
+    pub fn new(sender: &User, token: &Token, v: f64) -> Self {
+        assert!(v > 0.0);
+        Repay { sender: sender.clone(), token: token.clone(), v }
+    }
+}
+
+impl fmt::Display for Repay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:repay({}:{})", self.sender, self.v, self.token)
+    }
+}
+
+impl Transition for Repay {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        repay(&mut post, &self.sender, &self.sender, &self.token, self.v)?;
+        Ok(post)
+    }
+}
+
+// The payer repays up to v tokens of the borrower's debt.
+// Returns the amount actually repaid.
+fn repay(s: &mut State, payer: &User, borrower: &User, token: &Token, v: f64) -> Result<f64, TransitionError> {
+    let block = s.block;
+    let v = v.min(s.lending.get_debt(borrower, token, block));
+    let balance = s.get_balance(payer, token);
+    if balance < v {
+        return Err(TransitionError::InsufficientBalance);
+    }
+    let market = s.lending.get_market_mut(token).ok_or(TransitionError::UnknownMarket)?;
+    market.accrue(block);
+    let scaled = v / market.borrow_index;
+    market.cash += v;
+    market.scaled_borrows -= scaled;
+    s.lending.get_position_mut(borrower, token).scaled_debt -= scaled;
+    s.set_balance(payer, token, balance - v);
+    Ok(v)
+}
+
+// Repays up to v tokens of the debt of an unhealthy borrower, in exchange for
+// its supplied collateral, valued with the market oracle plus the liquidation bonus
+pub struct Liquidate {
+    sender: User,
+    borrower: User,
+    debt_token: Token,
+    v: f64,
+    collateral_token: Token,
+}
+
+impl Liquidate {
+    pub fn new(sender: &User, borrower: &User, debt_token: &Token, v: f64, collateral_token: &Token) -> Self {
+        assert!(v > 0.0);
+        Liquidate {
+            sender: sender.clone(),
+            borrower: borrower.clone(),
+            debt_token: debt_token.clone(),
+            v,
+            collateral_token: collateral_token.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Liquidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:liquidate({},{}:{},{})", self.sender, self.borrower, self.v, self.debt_token, self.collateral_token)
+    }
+}
+
+impl Transition for Liquidate {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        if pre.health_factor(&self.borrower) >= 1.0 {
+            return Err(TransitionError::HealthyPosition);
+        }
+        let mut post = pre.clone();
+        let block = post.block;
+        let repaid = repay(&mut post, &self.sender, &self.borrower, &self.debt_token, self.v)?;
+
+        let oracle = post.lending.oracle.clone();
+        let seized = repaid * oracle.price(&post, &self.debt_token) / oracle.price(&post, &self.collateral_token)
+            * (1.0 + post.lending.liquidation_bonus);
+        let seized = seized.min(post.lending.get_supplied(&self.borrower, &self.collateral_token, block));
+        let market = post.lending.get_market_mut(&self.collateral_token).ok_or(TransitionError::UnknownMarket)?;
+        market.accrue(block);
+        let shares = seized / market.exchange_rate_at(block);
+        post.lending.get_position_mut(&self.borrower, &self.collateral_token).shares -= shares;
+        post.lending.get_position_mut(&self.sender, &self.collateral_token).shares += shares;
+        Ok(post)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AdvanceBlock, CreatePool, Curve, Deposit, Swap};
+
+    fn setup() -> (State, Token, Token, User, User) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let b = User::new("B");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 200.0);
+        s.set_balance(&o, &t1, 200.0);
+        s.set_balance(&b, &t0, 50.0);
+        s.lending.oracle = Oracle::Spot(t1.clone());
+        s.lending.add_market(&t0, 0.0);
+        s.lending.add_market(&t1, 0.01);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        let s = Supply::new(&o, &t1, 100.0).apply(&s).unwrap();
+        let s = Supply::new(&b, &t0, 50.0).apply(&s).unwrap();
+        (s, t0, t1, o, b)
+    }
+
+    #[test]
+    fn borrow_is_limited_by_collateral_factor() {
+        let (s, _, t1, _, b) = setup();
+        assert!(matches!(Borrow::new(&b, &t1, 38.0).apply(&s), Err(TransitionError::Undercollateralized)));
+        let s = Borrow::new(&b, &t1, 37.0).apply(&s).unwrap();
+        assert_eq!(s.get_balance(&b, &t1), 37.0);
+        assert!(s.health_factor(&b) >= 1.0);
+    }
+
+    #[test]
+    fn interest_accrues_per_block() {
+        let (s, _, t1, o, b) = setup();
+        let s = Borrow::new(&b, &t1, 30.0).apply(&s).unwrap();
+        let s = AdvanceBlock::new(10).apply(&s).unwrap();
+        let debt = s.lending.get_debt(&b, &t1, s.block);
+        assert!((debt - 30.0 * 1.01f64.powi(10)).abs() < 1e-9);
+        // the interest goes to the suppliers
+        assert!((s.lending.get_supplied(&o, &t1, s.block) - (70.0 + debt)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn spot_price_manipulation_triggers_liquidation() {
+        let (s, t0, t1, o, b) = setup();
+        let s = Borrow::new(&b, &t1, 35.0).apply(&s).unwrap();
+        assert!(matches!(Liquidate::new(&o, &b, &t1, 10.0, &t0).apply(&s), Err(TransitionError::HealthyPosition)));
+
+        let s = Swap::new(&o, &t0, &t1, 20.0).apply(&s).unwrap();
+        assert!(s.health_factor(&b) < 1.0);
+        let s = Liquidate::new(&o, &b, &t1, 10.0, &t0).apply(&s).unwrap();
+        let price = 83.3333333 / 120.0;
+        let seized = 10.0 / price * 1.05;
+        assert!((s.lending.get_supplied(&o, &t0, s.block) - seized).abs() < 1e-5);
+        assert!((s.lending.get_debt(&b, &t1, s.block) - 25.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,830 @@
+use std::any::Any;
+use std::fmt;
+
+pub mod agent;
+pub mod batch;
+pub mod bridge;
+pub mod checker;
+pub mod detect;
+pub mod dynamic_fee;
+pub mod feed;
+pub mod game;
+pub mod inflation;
+pub mod interval;
+pub mod jit;
+pub mod lending;
+pub mod montecarlo;
+pub mod notation;
+pub mod oracle;
+pub mod pga;
+pub mod protocol_fee;
+pub mod quote;
+pub mod replay;
+pub mod rewards;
+pub mod rng;
+pub mod smt;
+pub mod svg;
+pub mod sweep;
+pub mod table;
+pub mod twamm;
+
+use dynamic_fee::DynamicFee;
+use lending::Lending;
+use rewards::Rewards;
+use twamm::Twamm;
+use protocol_fee::{FeeMode, ProtocolFee};
+
+#[derive(PartialEq, PartialOrd, Eq, Clone)]
+pub struct User {
+    pub name: String
+}
+
+impl User {
+    pub fn new(name: &str) -> Self {
+        User {
+            name: String::from(name)
+        }
+    }
+}
+
+impl fmt::Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[derive(PartialEq, PartialOrd, Clone)]
+pub enum Token {
+    Atomic(String),
+    Minted(String,String)
+}
+
+impl Token {
+    pub fn mint(token0: &Token, token1: &Token) -> Token {
+        let (token0, token1) = order_tokens(token0, token1);
+        if let Token::Atomic(t0) = token0 {
+            if let Token::Atomic(t1) = token1 {
+                return Token::Minted(t0.clone(), t1.clone());
+            }
+        }
+        panic!("invalid token pair mint");
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Atomic(d) => write!(f, "{}", d),
+            Token::Minted(d0, d1) => write!(f, "{}+{}", d0, d1)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Balance {
+    pub token: Token,
+    pub value: f64
+}
+
+impl fmt::Display for Balance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        //values are printed with one decimal unless a precision is given
+        write!(f, "{:.*}:{}", f.precision().unwrap_or(1), self.value, self.token)
+    }
+}
+
+#[derive(Clone)]
+pub struct Wallet {
+    pub user: User,
+    pub balances: Vec<Balance>
+}
+
+impl Wallet {
+    pub fn new(user: &User) -> Self {
+        Wallet {
+            user: user.clone(),
+            balances: Vec::new()
+        }
+    }
+}
+
+impl Wallet {
+    pub fn get_balance(&self, token: &Token) -> f64 {
+        self.balances.iter().find(|b| &b.token == token)
+            .map_or(0.0, |b| b.value)
+    }
+}
+
+impl fmt::Display for Wallet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = f.precision().unwrap_or(1);
+        write!(f, "{}[{}]", self.user, self.balances.iter().map(|x| format!("{:.*}", p, x)).collect::<Vec<_>>().join(","))
+    }
+}
+
+//LP tokens are accounted in units of LP_UNIT, rounding down in favour of the
+//pool. It is a power of two, so that rounding a multiple of it is exact.
+pub const LP_UNIT: f64 = 1.0 / (1u64 << 30) as f64;
+
+//LP tokens locked forever by the first deposit in a pool, so that inflating
+//the value of a unit of LP token by a donation is expensive
+pub const MINIMUM_LIQUIDITY: f64 = 1000.0 * LP_UNIT;
+
+pub fn lp_round(v: f64) -> f64 {
+    (v / LP_UNIT).floor() * LP_UNIT
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Curve {
+    //r0*r1 = k
+    ConstantProduct,
+    //r0+r1 = k, i.e. a fixed 1:1 price
+    ConstantSum
+}
+
+impl fmt::Display for Curve {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Curve::ConstantProduct => write!(f, "cp"),
+            Curve::ConstantSum => write!(f, "cs")
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone)]
+pub struct AMM {
+    pub r0: f64,
+    pub t0: Token,
+    pub r1: f64,
+    pub t1: Token,
+    pub curve: Curve,
+    //fraction of the input of a swap kept by the pool
+    pub fee: f64,
+    //share of the swap fees collected by the protocol, if switched on
+    pub protocol_fee: Option<ProtocolFee>,
+    //fee policy following the volatility of the price, if any
+    pub dynamic_fee: Option<DynamicFee>,
+    //LP tokens locked by the first deposit
+    pub locked: f64
+}
+
+
+impl AMM {
+    pub fn new(r0: f64, t0: &Token, r1: f64, t1: &Token) -> Self {
+        assert!(t0 < t1);
+        AMM {
+            r0,
+            t0: t0.clone(), r1, t1: t1.clone(),
+            curve: Curve::ConstantProduct,
+            fee: 0.0,
+            protocol_fee: None,
+            dynamic_fee: None,
+            locked: 0.0,
+        }
+    }
+
+    pub fn get_reserves(&self, t: &Token) -> f64 {
+        if &self.t0 == t {
+            self.r0
+        } else if &self.t1 == t {
+            self.r1
+        } else {
+            0.0
+        }
+    }
+
+    //Output of a swap of x units of tin, according to the curve and fee of the pool
+    pub fn amount_out(&self, tin: &Token, x: f64) -> f64 {
+        let r_in = self.get_reserves(tin);
+        let r_out = if *tin == self.t0 { self.r1 } else { self.r0 };
+        //only the input net of the fee moves along the curve, the fee stays in the pool
+        let x = x * (1.0 - self.fee);
+        match self.curve {
+            Curve::ConstantProduct => r_out - r_in * r_out / (r_in + x),
+            Curve::ConstantSum => x
+        }
+    }
+}
+
+impl fmt::Display for AMM {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = f.precision().unwrap_or(1);
+        write!(f, "{{{:.*}:{} {:.*}:{}}}", p, self.r0, self.t0, p, self.r1, self.t1)
+    }
+}
+
+#[derive(Clone)]
+pub struct State {
+    pub wallets: Vec<Wallet>,
+    pub amms:  Vec<AMM>,
+    pub lending: Lending,
+    pub rewards: Rewards,
+    pub twamm: Twamm,
+    pub block: u64
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = f.precision().unwrap_or(1);
+        let elms =
+            [self.wallets.iter().map(|x| format!("{:.*}", p, x)).collect::<Vec<_>>(),
+            self.amms.iter().map(|x| format!("{:.*}", p, x)).collect::<Vec<_>>(),
+            self.lending.markets.iter().map(|x| format!("{:.*}", p, x)).collect::<Vec<_>>(),
+            self.rewards.farms.iter().map(|x| format!("{:.*}", p, x)).collect::<Vec<_>>()].concat();
+
+        write!(f, "{}", elms.join(" | "))
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        State::new()
+    }
+}
+
+pub fn order_tokens<'a>( t0: &'a Token, t1: &'a Token) -> (&'a Token, &'a Token) {
+    assert!(t0 != t1);
+    if t0 < t1 {
+        (t0, t1)
+    } else {
+        (t1, t0)
+    }
+}
+
+impl State {
+
+    pub fn new() -> Self {
+        State {
+            wallets: Vec::new(),
+            amms: Vec::new(),
+            lending: Lending::new(),
+            rewards: Rewards::new(),
+            twamm: Twamm::new(),
+            block: 0,
+        }
+    }
+
+    //Token supply. We define the supply of a token type τ in a state Γ as the sum of the 
+    //reserves of τ in all the wallets and the AMMs occurring in Γ. 
+    //Tokens held as cash by a lending market, staked in a farm, or not sold yet
+    //by a long-term order are part of the supply as well.
+    pub fn token_supply(&self, token: &Token) -> f64 {
+        let mut total:f64 = 0.0;
+        for amm in &self.amms{
+            total += amm.get_reserves(token);
+            if Token::Minted(amm.t0.to_string(), amm.t1.to_string()) == *token {
+                total += amm.locked;
+            }
+        }
+        for wallet in &self.wallets{
+            total += wallet.get_balance(token);
+        }
+        total + self.lending.get_cash(token) + self.rewards.get_staked(token) + self.twamm.get_escrow(token)
+    }
+
+
+    pub fn get_amm(&self, t0: &Token, t1: &Token) -> Option<&AMM> {
+        for amm in &self.amms {
+            if amm.t0 == *t0 && amm.t1 == *t1 || amm.t0 == *t1 && amm.t1 == *t0{
+                return Some(amm)
+            }
+        }
+        None
+    }
+
+    pub fn get_amm_mut(&mut self, t0: &Token, t1: &Token) -> Option<&mut AMM> {
+        self.amms.iter_mut()
+            .find(|amm| amm.t0 == *t0 && amm.t1 == *t1 || amm.t0 == *t1 && amm.t1 == *t0)
+    }
+
+    pub fn get_reserves(&self, t: &Token, tother: &Token) -> f64 {
+        match self.get_amm(t,tother) {
+            Some(amm) => amm.get_reserves(t),
+            None => 0.0
+        }
+    }
+
+    //Pools are created by CreatePool: the AMM must exist
+    pub fn set_reserve(&mut self, t0: &Token, r0: f64, t1: &Token, r1: f64) {
+        let amm = self.get_amm_mut(t0, t1).expect("no AMM for the token pair");
+        if amm.t0 == *t0 {
+            amm.r0 = r0;
+            amm.r1 = r1;
+        }else{
+            amm.r0 = r1;
+            amm.r1 = r0;
+        }
+    }
+
+    pub fn get_fee(&self, t0: &Token, t1: &Token) -> f64 {
+        self.get_amm(t0, t1).map_or(0.0, |amm| amm.fee)
+    }
+
+  
+    pub fn get_balance(&self, user: &User, token:  &Token) -> f64 {
+        let mut i = 0;
+        for wallet in &self.wallets {
+            if wallet.user == *user {
+                break
+            }
+            i += 1;
+        }
+        if i >= self.wallets.len() {
+            return 0.0
+        }
+        self.wallets[i].get_balance(token)
+    }
+
+    pub fn set_balance(&mut self, user: &User, token:  &Token, new_value: f64) {
+        let mut i = 0;
+        for wallet in &self.wallets {
+            if wallet.user == *user {
+                break
+            }
+            i += 1;
+        }
+
+        if i >= self.wallets.len() {
+            self.wallets.push(Wallet::new(user));
+        }
+
+        let mut j = 0;
+        for balance in &self.wallets[i].balances {
+            if balance.token == *token {
+                break
+            }
+            j += 1;
+        }
+
+        if j >= self.wallets[i].balances.len(){
+            let new_balance = Balance {
+                token: token.clone(),
+                value: new_value
+            };
+            self.wallets[i].balances.push(new_balance);
+        }else{
+            self.wallets[i].balances[j].value = new_value;
+        }
+
+    }
+
+    //Net wealth of a user: the value of the tokens in its wallet, plus the
+    //value of its lending position (supplied tokens minus debt) and of its
+    //stakes (staked LP tokens and pending rewards) and of its long-term orders,
+    //with the virtual orders executed up to the current block.
+    pub fn net_wealth_user(&self,user: &User, f: &dyn Fn(&State, &Token)-> f64) -> f64{
+        if let Some(settled) = twamm::settled_view(self) {
+            return settled.net_wealth_user(user, f);
+        }
+        let mut sum:f64 = 0.0;
+        for wallet in self.wallets.iter().filter(|w| w.user == *user) {
+            for balance in &wallet.balances {
+                sum += f(self,&balance.token)*balance.value;
+            }
+        }
+        sum + self.position_value(user, f) + self.stake_value(user, f) + self.order_value(user, f)
+    }
+
+    pub fn net_wealth(&self, f: &dyn Fn(&State,&Token) -> f64  ) -> f64{
+        let mut sum:f64 = 0.0;
+        for wallet in &self.wallets {
+            sum += self.net_wealth_user(&wallet.user, f);
+        }
+        sum
+    }
+
+
+
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum TransitionError {
+    InsufficientBalance,
+    InvalidDepositRatio,
+    InsufficientReserves,
+    InsufficientLiquidity,
+    PoolExists,
+    NonAtomicToken,
+    UnknownPool,
+    UnknownMarket,
+    UnknownFarm,
+    UnknownOrder,
+    UnknownFeed,
+    Unauthorized,
+    Undercollateralized,
+    HealthyPosition,
+    Unimplemented
+}
+
+
+// Transitions display in the notation of the AMM paper, e.g. A:swap(t0,t1,20)
+pub trait Transition: Any + fmt::Display {
+    fn apply(&self, s0: &State) -> Result<State, TransitionError>;
+}
+
+impl dyn Transition {
+    pub fn downcast_ref<T: Transition>(&self) -> Option<&T> {
+        (self as &dyn Any).downcast_ref::<T>()
+    }
+}
+
+pub struct Deposit {
+    pub sender: User,
+    pub v0: f64,
+    pub t0: Token,
+    pub v1: f64,
+    pub t1: Token
+}
+
+impl Deposit  {
+    pub fn new(sender: &User, r0: f64, t0: &Token, r1: f64, t1: &Token) -> Self {
+        assert!(r0 > 0.0 && r1 > 0.0);
+        Deposit {
+            sender: sender.clone(),
+            v0: r0,
+            t0: t0.clone(),
+            v1: r1,
+            t1: t1.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Deposit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:dep({}:{},{}:{})", self.sender, self.v0, self.t0, self.v1, self.t1)
+    }
+}
+
+impl Transition for Deposit {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        if post.get_amm(&self.t0, &self.t1).is_none() {
+            return Err(TransitionError::UnknownPool);
+        }
+        post.mint_protocol_fee(&self.t0, &self.t1);
+        post.execute_virtual_orders(&self.t0, &self.t1);
+
+        let t0_balance:f64 = post.get_balance(&self.sender, &self.t0);
+        let t1_balance:f64 = post.get_balance(&self.sender, &self.t1);
+        let t0_reserve:f64 = post.get_reserves(&self.t0,&self.t1);
+        let t1_reserve:f64 = post.get_reserves(&self.t1,&self.t0);
+
+        //add LP Token: the first deposit mints v0+v1, minus the locked minimum
+        //liquidity, the next ones a share of the supply proportional to the
+        //share of the reserves they add
+        let lp_token = Token::mint(&self.t0, &self.t1);
+        let lp_supply = post.token_supply(&lp_token);
+        let lp_balance = post.get_balance(&self.sender, &lp_token);
+        let minted = if lp_supply == 0.0 {
+            if self.v0 + self.v1 <= MINIMUM_LIQUIDITY {
+                return Err(TransitionError::InsufficientLiquidity);
+            }
+            post.get_amm_mut(&self.t0, &self.t1).unwrap().locked = MINIMUM_LIQUIDITY;
+            lp_round(self.v0 + self.v1 - MINIMUM_LIQUIDITY)
+        } else {
+            lp_round(lp_supply * (self.v0/t0_reserve).min(self.v1/t1_reserve))
+        };
+
+        post.set_balance(&self.sender,&self.t0, t0_balance - self.v0);
+        post.set_balance(&self.sender,&self.t1, t1_balance - self.v1);
+        post.set_reserve(&self.t0,t0_reserve+self.v0,&self.t1,t1_reserve+self.v1);
+        post.set_balance(&self.sender,&lp_token,lp_balance + minted);
+        post.update_k_last(&self.t0, &self.t1);
+        
+        Result::Ok(post)
+        // Result::Err(TransitionError::Unimplemented)
+    }
+}
+
+pub struct Redeem {
+    pub sender: User,
+    pub t0: Token,
+    pub t1: Token,
+    pub v: f64,
+}
+
+impl Redeem  {
+    pub fn new(sender: &User, t0: &Token, t1: &Token, v: f64) -> Self {
+        assert!(v > 0.0);
+        Redeem {
+            sender: sender.clone(),
+            t0: t0.clone(),
+            t1: t1.clone(),
+            v,
+        }
+    }
+}
+
+impl fmt::Display for Redeem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:rdm({}:{})", self.sender, self.v, Token::mint(&self.t0, &self.t1))
+    }
+}
+
+impl Transition for Redeem {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        if post.get_amm(&self.t0, &self.t1).is_none() {
+            return Err(TransitionError::UnknownPool);
+        }
+        post.mint_protocol_fee(&self.t0, &self.t1);
+        post.execute_virtual_orders(&self.t0, &self.t1);
+        let lp_token = Token::mint(&self.t0, &self.t1);
+        let lp_supply = post.token_supply(&lp_token);
+        let t0_reserve = post.get_reserves(&self.t0,&self.t1);
+        let t1_reserve = post.get_reserves(&self.t1,&self.t0);
+        let t0_balance = post.get_balance(&self.sender,&self.t0);
+        let t1_balance = post.get_balance(&self.sender,&self.t1);
+        let lp_balance = post.get_balance(&self.sender,&lp_token);
+
+        post.set_reserve(&self.t0,t0_reserve - t0_reserve*self.v/lp_supply,&self.t1,t1_reserve- t1_reserve*self.v/lp_supply);
+        post.set_balance(&self.sender,&self.t0, t0_balance + t0_reserve*self.v/lp_supply);
+        post.set_balance(&self.sender,&self.t1, t1_balance + t1_reserve*self.v/lp_supply);
+        post.set_balance(&self.sender,&lp_token, lp_balance - self.v);
+        post.update_k_last(&self.t0, &self.t1);
+
+        Result::Ok(post)
+        // Result::Err(TransitionError::Unimplemented)
+    }
+}
+
+
+pub struct Swap {
+    pub sender: User,
+    pub tin: Token,
+    pub tout: Token,
+    pub x: f64,
+}
+
+impl Swap {
+    // tin means token to be in the AMM
+    pub fn new(sender: &User, tin: &Token, tout: &Token, x: f64) -> Self {
+        Swap {
+            sender: sender.clone(),
+            tin: tin.clone(),
+            tout: tout.clone(),
+            x,
+        }
+    }
+}
+
+impl Transition for Swap {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        if post.get_amm(&self.tin,&self.tout).is_none() {
+            return Err(TransitionError::UnknownPool);
+        }
+        //long-term orders trade first, at the start of the block
+        post.execute_virtual_orders(&self.tin,&self.tout);
+        let amm = post.get_amm(&self.tin,&self.tout).unwrap();
+        let pre_in_balance = post.get_balance(&self.sender,&self.tin);
+        let pre_out_balance = post.get_balance(&self.sender, &self.tout);
+        //get reserves before swap
+        let pre_out_reserve = post.get_reserves(&self.tout,&self.tin);
+        let pre_in_reserve = post.get_reserves(&self.tin,&self.tout);
+        //calculate reserves after swap, along the curve of the pool
+        let out = amm.amount_out(&self.tin, self.x);
+        if out >= pre_out_reserve {
+            return Err(TransitionError::InsufficientReserves);
+        }
+        let post_in_reserve = pre_in_reserve + self.x ;
+        let post_out_reserve = pre_out_reserve - out ;
+        //set in token balance
+        post.set_balance(&self.sender,&self.tin,pre_in_balance - self.x);
+        //set out token balance
+        post.set_balance(&self.sender,&self.tout,pre_out_balance + pre_out_reserve-post_out_reserve);
+        //set post in and out reserve
+        post.set_reserve(&self.tin,post_in_reserve,&self.tout,post_out_reserve);
+        //the protocol may take its share of the fee out of the pool
+        if let Some((treasury, cut)) = post.protocol_fee_cut(&self.tin, &self.tout, self.x) {
+            let treasury_balance = post.get_balance(&treasury, &self.tin);
+            post.set_balance(&treasury, &self.tin, treasury_balance + cut);
+            post.set_reserve(&self.tin,post_in_reserve - cut,&self.tout,post_out_reserve);
+        }
+        post.record_price_move(&self.tin,&self.tout);
+
+        Result::Ok(post)
+        // Result::Err(TransitionError::Unimplemented)
+    }
+}
+
+impl fmt::Display for Swap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:swap({},{},{})", self.sender, self.tin, self.tout, self.x)
+    }
+}
+
+//Creates an empty pool for a pair of atomic tokens, with the given curve and
+//swap fee. Liquidity is then added by Deposit.
+pub struct CreatePool {
+    pub t0: Token,
+    pub t1: Token,
+    pub curve: Curve,
+    pub fee: f64
+}
+
+impl CreatePool {
+    pub fn new(t0: &Token, t1: &Token, curve: Curve, fee: f64) -> Self {
+        assert!((0.0..1.0).contains(&fee));
+        let (t0, t1) = order_tokens(t0, t1);
+        CreatePool {
+            t0: t0.clone(),
+            t1: t1.clone(),
+            curve,
+            fee,
+        }
+    }
+}
+
+impl Transition for CreatePool {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        // pools pair atomic tokens: LP tokens are not traded
+        if !matches!(self.t0, Token::Atomic(_)) || !matches!(self.t1, Token::Atomic(_)) {
+            return Err(TransitionError::NonAtomicToken);
+        }
+        if pre.get_amm(&self.t0, &self.t1).is_some() {
+            return Err(TransitionError::PoolExists);
+        }
+        let mut post = pre.clone();
+        let mut amm = AMM::new(0.0, &self.t0, 0.0, &self.t1);
+        amm.curve = self.curve;
+        amm.fee = self.fee;
+        post.amms.push(amm);
+        Ok(post)
+    }
+}
+
+impl fmt::Display for CreatePool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "create({},{},{},{})", self.t0, self.t1, self.curve, self.fee)
+    }
+}
+
+//Transfers tokens to the reserves of a pool without minting LP tokens:
+//the value of the LP tokens increases accordingly
+pub struct Donate {
+    pub sender: User,
+    pub v0: f64,
+    pub t0: Token,
+    pub v1: f64,
+    pub t1: Token
+}
+
+impl Donate {
+    pub fn new(sender: &User, v0: f64, t0: &Token, v1: f64, t1: &Token) -> Self {
+        assert!(v0 >= 0.0 && v1 >= 0.0 && v0 + v1 > 0.0);
+        Donate {
+            sender: sender.clone(),
+            v0,
+            t0: t0.clone(),
+            v1,
+            t1: t1.clone(),
+        }
+    }
+}
+
+impl Transition for Donate {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        if post.get_amm(&self.t0, &self.t1).is_none() {
+            return Err(TransitionError::UnknownPool);
+        }
+        let t0_balance = post.get_balance(&self.sender, &self.t0);
+        let t1_balance = post.get_balance(&self.sender, &self.t1);
+        if t0_balance < self.v0 || t1_balance < self.v1 {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        post.execute_virtual_orders(&self.t0, &self.t1);
+        let t0_reserve = post.get_reserves(&self.t0, &self.t1);
+        let t1_reserve = post.get_reserves(&self.t1, &self.t0);
+        post.set_balance(&self.sender, &self.t0, t0_balance - self.v0);
+        post.set_balance(&self.sender, &self.t1, t1_balance - self.v1);
+        post.set_reserve(&self.t0, t0_reserve + self.v0, &self.t1, t1_reserve + self.v1);
+        Ok(post)
+    }
+}
+
+impl fmt::Display for Donate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:donate({}:{},{}:{})", self.sender, self.v0, self.t0, self.v1, self.t1)
+    }
+}
+
+// Advances the block height. Protocols that accrue over time (e.g. lending
+// interest) catch up lazily the next time they are touched; dynamic fees are
+// set for the new block.
+pub struct AdvanceBlock {
+    pub blocks: u64
+}
+
+impl AdvanceBlock {
+    pub fn new(blocks: u64) -> Self {
+        assert!(blocks > 0);
+        AdvanceBlock { blocks }
+    }
+}
+
+impl Transition for AdvanceBlock {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        post.block += self.blocks;
+        post.update_dynamic_fees();
+        Ok(post)
+    }
+}
+
+impl fmt::Display for AdvanceBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "tick({})", self.blocks)
+    }
+}
+
+pub fn price_oracle(s: &State, t: &Token) -> f64 {
+    match t{
+        Token::Atomic(token0) => {
+            if token0 == "t0" || token0 == "t1" {
+                return 1000.0
+            }
+            0.0
+        }
+        Token::Minted(t0, t1) => {
+            let minted_balance :f64 = s.token_supply(t);
+            let token0 = Token::Atomic(String::from(t0));
+            let token1 = Token::Atomic(String::from(t1));
+            let r0 = s.get_reserves(&token0,&token1);
+            let r1 = s.get_reserves(&token1,&token0);
+
+            (1000.0*r0 + 1000.0*r1)/minted_balance
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+pub fn SFr0(v0:f64,v1:f64,r0:f64,r1:f64) -> f64{
+    let variable0:f64 = v0*v0*v1*v1 + 4.0*v0*v1*r0*r1;
+    (variable0.sqrt() - v0*v1)/(2.0*v1)
+}
+
+#[allow(non_snake_case, dead_code)]
+pub fn SFr1(r0:f64,r1:f64,sfr0: f64) -> f64 {
+    r0*r1/sfr0
+}
+
+pub fn price_mini_transaction(p0:f64,p1:f64,r0:f64,r1:f64) -> f64 {
+    let p = p1/p0 * r0 * r1;
+    p.sqrt() - r0
+}
+
+//Price minimization on a pool with a fee: the amount of t0 to swap so that the
+//marginal price of the pool, fee included, matches the external prices.
+//With no fee this is price_mini_transaction; a negative result means that
+//swapping t0 is not profitable.
+pub fn arbitrage_amount(p0:f64,p1:f64,r0:f64,r1:f64,fee:f64) -> f64 {
+    let g = 1.0 - fee;
+    ((p1/p0 * r0 * r1 * g).sqrt() - r0)/g
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balances_of_users_without_a_wallet_are_zero() {
+        let t0 = Token::Atomic(String::from("t0"));
+        let mut s = State::new();
+        assert_eq!(s.get_balance(&User::new("B"), &t0), 0.0);
+        s.set_balance(&User::new("O"), &t0, 5.0);
+        assert_eq!(s.get_balance(&User::new("B"), &t0), 0.0);
+        assert_eq!(s.get_balance(&User::new("O"), &Token::Atomic(String::from("t1"))), 0.0);
+    }
+
+    #[test]
+    fn pairs_are_named_in_either_order() {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        assert!(Token::mint(&t1, &t0) == Token::Minted(String::from("t0"), String::from("t1")));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 10.0);
+        s.set_balance(&o, &t1, 20.0);
+        let s = CreatePool::new(&t1, &t0, Curve::ConstantProduct, 0.0).apply(&s).unwrap();
+        let s = Deposit::new(&o, 20.0, &t1, 10.0, &t0).apply(&s).unwrap();
+        assert_eq!(s.get_reserves(&t0, &t1), 10.0);
+        assert_eq!(s.get_reserves(&t1, &t0), 20.0);
+        assert!(s.get_balance(&o, &Token::mint(&t0, &t1)) > 0.0);
+    }
+
+    #[test]
+    fn net_wealth_values_every_token() {
+        let x = Token::Atomic(String::from("x"));
+        let mut s = State::new();
+        s.set_balance(&User::new("O"), &x, 3.0);
+        s.set_balance(&User::new("B"), &Token::Atomic(String::from("t0")), 1.0);
+        let f = |_: &State, t: &Token| if *t == x { 2.0 } else { 1000.0 };
+        assert_eq!(s.net_wealth_user(&User::new("O"), &f), 6.0);
+        assert_eq!(s.net_wealth(&f), 1006.0);
+    }
+}
@@ -1,664 +1,1043 @@
-use std::fmt;
-use crate::TransitionError::{InsufficientBalance, InsufficientReserves};
+use amm_theory::agent::{Arbitrageur, NoiseTrader, PassiveLP, SandwichBot, Simulation};
+use amm_theory::dynamic_fee::{self, SetDynamicFee};
+use amm_theory::feed;
+use amm_theory::lending::{Borrow, Liquidate, Repay, Supply, Withdraw};
+use amm_theory::oracle::Oracle;
+use amm_theory::protocol_fee::{FeeMode, SetProtocolFee};
+use amm_theory::rewards::{self, Claim, Stake, Unstake};
+use amm_theory::*;
+
+//Initial state and transactions of the mev0 scenario
+fn mev0_block() -> (State, Vec<Box<dyn Transition>>) {
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let m: User = User::new("M");
 
+    let mut s0: State = State::new();
 
-#[derive(PartialEq, PartialOrd, Eq, Clone)]
-struct User {
-    name: String
-}
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 100.0);
+    s0.set_balance(&a, &t0, 20.0);
+    s0.set_balance(&a, &t1, 0.0);
+    s0.set_balance(&m, &t0, 5.9);
+    s0.set_balance(&m, &t1, 20.6);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.0).apply(&s0).unwrap();
 
-impl User {
-    fn new(name: &str) -> Self {
-        User {
-            name: String::from(name)
-        }
-    }
-}
 
-impl fmt::Display for User {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.name)
-    }
-}
+    let mut v: Vec<Box<dyn Transition>> = Vec::new();
+    v.push(Box::new(Deposit::new(&o,100.0,&t0,100.0,&t1)));
+    
+    //inner layer
+    let sfr0 = SFr0(20.0, 15.0, 100.0,100.0);
+    let v0 = sfr0 - 100.0;
+    v.push(Box::new(Swap::new(&m,&t0,&t1,v0)));
 
-#[derive(PartialEq, PartialOrd, Clone)]
-enum Token {
-    Atomic(String),
-    Minted(String,String)
-}
+    v.push(Box::new(Swap::new(&a,&t0,&t1,20.0)));
+    
+    //price minimization
+    let v0 = price_mini_transaction(1000.0,1000.0,79.4, 125.9);
+    v.push(Box::new(Swap::new(&m,&t1,&t0,v0)));
+    //
 
-impl Token {
-    fn mint(token0: &Token, token1: &Token) -> Token {
-        if let Token::Atomic(t0) = token0 {
-            if let Token::Atomic(t1) = token1 {
-                return Token::Minted(t0.clone(), t1.clone());
-            }
-        }
-        panic!("invalid token pair mint");
-    }
+    (s0, v)
 }
 
-impl fmt::Display for Token {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Token::Atomic(d) => write!(f, "{}", d),
-            Token::Minted(d0, d1) => write!(f, "{}+{}", d0, d1)
-        }
+fn mev0(){
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let m: User = User::new("M");
+    let (mut s0, v) = mev0_block();
+
+    println!("Initial: {:.1}", s0);
+    for t in v {
+        s0 = t.apply(&s0).unwrap();
+        // println!("reserve: {:.1}", s0.get_reserves(&t0, &t1));
+        println!("{:.1}", s0);
+        println!("\ttotal net_wealth: {:.1}", s0.net_wealth(&price_oracle));
+        println!("\tO's net_wealth: {:.1}", s0.net_wealth_user(&o, &price_oracle));
+        println!("\tA's net_wealth: {:.1}", s0.net_wealth_user(&a, &price_oracle));
+        println!("\tM's net_wealth: {:.1}", s0.net_wealth_user(&m, &price_oracle));
     }
 }
 
-#[derive(Clone)]
-struct Balance {
-    token: Token,
-    value: f64
-}
+//Initial state and transactions of the mev1 scenario
+fn mev1_block() -> (State, Vec<Box<dyn Transition>>) {
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let m: User = User::new("M");
 
-impl fmt::Display for Balance {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:.1}:{}", self.value, self.token)
-    }
-}
+    let mut s0: State = State::new();
 
-#[derive(Clone)]
-struct Wallet {
-    user: User,
-    balances: Vec<Balance>
-}
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 100.0);
+    s0.set_balance(&a, &t0, 100.0);
+    s0.set_balance(&a, &t1, 100.0);
+    s0.set_balance(&m, &t0, 100.0);
+    s0.set_balance(&m, &t1, 100.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.0).apply(&s0).unwrap();
 
-impl Wallet {
-    fn new(user: &User) -> Self {
-        Wallet {
-            user: user.clone(),
-            balances: Vec::new()
-        }
-    }
-}
 
-impl Wallet {
-    fn get_balance(&self, token: &Token) -> f64 {
-        self.balances.iter().find(|b| &b.token == token)
-            .map_or(0.0, |b| b.value)
-    }
+    let mut v: Vec<Box<dyn Transition>> = Vec::new();
+    v.push(Box::new(Deposit::new(&o,100.0,&t0,100.0,&t1)));
+    
+    //inner layer
+    let sfr0 = SFr0(40.0, 35.0, 100.0,100.0);
+    let mut v0 = 100.0 - sfr0;
+    v.push(Box::new(Swap::new(&m,&t1,&t0,v0)));
+
+    v.push(Box::new(Swap::new(&a,&t0,&t1,40.0)));
+    v.push(Box::new(Swap::new(&m,&t1,&t0,38.3)));
+    v.push(Box::new(Deposit::new(&a,30.0,&t0,40.0,&t1)));
+    
+    //price minimization
+    v0 = price_mini_transaction(1000.0,1000.0,117.0, 155.0);
+    v.push(Box::new(Swap::new(&m,&t0,&t1,v0)));
+    v.push(Box::new(Redeem::new(&a,&t0,&t1,10.0)));
+
+    
+    //price minimization
+    let _v0 = price_mini_transaction(1000.0,1000.0,78.0, 129.0);
+    // v.push(Box::new(Swap::new(&m,&t1,&t0,v0)));
+    //
+
+    (s0, v)
 }
 
-impl fmt::Display for Wallet {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}[{}]", self.user, self.balances.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(","))
+fn mev1(){
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let m: User = User::new("M");
+    let (mut s0, v) = mev1_block();
+
+    println!("Initial: {:.1}", s0);
+    for t in v {
+        s0 = t.apply(&s0).unwrap();
+        // println!("reserve: {:.1}", s0.get_reserves(&t0, &t1));
+        println!("{:.1}", s0);
+        println!("\ttotal net_wealth: {:.1}", s0.net_wealth(&price_oracle));
+        println!("\tO's net_wealth: {:.1}", s0.net_wealth_user(&o, &price_oracle));
+        println!("\tA's net_wealth: {:.1}", s0.net_wealth_user(&a, &price_oracle));
+        println!("\tM's net_wealth: {:.1}", s0.net_wealth_user(&m, &price_oracle));
     }
 }
 
-#[derive(Clone)]
-struct AMM {
-    r0: f64,
-    t0: Token,
-    r1: f64,
-    t1: Token
-}
+fn mev3(){
+let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let a: User = User::new("A");
+    let b =  User::new("B");
 
+    let mut s0 = State::new();
 
-impl AMM {
-    fn new(r0: f64, t0: &Token, r1: f64, t1: &Token) -> Self {
-        assert!(t0 < t1);
-        AMM {
-            r0,
-            t0: t0.clone(), r1, t1: t1.clone(),
-        }
-    }
+    s0.set_balance(&a, &t0, 100.0);
+    s0.set_balance(&a, &t1, 100.0);
+    s0.set_balance(&b, &t0, 100.0);
+    s0.set_balance(&b, &t1, 100.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.0).apply(&s0).unwrap();
+
+    let v: Vec<Box<dyn Transition>> = vec![
+        Box::new(Deposit::new(&a,100.0, &t0, 100.0, &t1)),
+        Box::new(Swap::new(&b,&t1, &t0, 13.0)),
+        Box::new(Swap::new(&b,&t0, &t1, 40.0)),
+        Box::new(Swap::new(&b,&t0, &t1, 30.0)),
+    ];
+    // v.push( Box::new(Redeem::new(&a,&t0, &t1, 30.0)));
+    // v.push( Box::new(Swap::new(&b,&t0, &t1, 30.0)));
+    // v.push( Box::new(Redeem::new(&a,&t0, &t1, 30.0)));
 
-    fn get_reserves(&self, t: &Token) -> f64 {
-        if &self.t0 == t {
-            self.r0
-        } else if &self.t1 == t {
-            self.r1
-        } else {
-            0.0
-        }
+    println!("Initial: {}", s0);
+    for t in v {
+        s0 = t.apply(&s0).unwrap();
+        println!("{}", s0);
     }
 }
 
-impl fmt::Display for AMM {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{{{:.1}:{} {:.1}:{}}}", self.r0, self.t0, self.r1, self.t1)
-    }
-}
+// Oracle manipulation: the lending market values collateral at the AMM spot
+// price, so M can push the price of t0 down with a swap, liquidate B at the
+// manipulated price, and swap back.
+fn lending_attack(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let b: User = User::new("B");
+    let m: User = User::new("M");
 
-#[derive(Clone)]
-struct State {
-    wallets: Vec<Wallet>,
-    amms:  Vec<AMM>
-}
+    let mut s0: State = State::new();
 
-impl fmt::Display for State {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let elms =
-            [self.wallets.iter().map(|x| x.to_string()).collect::<Vec<_>>(),
-            self.amms.iter().map(|x| x.to_string()).collect::<Vec<_>>()].concat();
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 200.0);
+    s0.set_balance(&b, &t0, 50.0);
+    s0.set_balance(&m, &t0, 40.0);
+    s0.set_balance(&m, &t1, 20.0);
+    s0.lending.oracle = Oracle::Spot(t1.clone());
+    s0.lending.add_market(&t0, 0.0);
+    s0.lending.add_market(&t1, 0.001);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.0).apply(&s0).unwrap();
+
+    let v: Vec<Box<dyn Transition>> = vec![
+        Box::new(Deposit::new(&o,100.0,&t0,100.0,&t1)),
+        Box::new(Supply::new(&o,&t1,100.0)),
+        Box::new(Supply::new(&b,&t0,50.0)),
+        Box::new(Borrow::new(&b,&t1,35.0)),
+        Box::new(AdvanceBlock::new(10)),
+        //manipulation
+        Box::new(Swap::new(&m,&t0,&t1,20.0)),
+        Box::new(Liquidate::new(&m,&b,&t1,17.5,&t0)),
+        Box::new(Swap::new(&m,&t1,&t0,16.7)),
+        Box::new(Withdraw::new(&m,&t0,26.0)),
+        Box::new(Repay::new(&b,&t1,10.0)),
+    ];
 
-        write!(f, "{}", elms.join(" | "))
+    println!("Initial: {:.1}", s0);
+    for t in v {
+        s0 = t.apply(&s0).unwrap();
+        println!("{:.1}", s0);
+        println!("\tB's health factor: {:.2}", s0.health_factor(&b));
+        println!("\ttotal net_wealth: {:.1}", s0.net_wealth(&price_oracle));
+        println!("\tO's net_wealth: {:.1}", s0.net_wealth_user(&o, &price_oracle));
+        println!("\tB's net_wealth: {:.1}", s0.net_wealth_user(&b, &price_oracle));
+        println!("\tM's net_wealth: {:.1}", s0.net_wealth_user(&m, &price_oracle));
     }
 }
 
-fn order_tokens<'a>( t0: &'a Token, t1: &'a Token) -> (&'a Token, &'a Token) {
-    assert!(t0 != t1);
-    if t0 < t1 {
-        return (t0, t1);
-    } else {
-        return (t1, t0);
-    }
-}
+// Agent-based run: a noise trader and a passive LP around a pool kept in line
+// with the external prices by an arbitrageur, with a sandwich bot watching
+fn simulation(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let n: User = User::new("N");
+    let p: User = User::new("P");
+    let r: User = User::new("R");
+    let m: User = User::new("M");
 
-fn order_token_reserves<'a>( t0: &'a Token, r0: f64, t1: &'a Token, r1: f64) -> (&'a Token, f64, &'a Token, f64) {
-    assert!(t0 != t1);
-    if t0 < t1 {
-        return (t0, r0, t1, r1);
-    } else {
-        return (t1, r1, t0, r0);
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 1000.0);
+    s0.set_balance(&o, &t1, 1000.0);
+    for u in [&n, &p, &r, &m] {
+        s0.set_balance(u, &t0, 100.0);
+        s0.set_balance(u, &t1, 100.0);
     }
-}
-
-impl State {
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.0).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,1000.0,&t0,1000.0,&t1).apply(&s0).unwrap();
+
+    let mut sim = Simulation::new(42, Oracle::Manual(vec![(t0.clone(), 1000.0), (t1.clone(), 1000.0)]));
+    //the market price of t0 moves away from the pool price
+    sim.feed.set_price(&t0, 1050.0).unwrap();
+    sim.add_agent(NoiseTrader::new(&n, &t0, &t1, 0.7, 0.5));
+    sim.add_agent(PassiveLP::new(&p, 100.0, &t0, 100.0, &t1));
+    sim.add_agent(Arbitrageur::new(&r, &t0, &t1));
+    sim.add_agent(SandwichBot::new(&m, 50.0));
+    let report = sim.run(&s0, 10);
 
-    fn new() -> Self {
-        State {
-            wallets: Vec::new(),
-            amms: Vec::new(),
+    println!("Initial: {:.1}", s0);
+    for step in &report.trace {
+        match &step.error {
+            None => println!("{}\t{}\t{:.1}", step.block, step.label, step.state),
+            Some(e) => println!("{}\t{}\tfailed: {:?}", step.block, step.label, e),
         }
     }
+    for (user, curve) in &report.wealth {
+        println!("{}'s net_wealth: {}", user, curve.iter().map(|w| format!("{:.1}", w)).collect::<Vec<_>>().join(" "));
+    }
+}
 
-    //Token supply. We define the supply of a token type τ in a state Γ as the sum of the 
-    //reserves of τ in all the wallets and the AMMs occurring in Γ. 
-    fn token_supply(&self, token: &Token) -> f64 {
-        //UNIMPLEMENTED
-        let mut total:f64 = 0.0;
-        for amm in &self.amms{
-            total = total + amm.get_reserves(&token);
-        }
-        for wallet in &self.wallets{
-            total = total + wallet.get_balance(&token);
-        }
-        return total
+// LP returns of a 0.3% pool over 100 days of a volatile external price.
+// The outcome of every path is written as CSV to the given file, if any.
+fn montecarlo(csv: Option<String>){
+    let params = montecarlo::Params {
+        paths: 2000,
+        steps: 100,
+        dt: 1.0/365.0,
+        drift: 0.0,
+        volatility: 0.8,
+        fee: 0.003,
+        r0: 1000.0,
+        r1: 1000.0,
+        seed: 42,
+    };
+    let outcomes = montecarlo::run(&params);
+    let summary = montecarlo::summarize(&outcomes).expect("no paths");
+    println!("{:>12} {:>9} {:>9} {:>9} {:>9} {:>9} {:>9}", "", "mean", "p5", "p25", "p50", "p75", "p95");
+    for (name, d) in &summary {
+        println!("{:>12} {:>9.4} {:>9.4} {:>9.4} {:>9.4} {:>9.4} {:>9.4}", name, d.mean, d.p5, d.p25, d.p50, d.p75, d.p95);
+    }
+    if let Some(path) = csv {
+        std::fs::write(&path, montecarlo::outcomes_csv(&outcomes)).unwrap();
+        std::fs::write(format!("{}.summary", path), montecarlo::summary_csv(&summary)).unwrap();
     }
+}
 
+// Fee switch proposal: a sixth of the 0.3% swap fee goes to the treasury T,
+// which is minted LP tokens when O redeems
+fn fee_switch(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let b: User = User::new("B");
+    let treasury: User = User::new("T");
 
-    fn get_amm(&self, t0: &Token, t1: &Token) -> Option<&AMM> {
-        //UNIMPLEMENTED
-        for amm in &self.amms {
-            if amm.t0 == *t0 && amm.t1 == *t1 || amm.t0 == *t1 && amm.t1 == *t0{
-                return Some(amm)
-            }
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 100.0);
+    s0.set_balance(&b, &t0, 100.0);
+    s0.set_balance(&b, &t1, 100.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,100.0,&t0,100.0,&t1).apply(&s0).unwrap();
+
+    let v: Vec<Box<dyn Transition>> = vec![
+        Box::new(SetProtocolFee::new(&t0,&t1,1.0/6.0,&treasury,FeeMode::Minted)),
+        Box::new(Swap::new(&b,&t0,&t1,50.0)),
+        Box::new(Swap::new(&b,&t1,&t0,40.0)),
+        Box::new(Swap::new(&b,&t0,&t1,30.0)),
+        Box::new(Redeem::new(&o,&t0,&t1,100.0)),
+    ];
+
+    println!("Initial: {:.1}", s0);
+    for t in v {
+        s0 = t.apply(&s0).unwrap();
+        println!("{}\t{:.1}", t, s0);
+        println!("\ttotal net_wealth: {:.1}", s0.net_wealth(&price_oracle));
+        for u in [&o, &b, &treasury] {
+            println!("\t{}'s net_wealth: {:.1}", u, s0.net_wealth_user(u, &price_oracle));
         }
-        return None
     }
+}
 
-    fn get_reserves(&self, t: &Token, tother: &Token) -> f64 {
+// First-depositor inflation attack: M deposits dust in a new pool, donates
+// to inflate the value of an LP token, and A's deposit is minted few LP
+// tokens, rounded down. The locked minimum liquidity makes it unprofitable.
+fn inflation(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let a: User = User::new("A");
+    let m: User = User::new("M");
 
-        let reserve = match self.get_amm(t,tother) {
-            Some(amm) => {
-                if amm.t0 == *t{
-                return amm.r0
-                }else{
-                    return amm.r1
-                }
-            },
+    let mut s0: State = State::new();
+    s0.set_balance(&a, &t0, 100.0);
+    s0.set_balance(&a, &t1, 100.0);
+    s0.set_balance(&m, &t0, 200.0);
+    s0.set_balance(&m, &t1, 200.0);
+
+    let dust = MINIMUM_LIQUIDITY + LP_UNIT;
+    let v: Vec<Box<dyn Transition>> = vec![
+        Box::new(CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.0)),
+        Box::new(Deposit::new(&m,dust,&t0,dust,&t1)),
+        Box::new(Donate::new(&m,100.0,&t0,100.0,&t1)),
+        Box::new(Deposit::new(&a,100.0,&t0,100.0,&t1)),
+        Box::new(Redeem::new(&m,&t0,&t1,2.0*dust - MINIMUM_LIQUIDITY)),
+    ];
 
-            None => return 0.0
-        };
-        // return reserve
+    println!("Initial: {:.1}", s0);
+    let mut s = s0.clone();
+    for t in &v {
+        s = t.apply(&s).unwrap();
+        println!("{}\t{:.1}", t, s);
     }
-
-    fn set_reserve(&mut self, t0: &Token, r0: f64, t1: &Token, r1: f64) {
-        //UNIMPLEMENTED
-        let mut i = 0;
-        for amm in &self.amms {
-            if amm.t0 == *t0 && amm.t1 == *t1 || amm.t0 == *t1 && amm.t1 == *t0{
-                break
-            }
-            i = i + 1;
-        }
-
-    
-        if i >= self.amms.len(){
-            let new_amm = AMM {
-                r0: r0,
-                t0: t0.clone(),
-                r1: r1,
-                t1: t1.clone()
-            };
-            self.amms.push(new_amm);
-        }else if self.amms[i].t0 == *t0 {
-            self.amms[i].r0 = r0;
-            self.amms[i].r1 = r1;
-        }else{
-            self.amms[i].r0 = r1;
-            self.amms[i].r1 = r0;
+    for alert in inflation::detect_inflation(&s0, &v, &price_oracle, 2.0) {
+        println!("step {}: {} inflated {} from {:.1} to {:.1}", alert.step, alert.donor, alert.lp_token,
+            alert.share_price_before, alert.share_price_after);
+        for (victim, loss) in &alert.victims {
+            println!("\t{} lost {:.1}", victim, loss);
         }
-        
-
+        println!("\t{} gained {:.1}", alert.donor, alert.donor_gain);
     }
+}
 
-  
-    fn get_balance(&self, user: &User, token:  &Token) -> f64 {
-        //UNIMPLEMENTED
-        let mut i = 0;
-        for wallet in &self.wallets {
-            if wallet.user == *user {
-                break
-            }
-            i = i + 1;
+// The same swap on a constant-product and on a constant-sum pool
+fn curves(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let b: User = User::new("B");
+
+    for curve in [Curve::ConstantProduct, Curve::ConstantSum] {
+        let mut s0: State = State::new();
+        s0.set_balance(&o, &t0, 100.0);
+        s0.set_balance(&o, &t1, 100.0);
+        s0.set_balance(&b, &t0, 50.0);
+        let v: Vec<Box<dyn Transition>> = vec![
+            Box::new(CreatePool::new(&t0,&t1,curve,0.003)),
+            Box::new(Deposit::new(&o,100.0,&t0,100.0,&t1)),
+        ];
+        for t in v {
+            s0 = t.apply(&s0).unwrap();
+            println!("{}\t{:.1}", t, s0);
         }
-        let mut j = 0;
-        for balance in &self.wallets[i].balances {
-            if balance.token == *token{
-                break
-            }
-            j = j + 1;
-            if j == self.wallets[i].balances.len(){
-                return 0.0
-            }
+        //quote the swap before making it
+        println!("spot {:.3}, out {:.3}, price after {:.3}, impact {:.2}%, in for 10:t1 {:.3}",
+            s0.spot_price(&t0,&t1).unwrap(), s0.quote_out(&t0,&t1,50.0).unwrap(),
+            s0.price_after(&t0,&t1,50.0).unwrap(), s0.price_impact(&t0,&t1,50.0).unwrap(),
+            s0.quote_in(&t0,&t1,10.0).unwrap());
+        let swap = Swap::new(&b,&t0,&t1,50.0);
+        s0 = swap.apply(&s0).unwrap();
+        println!("{}\t{:.1}", swap, s0);
+        if let Some((t, x)) = s0.amount_to_price(&t0,&t1,1.0) {
+            println!("{:.3}:{} to restore the price", x, t);
         }
-        
-        return self.wallets[i].balances[j].value;
-        // 0
     }
+}
 
-    fn set_balance(&mut self, user: &User, token:  &Token, new_value: f64) {
-        //UNIMPLEMENTED
-        let mut i = 0;
-        for wallet in &self.wallets {
-            if wallet.user == *user {
-                break
-            }
-            i = i + 1;
-        }
-
-        if i >= self.wallets.len() {
-
-            let new_wallet = Wallet {
-                user: user.clone(),
-                balances: Vec::new()
-            };
-            self.wallets.push(new_wallet);
-        }
+fn modelcheck(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let m: User = User::new("M");
 
-        let mut j = 0;
-        for balance in &self.wallets[i].balances {
-            if balance.token == *token {
-                break
-            }
-            j = j + 1;
-        }
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 100.0);
+    s0.set_balance(&a, &t0, 20.0);
+    s0.set_balance(&m, &t0, 20.0);
+    s0.set_balance(&m, &t1, 20.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,100.0,&t0,100.0,&t1).apply(&s0).unwrap();
 
-        if j >= self.wallets[i].balances.len(){
-            let new_balance = Balance {
-                token: token.clone(),
-                value: new_value
-            };
-            self.wallets[i].balances.push(new_balance);
-        }else{
-            self.wallets[i].balances[j].value = new_value;
-        }
+    //Conjecture: M cannot gain by trading around A
+    let moves = checker::grid_moves(&[a, m.clone()], &[5.0, 10.0, 20.0]);
+    let no_profit = |s0: &State, s: &State| s.net_wealth_user(&m, &price_oracle) <= s0.net_wealth_user(&m, &price_oracle) + 1e-6;
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let report = checker::check(&s0, 3, &moves, &no_profit, threads);
 
+    println!("Initial: {:.1}", s0);
+    println!("{} states explored, {} counterexamples", report.explored, report.counterexamples.len());
+    let best = report.counterexamples.iter()
+        .max_by(|c1, c2| c1.state.net_wealth_user(&m, &price_oracle).total_cmp(&c2.state.net_wealth_user(&m, &price_oracle)));
+    if let Some(c) = best {
+        println!("{}\t{:.1}", c.trace.join("; "), c.state);
+        println!("M gains {:.1}", c.state.net_wealth_user(&m, &price_oracle) - s0.net_wealth_user(&m, &price_oracle));
     }
+}
 
-    fn net_wealth_user(&self,user: &User, f: &dyn Fn(&State, &Token)-> f64) -> f64{
-        
-        let t0 = Token::Atomic(String::from("t0"));
-        let t1 = Token::Atomic(String::from("t1"));
-        let minted_token = Token::Minted(String::from("t0"),String::from("t1"));
-        let t0_balance = self.get_balance(user,&t0);
-        let t1_balance = self.get_balance(user,&t1);
-        let minted_balance = self.get_balance(user, &minted_token);
-        f(&self,&t0)*t0_balance + f(&self,&t1)*t1_balance +f(&self,&minted_token)*minted_balance
-    }
+fn smt_sandwich(model: Option<String>){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let m: User = User::new("M");
 
-    fn net_wealth(&self, f: &dyn Fn(&State,&Token) -> f64  ) -> f64{
-        let t0 = Token::Atomic(String::from("t0"));
-        let t1 = Token::Atomic(String::from("t1"));
-        let minted_token = Token::Minted(String::from("t0"),String::from("t1"));
-
-        let mut sum:f64 = 0.0;
-        let mut i:u32 = 0;
-        for wallet in &self.wallets {
-            for balance in &wallet.balances {
-                let t_value = f(&self,&balance.token)*balance.value;
-                sum += t_value;
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 100.0);
+    s0.set_balance(&a, &t0, 20.0);
+    s0.set_balance(&m, &t0, 50.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,100.0,&t0,100.0,&t1).apply(&s0).unwrap();
+
+    //M front-runs and back-runs the swap of A, with amounts left to the solver
+    let steps = vec![
+        smt::Symbolic::Swap { sender: m.clone(), tin: t0.clone(), tout: t1.clone(), x: smt::Amount::Var(String::from("x0")) },
+        smt::Symbolic::Swap { sender: a.clone(), tin: t0.clone(), tout: t1.clone(), x: smt::Amount::Const(20.0) },
+        smt::Symbolic::Swap { sender: m.clone(), tin: t1.clone(), tout: t0.clone(), x: smt::Amount::Var(String::from("x1")) },
+    ];
+
+    //Without a model, prints the query for a gain above the given bound (0 by
+    //default), to bisect on with any SMT-LIB solver; with the model found by a
+    //solver, replays the attack it describes
+    let model = match model {
+        Some(path) if path.parse::<f64>().is_err() => std::fs::read_to_string(path).expect("cannot read the model"),
+        bound => {
+            let bound = bound.map_or(0.0, |b| b.parse().unwrap());
+            print!("{}", smt::export(&s0, &steps, &m, &price_oracle, bound).unwrap());
+            return;
+        }
+    };
+    let values = smt::parse_model(&model).expect("invalid model");
+    println!("Initial: {:.1}", s0);
+    let mut s = s0.clone();
+    for t in smt::instantiate(&steps, &values).expect("incomplete model") {
+        match t.apply(&s) {
+            Ok(post) => {
+                s = post;
+                println!("{}\t{:.1}", t, s);
             }
+            Err(e) => println!("{}\tfailed: {:?}", t, e),
         }
-        sum
     }
-
-
-
-}
-
-#[derive(Debug)]
-enum TransitionError {
-    InsufficientBalance,
-    InvalidDepositRatio,
-    InsufficientReserves,
-    Unimplemented
+    println!("M gains {:.1}", s.net_wealth_user(&m, &price_oracle) - s0.net_wealth_user(&m, &price_oracle));
 }
 
+fn jit(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let j: User = User::new("J");
 
-trait Transition {
-    fn apply(&self, s0: &State) -> Result<State, TransitionError>;
-}
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 100.0);
+    s0.set_balance(&a, &t0, 100.0);
+    s0.set_balance(&j, &t0, 1000.0);
+    s0.set_balance(&j, &t1, 1000.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,100.0,&t0,100.0,&t1).apply(&s0).unwrap();
+    let victim = Swap::new(&a,&t0,&t1,100.0);
+
+    //J deposits m times the reserves around the swap of A
+    let optimal = jit::optimal_jit_multiple(100.0, 100.0, 0.003).unwrap();
+    println!("optimal multiple {:.3}", optimal);
+    println!("multiple\tJ\tO\tO alone\tA out\tA out alone");
+    for m in [0.5, 1.0, optimal, 2.0, 5.0, 10.0] {
+        let r = jit::jit_report(&s0, &j, &victim, m, &price_oracle).unwrap();
+        println!("{:.3}\t{:.1}\t{:.1}\t{:.1}\t{:.3}\t{:.3}", m, r.attacker_gain, r.lp_gain, r.lp_gain_without,
+            r.victim_out, r.victim_out_without);
+    }
 
-struct Deposit {
-    sender: User,
-    v0: f64,
-    t0: Token,
-    v1: f64,
-    t1: Token
+    let (deposit, redeem) = jit::jit_transitions(&s0, &j, &victim, optimal).unwrap();
+    let v: Vec<Box<dyn Transition>> = vec![Box::new(deposit), Box::new(victim), Box::new(redeem)];
+    println!("Initial: {:.1}", s0);
+    let mut s = s0.clone();
+    for t in &v {
+        s = t.apply(&s).unwrap();
+        println!("{}\t{:.1}", t, s);
+    }
 }
 
-impl Deposit  {
-    fn new(sender: &User, r0: f64, t0: &Token, r1: f64, t1: &Token) -> Self {
-        assert!(r0 > 0.0 && r1 > 0.0);
-        Deposit {
-            sender: sender.clone(),
-            v0: r0,
-            t0: t0.clone(),
-            v1: r1,
-            t1: t1.clone(),
+//The transactions of mev0 and mev1 executed in sequence and in a batch auction
+fn batch_auction(){
+    for (name, (s0, v)) in [("mev0", mev0_block()), ("mev1", mev1_block())] {
+        println!("{}: {}", name, v.iter().map(|t| t.to_string()).collect::<Vec<_>>().join("; "));
+        println!("\tuser\tsequential\tbatch");
+        for o in batch::compare(&s0, &v, &price_oracle).unwrap() {
+            println!("\t{}\t{:.1}\t{:.1}", o.user, o.sequential, o.batch);
         }
+        println!("\tsequential: {:.1}", batch::execute_sequential(&s0, &v).unwrap());
+        println!("\tbatch: {:.1}", batch::execute_batch(&s0, &v).unwrap());
     }
 }
 
-impl Transition for Deposit {
-    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
-        //UNIMPLEMENTED
-        let mut post = pre.clone();
-
-        let t0_balance:f64 = post.get_balance(&self.sender, &self.t0);
-        let t1_balance:f64 = post.get_balance(&self.sender, &self.t1);
-        let t0_reserve:f64 = post.get_reserves(&self.t0,&self.t1);
-        let t1_reserve:f64 = post.get_reserves(&self.t1,&self.t0);
-
-        post.set_balance(&self.sender,&self.t0, t0_balance - &self.v0);
-        post.set_balance(&self.sender,&self.t1, t1_balance - &self.v1);
-        post.set_reserve(&self.t0,t0_reserve+&self.v0,&self.t1,t1_reserve+&self.v1);
+//The sandwich of mev0 with a victim swapping the given amount of t0 with a
+//slippage limit of 25%, an attacker with the given budget of t0 and a pool
+//with the given reserves of both tokens
+fn mev0_sandwich(p: &sweep::Point) -> Result<sweep::Metrics, TransitionError> {
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let m: User = User::new("M");
+    let (victim, budget, reserves) = (p.get("victim"), p.get("budget"), p.get("reserves"));
 
-        //add LP Token
-        let lp_token = Token::mint(&self.t0, &self.t1);
-        post.set_balance(&self.sender,&lp_token,&self.v0+&self.v1);
-        
-        Result::Ok(post)
-        // Result::Err(TransitionError::Unimplemented)
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, reserves);
+    s0.set_balance(&o, &t1, reserves);
+    s0.set_balance(&a, &t0, victim);
+    s0.set_balance(&m, &t0, budget);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.0).apply(&s0)?;
+    s0 = Deposit::new(&o,reserves,&t0,reserves,&t1).apply(&s0)?;
+    let swap = Swap::new(&a,&t0,&t1,victim);
+
+    //inner layer, within the budget
+    let front = (SFr0(victim, 0.75 * victim, reserves, reserves) - reserves).min(budget);
+    let mut s = s0.clone();
+    if front > 0.0 {
+        s = Swap::new(&m,&t0,&t1,front).apply(&s)?;
+    }
+    s = swap.apply(&s)?;
+    //price minimization, with what the front-run gave
+    let back = price_mini_transaction(1000.0,1000.0,s.get_reserves(&t1,&t0),s.get_reserves(&t0,&t1))
+        .min(s.get_balance(&m,&t1));
+    if back > 0.0 {
+        s = Swap::new(&m,&t1,&t0,back).apply(&s)?;
     }
-}
 
-struct Redeem {
-    sender: User,
-    t0: Token,
-    t1: Token,
-    v: f64,
+    let alone = swap.apply(&s0)?;
+    Ok(vec![
+        ("attacker_profit", s.net_wealth_user(&m,&price_oracle) - s0.net_wealth_user(&m,&price_oracle)),
+        ("victim_loss", alone.net_wealth_user(&a,&price_oracle) - s.net_wealth_user(&a,&price_oracle)),
+        ("r0", s.get_reserves(&t0,&t1)),
+        ("r1", s.get_reserves(&t1,&t0)),
+    ])
 }
 
-impl Redeem  {
-    fn new(sender: &User, t0: &Token, t1: &Token, v: f64) -> Self {
-        assert!(v > 0.0);
-        Redeem {
-            sender: sender.clone(),
-            t0: t0.clone(),
-            t1: t1.clone(),
-            v,
-        }
+//mev0 over a range of victim sizes, attacker budgets and initial reserves.
+//Every point is written as CSV to the given file, if any.
+fn sweep(csv: Option<String>){
+    let ranges = vec![
+        sweep::Range::linear("victim", 5.0, 40.0, 8),
+        sweep::Range::linear("budget", 0.0, 20.0, 5),
+        sweep::Range::new("reserves", &[100.0, 200.0, 400.0]),
+    ];
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let table = sweep::run(ranges, &mev0_sandwich, threads);
+    println!("{}", table.heatmap("victim", "budget", "attacker_profit"));
+    println!("{}", table.heatmap("victim", "reserves", "victim_loss"));
+    if let Some(path) = csv {
+        std::fs::write(&path, table.csv()).unwrap();
     }
 }
 
-impl Transition for Redeem {
-    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
-        //UNIMPLEMENTED
-        let mut post = pre.clone();
-        let lp_token = Token::mint(&self.t0, &self.t1);
-        let lp_supply = post.token_supply(&lp_token);
-        let ratio_redeem = self.v/lp_supply;
-        let t0_reserve = post.get_reserves(&self.t0,&self.t1);
-        let t1_reserve = post.get_reserves(&self.t1,&self.t0);
-        let t0_balance = post.get_balance(&self.sender,&self.t0);
-        let t1_balance = post.get_balance(&self.sender,&self.t1);
-        let lp_balance = post.get_balance(&self.sender,&lp_token);
-
-        post.set_reserve(&self.t0,t0_reserve - t0_reserve*self.v/lp_supply,&self.t1,t1_reserve- t1_reserve*self.v/lp_supply);
-        post.set_balance(&self.sender,&self.t0, t0_balance + t0_reserve*self.v/lp_supply);
-        post.set_balance(&self.sender,&self.t1, t1_balance + t1_reserve*self.v/lp_supply);
-        post.set_balance(&self.sender,&lp_token, lp_balance - self.v);
-
-        Result::Ok(post)
-        // Result::Err(TransitionError::Unimplemented)
+//Charts of the reserves and of the net wealth of every user along mev1,
+//written as SVG to the given directory
+fn charts(dir: Option<String>){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let dir = std::path::PathBuf::from(dir.unwrap_or(String::from(".")));
+    let (s0, v) = mev1_block();
+
+    let mut states = vec![s0];
+    for t in v {
+        let next = t.apply(states.last().unwrap()).unwrap();
+        states.push(next);
     }
+    let reserves = dir.join("mev1_reserves.svg");
+    let wealth = dir.join("mev1_wealth.svg");
+    std::fs::write(&reserves, svg::reserves_chart(&states, &t0, &t1)).unwrap();
+    std::fs::write(&wealth, svg::wealth_chart(&svg::wealth_series(&states, &price_oracle))).unwrap();
+    println!("wrote {} and {}", reserves.display(), wealth.display());
 }
 
+//Liquidity mining: a farm emits 10 r per block to the stakers of the LP
+//token of t0/t1. O stakes for 100 blocks; S deposits and stakes three times
+//as much in the last block, claims and leaves.
+fn mining(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let r = Token::Atomic(String::from("r"));
+    let o: User = User::new("O");
+    let sniper: User = User::new("S");
+    let feed = Oracle::Manual(vec![(t0.clone(), 1000.0), (t1.clone(), 1000.0), (r.clone(), 10.0)]);
+    let f = |s: &State, t: &Token| feed.price(s, t);
 
-struct Swap {
-    sender: User,
-    tin: Token,
-    tout: Token,
-    x: f64,
-}
-
-impl Swap {
-    // tin means token to be in the AMM
-    fn new(sender: &User, tin: &Token, tout: &Token, x: f64) -> Self {
-        Swap {
-            sender: sender.clone(),
-            tin: tin.clone(),
-            tout: tout.clone(),
-            x,
-        }
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 100.0);
+    s0.set_balance(&sniper, &t0, 300.0);
+    s0.set_balance(&sniper, &t1, 300.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.0).apply(&s0).unwrap();
+    s0.rewards.add_farm(&Token::mint(&t0, &t1), &r, 10.0, 0);
+
+    let v: Vec<Box<dyn Transition>> = vec![
+        Box::new(Deposit::new(&o,100.0,&t0,100.0,&t1)),
+        Box::new(Stake::new(&o,&t0,&t1,199.0)),
+        Box::new(AdvanceBlock::new(99)),
+        //sniping
+        Box::new(Deposit::new(&sniper,300.0,&t0,300.0,&t1)),
+        Box::new(Stake::new(&sniper,&t0,&t1,600.0)),
+        Box::new(AdvanceBlock::new(1)),
+    ];
+    println!("Initial: {:.1}", s0);
+    for t in v {
+        s0 = t.apply(&s0).unwrap();
+        println!("{}\t{:.1}", t, s0);
     }
-}
+    let naive = rewards::snapshot_rewards(&s0, &Token::mint(&t0, &t1), 0);
 
-impl Transition for Swap {
-    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
-        //UNIMPLEMENTED
-        let mut post = pre.clone();
-        let pre_in_balance = post.get_balance(&self.sender,&self.tin);
-        let pre_out_balance = post.get_balance(&self.sender, &self.tout);
-        //get reserves before swap
-        let pre_out_reserve = post.get_reserves(&self.tout,&self.tin);
-        let pre_in_reserve = post.get_reserves(&self.tin,&self.tout);
-        //calculate constant K
-        let constant_num = pre_out_reserve * pre_in_reserve;
-        //calculate reserves after swap
-        let post_in_reserve = pre_in_reserve + self.x ;
-        let post_out_reserve = constant_num/post_in_reserve ;
-        //set in token balance
-        post.set_balance(&self.sender,&self.tin,pre_in_balance - self.x);
-        //set out token balance
-        post.set_balance(&self.sender,&self.tout,pre_out_balance + pre_out_reserve-post_out_reserve);
-        //set post in and out reserve
-        post.set_reserve(&self.tin,post_in_reserve,&self.tout,post_out_reserve);
-
-        Result::Ok(post)
-        // Result::Err(TransitionError::Unimplemented)
+    let v: Vec<Box<dyn Transition>> = vec![
+        Box::new(Claim::new(&sniper,&t0,&t1)),
+        Box::new(Unstake::new(&sniper,&t0,&t1,600.0)),
+        Box::new(Redeem::new(&sniper,&t0,&t1,600.0)),
+    ];
+    for t in v {
+        s0 = t.apply(&s0).unwrap();
+        println!("{}\t{:.1}", t, s0);
     }
+    println!("\tS's rewards: {:.1} (a snapshot at block {} would give {:.1})",
+        s0.get_balance(&sniper, &r), s0.block, naive.iter().find(|(u, _)| *u == sniper).unwrap().1);
+    println!("\tO's pending rewards: {:.1}", s0.rewards.get_pending(&o, &Token::mint(&t0, &t1), s0.block));
+    println!("\tO's net_wealth: {:.1}", s0.net_wealth_user(&o, &f));
+    println!("\tS's net_wealth: {:.1}", s0.net_wealth_user(&sniper, &f));
 }
 
-fn price_oracle(s: &State, t: &Token) -> f64 {
-    match t{
-        Token::Atomic(token0) => {
-            if token0 == "t0" {
-                return 1000.0
-            }else if token0 == "t1"{
-                return 1000.0
-            }
-            0.0
-        }
-        Token::Minted(t0, t1) => {
-            // let minted_balance :f64 = s.token_supply(t);
-            let token0 = Token::Atomic(String::from(t0));
-            let token1 = Token::Atomic(String::from(t1));
-            // let amm = Some(s.get_amm(&token0, &token1)) ;
-            let r0 = s.get_reserves(&token0,&token1);
-            let r1 = s.get_reserves(&token1,&token0);
-
-            let mut sum = 0.0;
-            for wallet in &s.wallets {
-                for balance in &wallet.balances {
-                    if balance.token == *t {
-                        sum += balance.value;
-                    }
-                }
-            }
+//Priority gas auction: three arbitrageurs compete for a mispriced pool,
+//bidding tips in t1 to the block producer P
+fn pga(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let p: User = User::new("P");
+    let feed = Oracle::Manual(vec![(t0.clone(), 1100.0), (t1.clone(), 1000.0)]);
 
-            (1000.0*r0 + 1000.0*r1)/sum
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 100.0);
+    s0.set_balance(&o, &t1, 100.0);
+    for name in ["M1", "M2", "M3"] {
+        s0.set_balance(&User::new(name), &t0, 50.0);
+        s0.set_balance(&User::new(name), &t1, 50.0);
+    }
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,100.0,&t0,100.0,&t1).apply(&s0).unwrap();
+
+    let mut searchers = vec![
+        pga::Searcher::new(Arbitrageur::new(&User::new("M1"), &t0, &t1), 0.001, 0.5),
+        pga::Searcher::new(Arbitrageur::new(&User::new("M2"), &t0, &t1), 0.001, 0.9),
+        pga::Searcher::new(Arbitrageur::new(&User::new("M3"), &t0, &t1), 0.001, 0.99),
+    ];
+    let (s1, report) = pga::run(&s0, &mut searchers, &p, &t1, &feed, &mut amm_theory::rng::Rng::new(0), 1000);
+    let last = report.rounds.len() - 1;
+    for (i, round) in report.rounds.iter().enumerate() {
+        if i < 3 || i == last {
+            println!("round {}: {}", i + 1, round.iter().map(|(u, tip)| format!("{}={:.3}", u, tip)).collect::<Vec<_>>().join(" "));
+        } else if i == 3 {
+            println!("...");
         }
     }
+    println!("block: {}", report.block.join("; "));
+    println!("{:.1}", s1);
+    println!("\tuser\tvalue\ttip\tkept");
+    for o in &report.outcomes {
+        println!("\t{}\t{:.1}\t{:.1}\t{:.1}", o.user, o.value, o.tip * 1000.0, o.kept);
+    }
+    println!("\tproducer revenue: {:.1}", report.producer_revenue);
 }
 
-fn SFr0(v0:f64,v1:f64,r0:f64,r1:f64) -> f64{
-    let variable0:f64 = v0*v0*v1*v1 + 4.0*v0*v1*r0*r1;
-    let res:f64 = (variable0.sqrt() - v0*v1)/(2.0*v1);
-    res
-}
-
-fn SFr1(r0:f64,r1:f64,sfr0: f64) -> f64 {
-    r0*r1/sfr0
-}
-
-fn price_mini_transaction(p0:f64,p1:f64,r0:f64,r1:f64) -> f64 {
-    let p = p1/p0 * r0 * r1;
-    let w0 = p.sqrt() - r0;
-    w0
-}
-
-
-
-fn mev0(){
+//The sandwich of mev0 as a game: M chooses whether and how much to
+//front-run, A whether and how much to swap, valuing t1 above the pool price
+fn sandwich_game(){
     let t0 = Token::Atomic(String::from("t0"));
     let t1 = Token::Atomic(String::from("t1"));
     let o: User = User::new("O");
     let a: User = User::new("A");
     let m: User = User::new("M");
+    let feed = Oracle::Manual(vec![(t0.clone(), 1000.0), (t1.clone(), 1200.0)]);
+    let f = |s: &State, t: &Token| feed.price(s, t);
 
     let mut s0: State = State::new();
-
     s0.set_balance(&o, &t0, 100.0);
     s0.set_balance(&o, &t1, 100.0);
     s0.set_balance(&a, &t0, 20.0);
-    s0.set_balance(&a, &t1, 0.0);
-    s0.set_balance(&m, &t0, 5.9);
-    s0.set_balance(&m, &t1, 20.6);
-
-
-    let mut v: Vec<Box<dyn Transition>> = Vec::new();
-    v.push(Box::new(Deposit::new(&o,100.0,&t0,100.0,&t1)));
-    
-    //inner layer
-    let sfr0 = SFr0(20.0, 15.0, 100.0,100.0);
-    let v0 = sfr0 - 100.0;
-    let sfr1 = SFr1(s0.get_reserves(&t0, &t1), s0.get_reserves(&t1, &t0), sfr0);
-    v.push(Box::new(Swap::new(&m,&t0,&t1,v0)));
+    s0.set_balance(&m, &t0, 20.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,100.0,&t0,100.0,&t1).apply(&s0).unwrap();
+
+    let mut strategies = vec![game::Strategy::new("none", Vec::new())];
+    for x in [5.0, 15.0] {
+        let out = s0.quote_out(&t0, &t1, x).unwrap();
+        strategies.push(game::Strategy::new(&format!("sandwich {}", x), vec![
+            Box::new(Swap::new(&m,&t0,&t1,x)),
+            Box::new(Swap::new(&m,&t1,&t0,out)),
+        ]));
+    }
+    let mut g = game::Game::new(&s0);
+    g.add_player(&m, strategies);
+    g.add_player(&a, vec![
+        game::Strategy::new("none", Vec::new()),
+        game::Strategy::new("swap 10", vec![Box::new(Swap::new(&a,&t0,&t1,10.0))]),
+        game::Strategy::new("swap 20", vec![Box::new(Swap::new(&a,&t0,&t1,20.0))]),
+    ]);
+
+    let payoffs = g.payoffs(&f);
+    for profile in payoffs.profiles() {
+        let p = payoffs.get(&profile);
+        println!("{:<28}\tM: {:>8.1}\tA: {:>8.1}\twelfare: {:>8.1}", g.label(&profile), p[0], p[1], payoffs.welfare(&profile));
+    }
+    let equilibria = payoffs.nash_equilibria();
+    if equilibria.is_empty() {
+        println!("no pure equilibrium");
+    }
+    for eq in equilibria {
+        println!("equilibrium: {}\twelfare loss: {:.1}", g.label(&eq), payoffs.welfare_loss(&eq));
+    }
+    let dynamics = payoffs.best_response_dynamics(&[0, 0]);
+    println!("best responses: {}{}", dynamics.path.iter().map(|p| g.label(p)).collect::<Vec<_>>().join(" -> "),
+        if dynamics.converged { "" } else { " (cycle)" });
+    let (optimum, welfare) = payoffs.optimum();
+    println!("cooperative optimum: {}\twelfare: {:.1}", g.label(&optimum), welfare);
+}
 
-    v.push(Box::new(Swap::new(&a,&t0,&t1,20.0)));
-    
-    //price minimization
-    let v0 = price_mini_transaction(1000.0,1000.0,79.4, 125.9);
-    v.push(Box::new(Swap::new(&m,&t1,&t0,v0)));
-    //
+//Cross-chain arbitrage: the pool of chain 0 follows a volatile external
+//price, the cross-chain arbitrageur X trades the gap with the pool of chain
+//1 and bridges its inventory back. The longer the bridge delay, the more
+//inventory is in flight and exposed to the price.
+fn cross_chain(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let x: User = User::new("X");
+    let l: User = User::new("L");
 
-    println!("Initial: {:.1}", s0);
-    for t in v {
-        s0 = t.apply(&s0).unwrap();
-        // println!("reserve: {:.1}", s0.get_reserves(&t0, &t1));
-        println!("{:.1}", s0);
-        println!("\ttotal net_wealth: {:.1}", s0.net_wealth(&price_oracle));
-        println!("\tO's net_wealth: {:.1}", s0.net_wealth_user(&o, &price_oracle));
-        println!("\tA's net_wealth: {:.1}", s0.net_wealth_user(&a, &price_oracle));
-        println!("\tM's net_wealth: {:.1}", s0.net_wealth_user(&m, &price_oracle));
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 1000.0);
+    s0.set_balance(&o, &t1, 1000.0);
+    s0.set_balance(&x, &t0, 100.0);
+    s0.set_balance(&x, &t1, 100.0);
+    s0.set_balance(&l, &t0, 1e6);
+    s0.set_balance(&l, &t1, 1e6);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,1000.0,&t0,1000.0,&t1).apply(&s0).unwrap();
+
+    let arb = bridge::CrossArbitrageur::new(&x, &t0, &t1, 0.01);
+    println!("{:>6} {:>10} {:>10} {:>10} {:>10} {:>10}", "delay", "captured", "fees", "inventory", "(stdev)", "in flight");
+    println!("{:>6} {:>10} {:>10} {:>10} {:>10} {:>10}", "", "", "", "vs hold", "", "(max)");
+    for delay in [1, 10, 50] {
+        let w = bridge::World::new(&s0, &s0, delay, 0.001, &User::new("B"));
+        let reports: Vec<bridge::Report> = (0..50)
+            .map(|seed| bridge::run(&w, &arb, &mut Arbitrageur::new(&l, &t0, &t1), 1.0, 0.01, 500, &mut amm_theory::rng::Rng::new(seed)).1)
+            .collect();
+        let mean = |g: fn(&bridge::Report) -> f64| reports.iter().map(g).sum::<f64>() / reports.len() as f64;
+        let inventory = mean(|r| r.inventory_pnl);
+        let stdev = (reports.iter().map(|r| (r.inventory_pnl - inventory).powi(2)).sum::<f64>() / reports.len() as f64).sqrt();
+        println!("{:>6} {:>10.3} {:>10.3} {:>10.3} {:>10.3} {:>10.3}", delay, mean(|r| r.captured), mean(|r| r.bridge_fees),
+            inventory, stdev, mean(|r| r.max_in_flight));
     }
 }
 
-fn mev1(){
+//Sandwich exposure of A selling 100 t0 at once, or as a long-term order over
+//10 blocks. M front-runs with the given amount and sells back what it got,
+//around the swap or around every block of the order; in the last column an
+//arbitrageur B restores the price first in every block, before M sells back.
+fn twamm(){
     let t0 = Token::Atomic(String::from("t0"));
     let t1 = Token::Atomic(String::from("t1"));
     let o: User = User::new("O");
     let a: User = User::new("A");
+    let b: User = User::new("B");
     let m: User = User::new("M");
 
     let mut s0: State = State::new();
-
-    s0.set_balance(&o, &t0, 100.0);
-    s0.set_balance(&o, &t1, 100.0);
+    s0.set_balance(&o, &t0, 1000.0);
+    s0.set_balance(&o, &t1, 1000.0);
     s0.set_balance(&a, &t0, 100.0);
-    s0.set_balance(&a, &t1, 100.0);
-    s0.set_balance(&m, &t0, 100.0);
-    s0.set_balance(&m, &t1, 100.0);
-
-
-    let mut v: Vec<Box<dyn Transition>> = Vec::new();
-    v.push(Box::new(Deposit::new(&o,100.0,&t0,100.0,&t1)));
-    
-    //inner layer
-    let sfr0 = SFr0(40.0, 35.0, 100.0,100.0);
-    let mut v0 = 100.0 - sfr0;
-    let sfr1 = SFr1(s0.get_reserves(&t0, &t1), s0.get_reserves(&t1, &t0), sfr0);
-    v.push(Box::new(Swap::new(&m,&t1,&t0,v0)));
+    s0.set_balance(&b, &t0, 1000.0);
+    s0.set_balance(&b, &t1, 1000.0);
+    s0.set_balance(&m, &t0, 1000.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,1000.0,&t0,1000.0,&t1).apply(&s0).unwrap();
+
+    let gain = |s: &State, u: &User| s.net_wealth_user(u, &price_oracle) - s0.net_wealth_user(u, &price_oracle);
+    let front = |s: &State, v: f64| -> (State, f64) {
+        (Swap::new(&m,&t0,&t1,v).apply(s).unwrap(), s.quote_out(&t0, &t1, v).unwrap())
+    };
+    println!("{:>6} {:>10} {:>10} {:>10} {:>10} {:>10}", "front", "swap", "", "twamm", "", "raced");
+    println!("{:>6} {:>10} {:>10} {:>10} {:>10} {:>10}", "", "M", "A", "M", "A", "M");
+    for v in [0.0, 10.0, 50.0, 200.0] {
+        let swap = if v > 0.0 {
+            let (s, out) = front(&s0, v);
+            Swap::new(&m,&t1,&t0,out).apply(&Swap::new(&a,&t0,&t1,100.0).apply(&s).unwrap()).unwrap()
+        } else {
+            Swap::new(&a,&t0,&t1,100.0).apply(&s0).unwrap()
+        };
+        let mut held = twamm::LongTermSwap::new(&a,&t0,&t1,100.0,10).apply(&s0).unwrap();
+        let mut raced = held.clone();
+        for _ in 0..10 {
+            if v == 0.0 {
+                held = AdvanceBlock::new(1).apply(&held).unwrap();
+                continue;
+            }
+            let (s, out) = front(&held, v);
+            held = Swap::new(&m,&t1,&t0,out).apply(&AdvanceBlock::new(1).apply(&s).unwrap()).unwrap();
+            let (s, out) = front(&raced, v);
+            let s = AdvanceBlock::new(1).apply(&s).unwrap();
+            let (tin, x) = s.amount_to_price(&t0, &t1, 1.0).unwrap();
+            let tout = if tin == t0 { &t1 } else { &t0 };
+            raced = Swap::new(&m,&t1,&t0,out).apply(&Swap::new(&b,&tin,tout,x).apply(&s).unwrap()).unwrap();
+        }
+        println!("{:>6.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1} {:>10.1}", v, gain(&swap, &m), gain(&swap, &a),
+            gain(&held, &m), gain(&held, &a), if v > 0.0 { gain(&raced, &m) } else { 0.0 });
+    }
 
-    v.push(Box::new(Swap::new(&a,&t0,&t1,40.0)));
-    v.push(Box::new(Swap::new(&m,&t1,&t0,38.3)));
-    v.push(Box::new(Deposit::new(&a,30.0,&t0,40.0,&t1)));
-    
-    //price minimization
-    v0 = price_mini_transaction(1000.0,1000.0,117.0, 155.0);
-    v.push(Box::new(Swap::new(&m,&t0,&t1,v0)));
-    v.push(Box::new(Redeem::new(&a,&t0,&t1,10.0)));
+    //cancelling halfway refunds the unsold half
+    let s = twamm::LongTermSwap::new(&a,&t0,&t1,100.0,10).apply(&s0).unwrap();
+    let s = AdvanceBlock::new(5).apply(&s).unwrap();
+    let cancel = twamm::CancelOrder::new(&a, 0);
+    let s = cancel.apply(&s).unwrap();
+    println!("{}: {:.3}", cancel, s);
+}
 
-    
-    //price minimization
-    let v0 = price_mini_transaction(1000.0,1000.0,78.0, 129.0);
-    // v.push(Box::new(Swap::new(&m,&t1,&t0,v0)));
-    //
+//Fixed and dynamic fee pools in a market that is calm for 250 blocks and
+//volatile for the next 250: LP returns with respect to holding, arbitrage
+//gains and noise traders' gains, averaged over 20 runs
+fn dynamic_fee(){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
+    let a: User = User::new("A");
+    let n: User = User::new("N");
 
-    println!("Initial: {:.1}", s0);
-    for t in v {
-        s0 = t.apply(&s0).unwrap();
-        // println!("reserve: {:.1}", s0.get_reserves(&t0, &t1));
-        println!("{:.1}", s0);
-        println!("\ttotal net_wealth: {:.1}", s0.net_wealth(&price_oracle));
-        println!("\tO's net_wealth: {:.1}", s0.net_wealth_user(&o, &price_oracle));
-        println!("\tA's net_wealth: {:.1}", s0.net_wealth_user(&a, &price_oracle));
-        println!("\tM's net_wealth: {:.1}", s0.net_wealth_user(&m, &price_oracle));
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 1000.0);
+    s0.set_balance(&o, &t1, 1000.0);
+    for u in [&a, &n] {
+        s0.set_balance(u, &t0, 1000.0);
+        s0.set_balance(u, &t1, 1000.0);
+    }
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,1000.0,&t0,1000.0,&t1).apply(&s0).unwrap();
+
+    let volatility: Vec<f64> = (0..500).map(|b| if b < 250 { 0.002 } else { 0.02 }).collect();
+    let pools: Vec<(String, State)> = vec![
+        (String::from("fixed 0.3%"), s0.clone()),
+        (String::from("fixed 1%"), SetDynamicFee::new(&t0,&t1,0.01,0.01,0.0,1).apply(&s0).unwrap()),
+        (String::from("dynamic"), SetDynamicFee::new(&t0,&t1,0.001,0.05,0.4,10).apply(&s0).unwrap()),
+    ];
+    println!("{:>12} {:>10} {:>10} {:>10} {:>10} {:>10}", "pool", "fee calm", "volatile", "LP-hold", "arbitrage", "traders");
+    for (name, s) in pools {
+        let reports: Vec<dynamic_fee::Report> = (0..20)
+            .map(|seed| {
+                let mut sim = Simulation::new(seed, Oracle::Manual(vec![(t0.clone(), 1.0), (t1.clone(), 1.0)]));
+                sim.add_agent(NoiseTrader::new(&n, &t0, &t1, 0.5, 0.01));
+                sim.add_agent(Arbitrageur::new(&a, &t0, &t1));
+                dynamic_fee::run(&s, &mut sim, &t0, &t1, &o, &a, &volatility).1
+            })
+            .collect();
+        let mean = |g: &dyn Fn(&dynamic_fee::Report) -> f64| reports.iter().map(g).sum::<f64>() / reports.len() as f64;
+        let fee = |r: &dynamic_fee::Report, blocks: std::ops::Range<usize>| r.fees[blocks].iter().sum::<f64>() / 250.0;
+        println!("{:>12} {:>9.3}% {:>9.3}% {:>10.3} {:>10.3} {:>10.3}", name,
+            100.0 * mean(&|r| fee(r, 0..250)), 100.0 * mean(&|r| fee(r, 250..500)),
+            mean(&|r| r.lp_vs_hold), mean(&|r| r.arbitrage), mean(&|r| r.traders));
     }
 }
 
-fn mev3(){
-let t0 = Token::Atomic(String::from("t0"));
+//Stale prices: the market price of t0 falls by 2% per block for 30 blocks,
+//and the lending market reads a feed published by R on deviation or on a
+//heartbeat. M backruns every update with the liquidations it enables.
+fn stale_feed(){
+    let t0 = Token::Atomic(String::from("t0"));
     let t1 = Token::Atomic(String::from("t1"));
+    let o: User = User::new("O");
     let a: User = User::new("A");
-    let b =  User::new("B");
+    let m: User = User::new("M");
 
-    let mut s0 = State::new();
+    let mut s0: State = State::new();
+    s0.set_balance(&o, &t0, 1000.0);
+    s0.set_balance(&o, &t1, 2000.0);
+    s0.set_balance(&m, &t1, 1000.0);
+    s0.set_balance(&a, &t0, 1000.0);
+    s0.set_balance(&a, &t1, 1000.0);
+    s0.lending.oracle = Oracle::Manual(vec![(t0.clone(), 1.0), (t1.clone(), 1.0)]);
+    s0.lending.reporter = Some(User::new("R"));
+    s0.lending.add_market(&t0, 0.0);
+    s0.lending.add_market(&t1, 0.0);
+    s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&s0).unwrap();
+    s0 = Deposit::new(&o,1000.0,&t0,1000.0,&t1).apply(&s0).unwrap();
+    s0 = Supply::new(&o,&t1,1000.0).apply(&s0).unwrap();
+    for (i, ltv) in [0.5, 0.6, 0.7, 0.74].iter().enumerate() {
+        let b = User::new(&format!("B{}", i + 1));
+        s0.set_balance(&b, &t0, 100.0);
+        s0 = Supply::new(&b,&t0,100.0).apply(&s0).unwrap();
+        s0 = Borrow::new(&b,&t1,100.0 * ltv).apply(&s0).unwrap();
+    }
 
-    s0.set_balance(&a, &t0, 100.0);
-    s0.set_balance(&a, &t1, 100.0);
-    s0.set_balance(&b, &t0, 100.0);
-    s0.set_balance(&b, &t1, 100.0);
+    let prices: Vec<f64> = (1..=40).map(|b| 0.98f64.powi(b.min(30))).collect();
+    println!("{:>18} {:>8} {:>10} {:>8} {:>10} {:>10} {:>10}", "feed", "updates", "staleness", "liquid.", "extracted", "bad debt", "(peak)");
+    for (name, deviation, heartbeat) in [("every block", 0.0, 1), ("deviation 1%", 0.01, 100), ("deviation 5%", 0.05, 100), ("heartbeat 10", 1.0, 10), ("heartbeat 20", 1.0, 20)] {
+        let mut sim = Simulation::new(0, Oracle::Manual(vec![(t0.clone(), 1.0), (t1.clone(), 1.0)]));
+        sim.add_agent(feed::Reporter::new(&User::new("R"), std::slice::from_ref(&t0), deviation, heartbeat));
+        sim.add_agent(feed::Backrunner::new(&m));
+        sim.add_agent(Arbitrageur::new(&a, &t0, &t1));
+        let r = feed::run(&s0, &mut sim, &t0, &m, &prices).1;
+        println!("{:>18} {:>8} {:>9.2}% {:>8} {:>10.3} {:>10.3} {:>10.3}", name, r.updates, 100.0 * r.staleness,
+            r.liquidations, r.extracted, r.bad_debt, r.peak_bad_debt);
+    }
+}
 
-    let mut v: Vec<Box<dyn Transition>> = Vec::new();
+//The mev1 block as a table of the paper, in Markdown or (with the argument
+//latex) as a LaTeX tabular, M's transactions highlighted
+fn table(format: Option<String>){
+    let (s0, v) = mev1_block();
+    let steps = table::trace(&s0, &v).unwrap();
+    let options = table::Options { precision: 1, attacker: Some(User::new("M")) };
+    match format.as_deref() {
+        Some("latex") => print!("{}", table::latex(&s0, &steps, &price_oracle, &options)),
+        _ => print!("{}", table::markdown(&s0, &steps, &price_oracle, &options)),
+    }
+}
 
-    v.push( Box::new(Deposit::new(&a,100.0, &t0, 100.0, &t1)));
+//Replay of a log of pool events, from a CSV or JSON file if given. The
+//sample log has a donation synced into the reserves between blocks 103 and
+//105, which the model cannot see: the reserves diverge from then on.
+const SAMPLE_LOG: &str = "block,kind,sender,amount0,amount1,reserve0,reserve1
+100,mint,0xlp,1000,2000,1000,2000
+101,swap,0xa1,50,-94.965948,1050,1905.034052
+101,swap,0xb2,-42.194806,80,1007.805194,1985.034052
+103,swap,0xa1,-19.848384,40,987.95681,2025.034052
+105,swap,0xc3,30,-58.358903,1037.95681,1966.675149
+106,swap,0xb2,-12.990094,25,1024.966716,1991.675149
+107,burn,0xlp,-102.496672,-199.167515,922.470044,1792.507634
+";
+
+fn replay_log(path: Option<String>){
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let text = match &path {
+        Some(path) => std::fs::read_to_string(path).expect("cannot read the log"),
+        None => String::from(SAMPLE_LOG),
+    };
+    let events = match &path {
+        Some(path) if path.ends_with(".json") => replay::parse_json(&text),
+        _ => replay::parse_csv(&text),
+    }.expect("invalid log");
+    let s0 = CreatePool::new(&t0,&t1,Curve::ConstantProduct,0.003).apply(&State::new()).unwrap();
+    let (_, report) = replay::replay(&s0, &t0, &t1, &events, 1e-6);
+    println!("{:>6} {:<38} {:>24} {:>24} {:>10}", "block", "transition", "simulated", "logged", "diverg.");
+    for (i, r) in report.steps.iter().enumerate() {
+        let label = match &r.error {
+            Some(e) => format!("{} ({:?})", r.label, e),
+            None => r.label.clone(),
+        };
+        let flag = if report.divergent.contains(&i) { " <-" } else { "" };
+        println!("{:>6} {:<38} {:>11.4} {:>12.4} {:>11.4} {:>12.4} {:>10.2e}{}", r.block, label,
+            r.simulated.0, r.simulated.1, r.logged.0, r.logged.1, r.divergence(), flag);
+    }
+    println!("{} of {} events diverge from the log", report.divergent.len(), report.steps.len());
+}
 
-    v.push( Box::new(Swap::new(&b,&t1, &t0, 13.0)));
-    v.push( Box::new(Swap::new(&b,&t0, &t1, 40.0)));
-    v.push( Box::new(Swap::new(&b,&t0, &t1, 30.0)));
-    // v.push( Box::new(Redeem::new(&a,&t0, &t1, 30.0)));
-    // v.push( Box::new(Swap::new(&b,&t0, &t1, 30.0)));
-    // v.push( Box::new(Redeem::new(&a,&t0, &t1, 30.0)));
+//MEV detected in the mev0 and mev1 blocks, with the value extracted by each
+//pattern and the losses of its victims with respect to the block without it
+fn detect_mev(){
+    for (name, (s0, v)) in [("mev0", mev0_block()), ("mev1", mev1_block())] {
+        println!("{}:", name);
+        for p in detect::detect(&s0, &v, &price_oracle) {
+            let a = detect::attribute(&s0, &v, &p, &price_oracle);
+            let attack: Vec<String> = p.attack.iter().map(|i| v[*i].to_string()).collect();
+            let losses: Vec<String> = a.losses.iter().map(|(u, l)| format!("{} {:.1}", u, l)).collect();
+            let losses = if losses.is_empty() { String::from("nothing") } else { losses.join(", ") };
+            println!("\t{:?} by {}: {}", p.kind, p.attacker, attack.join(" "));
+            println!("\t\textracted {:.1}, lost {}", a.extracted, losses);
+        }
+    }
+}
 
-    println!("Initial: {}", s0);
-    for t in v {
-        s0 = t.apply(&s0).unwrap();
-        println!("{}", s0);
+//Gains of the users in the mev0 and mev1 blocks, in f64 and enclosed in
+//intervals: a gain is robust when its interval does not contain zero
+fn interval_gains(){
+    for (name, (s0, v)) in [("mev0", mev0_block()), ("mev1", mev1_block())] {
+        println!("{}:", name);
+        let (e, gains) = interval::gains(&s0, &v, &price_oracle).unwrap();
+        for g in gains {
+            let robust = if g.robust() { "robust" } else { "artifact?" };
+            println!("\t{}'s gain: {:.6} in {:.6} {}", g.user, g.value, g.interval, robust);
+        }
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        println!("\treserves: {:.12} {:.12}", e.reserve(&t0, &t1), e.reserve(&t1, &t0));
     }
 }
 
 fn main() {
-    // mev3();
-    // mev0();
-    mev1();
+    match std::env::args().nth(1).as_deref() {
+        Some("mev0") => mev0(),
+        Some("mev3") => mev3(),
+        Some("lending") => lending_attack(),
+        Some("simulation") => simulation(),
+        Some("fee_switch") => fee_switch(),
+        Some("inflation") => inflation(),
+        Some("curves") => curves(),
+        Some("modelcheck") => modelcheck(),
+        Some("jit") => jit(),
+        Some("batch") => batch_auction(),
+        Some("sweep") => sweep(std::env::args().nth(2)),
+        Some("svg") => charts(std::env::args().nth(2)),
+        Some("mining") => mining(),
+        Some("pga") => pga(),
+        Some("game") => sandwich_game(),
+        Some("bridge") => cross_chain(),
+        Some("twamm") => twamm(),
+        Some("dynamic_fee") => dynamic_fee(),
+        Some("feed") => stale_feed(),
+        Some("table") => table(std::env::args().nth(2)),
+        Some("replay") => replay_log(std::env::args().nth(2)),
+        Some("detect") => detect_mev(),
+        Some("interval") => interval_gains(),
+        Some("smt") => smt_sandwich(std::env::args().nth(2)),
+        Some("montecarlo") => montecarlo(std::env::args().nth(2)),
+        _ => mev1(),
+    }
     ///////////////////////////////////AMM
     // let t0 = Token::Atomic(String::from("dai"));
     // let t1 = Token::Atomic(String::from("eth"));
@@ -0,0 +1,194 @@
+use crate::agent::{Arbitrageur, Simulation};
+use crate::oracle::Oracle;
+use crate::rng::Rng;
+use crate::{CreatePool, Curve, Deposit, State, Token, Transition, User};
+
+// A pool t0/t1 whose LP is the only liquidity provider, while the external
+// price of t0 (in units of t1) follows a geometric Brownian motion and an
+// arbitrageur realigns the pool at every step
+pub struct Params {
+    pub paths: usize,
+    pub steps: usize,
+    // length of a step, in the time unit of drift and volatility
+    pub dt: f64,
+    pub drift: f64,
+    pub volatility: f64,
+    pub fee: f64,
+    // initial reserves: the external price starts at the pool price r1/r0
+    pub r0: f64,
+    pub r1: f64,
+    pub seed: u64,
+}
+
+// Result of a path. Returns, fee income and loss-versus-rebalancing are
+// fractions of the initial value of the LP position.
+pub struct Outcome {
+    pub final_price: f64,
+    pub lp_return: f64,
+    // return of holding the initial reserves instead of depositing them
+    pub hodl_return: f64,
+    pub fee_income: f64,
+    // value lost by the pool to the arbitrageur, w.r.t. trading at the external price
+    pub lvr: f64,
+}
+
+pub fn run(params: &Params) -> Vec<Outcome> {
+    (0..params.paths).map(|path| run_path(params, path as u64)).collect()
+}
+
+fn run_path(params: &Params, path: u64) -> Outcome {
+    let t0 = Token::Atomic(String::from("t0"));
+    let t1 = Token::Atomic(String::from("t1"));
+    let lp = User::new("LP");
+    let arb = User::new("R");
+
+    let mut s = State::new();
+    s.set_balance(&lp, &t0, params.r0);
+    s.set_balance(&lp, &t1, params.r1);
+    s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, params.fee).apply(&s).unwrap();
+    s = Deposit::new(&lp, params.r0, &t0, params.r1, &t1).apply(&s).unwrap();
+    s.set_balance(&arb, &t0, 1e12);
+    s.set_balance(&arb, &t1, 1e12);
+
+    let mut price = params.r1 / params.r0;
+    let mut sim = Simulation::new(params.seed, Oracle::Manual(vec![(t0.clone(), price), (t1.clone(), 1.0)]));
+    sim.add_agent(Arbitrageur::new(&arb, &t0, &t1));
+    let mut gbm = Rng::new(params.seed ^ path.wrapping_mul(0x9e3779b97f4a7c15));
+
+    let initial = s.net_wealth_user(&lp, &|s, t| sim.feed.price(s, t));
+    let mut fee_income = 0.0;
+    let mut lvr = 0.0;
+    let mut trace = Vec::new();
+    for _ in 0..params.steps {
+        let sigma = params.volatility;
+        price *= ((params.drift - sigma * sigma / 2.0) * params.dt + sigma * params.dt.sqrt() * gbm.normal()).exp();
+        sim.feed.set_price(&t0, price).unwrap();
+
+        let (r0, r1) = (s.get_reserves(&t0, &t1), s.get_reserves(&t1, &t0));
+        s = sim.step(&s, &mut trace);
+        trace.clear();
+        let d0 = s.get_reserves(&t0, &t1) - r0;
+        let d1 = s.get_reserves(&t1, &t0) - r1;
+        // the pool received one token, fee included, and gave away the other
+        if d0 > 0.0 {
+            fee_income += params.fee * d0 * price;
+            lvr += -d1 - d0 * (1.0 - params.fee) * price;
+        } else if d1 > 0.0 {
+            fee_income += params.fee * d1;
+            lvr += -d0 * price - d1 * (1.0 - params.fee);
+        }
+    }
+
+    let last = s.net_wealth_user(&lp, &|s, t| sim.feed.price(s, t));
+    Outcome {
+        final_price: price,
+        lp_return: last / initial - 1.0,
+        hodl_return: (params.r0 * price + params.r1) / initial - 1.0,
+        fee_income: fee_income / initial,
+        lvr: lvr / initial,
+    }
+}
+
+pub struct Distribution {
+    pub mean: f64,
+    pub p5: f64,
+    pub p25: f64,
+    pub p50: f64,
+    pub p75: f64,
+    pub p95: f64,
+}
+
+impl Distribution {
+    // None for no values
+    pub fn of(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        Some(Distribution {
+            mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+            p5: percentile(&sorted, 0.05),
+            p25: percentile(&sorted, 0.25),
+            p50: percentile(&sorted, 0.50),
+            p75: percentile(&sorted, 0.75),
+            p95: percentile(&sorted, 0.95),
+        })
+    }
+}
+
+// Linear interpolation between the closest ranks
+fn percentile(sorted: &[f64], q: f64) -> f64 {
+    let rank = q * (sorted.len() - 1) as f64;
+    let lo = rank.floor() as usize;
+    let hi = rank.ceil() as usize;
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+// None when no path was simulated
+pub fn summarize(outcomes: &[Outcome]) -> Option<Vec<(&'static str, Distribution)>> {
+    let metric = |f: fn(&Outcome) -> f64| Distribution::of(&outcomes.iter().map(f).collect::<Vec<_>>());
+    Some(vec![
+        ("final_price", metric(|o| o.final_price)?),
+        ("lp_return", metric(|o| o.lp_return)?),
+        ("hodl_return", metric(|o| o.hodl_return)?),
+        ("fee_income", metric(|o| o.fee_income)?),
+        ("lvr", metric(|o| o.lvr)?),
+    ])
+}
+
+pub fn outcomes_csv(outcomes: &[Outcome]) -> String {
+    let mut csv = String::from("path,final_price,lp_return,hodl_return,fee_income,lvr\n");
+    for (i, o) in outcomes.iter().enumerate() {
+        csv += &format!("{},{},{},{},{},{}\n", i, o.final_price, o.lp_return, o.hodl_return, o.fee_income, o.lvr);
+    }
+    csv
+}
+
+pub fn summary_csv(summary: &[(&'static str, Distribution)]) -> String {
+    let mut csv = String::from("metric,mean,p5,p25,p50,p75,p95\n");
+    for (name, d) in summary {
+        csv += &format!("{},{},{},{},{},{},{}\n", name, d.mean, d.p5, d.p25, d.p50, d.p75, d.p95);
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(volatility: f64, fee: f64) -> Params {
+        Params { paths: 50, steps: 30, dt: 1.0 / 365.0, drift: 0.0, volatility, fee, r0: 1000.0, r1: 1000.0, seed: 3 }
+    }
+
+    #[test]
+    fn constant_price_leaves_the_lp_untouched() {
+        for o in run(&params(0.0, 0.003)) {
+            assert!(o.lp_return.abs() < 1e-12);
+            assert_eq!(o.fee_income, 0.0);
+            assert_eq!(o.lvr, 0.0);
+        }
+    }
+
+    #[test]
+    fn without_fees_the_lp_loses_against_holding() {
+        let outcomes = run(&params(0.8, 0.0));
+        for o in &outcomes {
+            assert_eq!(o.fee_income, 0.0);
+            assert!(o.lvr >= -1e-12);
+            assert!(o.lp_return <= o.hodl_return + 1e-12);
+        }
+        assert_eq!(outcomes_csv(&outcomes), outcomes_csv(&run(&params(0.8, 0.0))));
+    }
+
+    #[test]
+    fn percentiles_interpolate_between_ranks() {
+        let d = Distribution::of(&[4.0, 0.0, 2.0, 1.0, 3.0]).unwrap();
+        assert_eq!(d.mean, 2.0);
+        assert_eq!(d.p50, 2.0);
+        assert_eq!(d.p25, 1.0);
+        assert!((d.p95 - 3.8).abs() < 1e-12);
+        assert!(Distribution::of(&[]).is_none());
+        assert!(summarize(&run(&Params { paths: 0, ..params(0.8, 0.0) })).is_none());
+    }
+}
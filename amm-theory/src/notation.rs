@@ -0,0 +1,330 @@
+use crate::dynamic_fee::SetDynamicFee;
+use crate::feed::UpdatePrice;
+use crate::lending::{Borrow, Liquidate, Market, Repay, Supply, Withdraw};
+use crate::pga::Tip;
+use crate::protocol_fee::{FeeMode, SetProtocolFee};
+use crate::rewards::{Claim, Farm, Stake, Unstake};
+use crate::twamm::{CancelOrder, LongTermSwap};
+use crate::{
+    AdvanceBlock, Balance, CreatePool, Curve, Deposit, Donate, Redeem, State, Swap, Token, Transition, User, Wallet, AMM,
+};
+
+// Parser of the notation of the paper, as printed by Display: states like
+// `A[20.0:t0,0.0:t1] | {100.0:t0 100.0:t1}` and transitions like
+// `A:swap(t0,t1,20)` or `O:dep(100:t0,100:t1)`
+
+#[derive(Debug)]
+pub enum ParseError {
+    Syntax(String),
+    UnknownTransition(String),
+}
+
+fn syntax(what: &str, input: &str) -> ParseError {
+    ParseError::Syntax(format!("expected {}: {}", what, input))
+}
+
+fn number(input: &str) -> Result<f64, ParseError> {
+    input.trim().parse().map_err(|_| syntax("a number", input))
+}
+
+fn name(input: &str) -> Result<String, ParseError> {
+    let input = input.trim();
+    if input.is_empty() || !input.chars().all(|c| c.is_alphanumeric() || c == '_') {
+        return Err(syntax("a name", input));
+    }
+    Ok(String::from(input))
+}
+
+pub fn parse_token(input: &str) -> Result<Token, ParseError> {
+    match input.split_once('+') {
+        // LP tokens name the pair in either order
+        Some((t0, t1)) => Ok(Token::mint(&Token::Atomic(name(t0)?), &Token::Atomic(name(t1)?))),
+        None => Ok(Token::Atomic(name(input)?)),
+    }
+}
+
+fn atomic(input: &str) -> Result<Token, ParseError> {
+    match parse_token(input)? {
+        Token::Minted(_, _) => Err(syntax("an atomic token", input)),
+        t => Ok(t),
+    }
+}
+
+// `v:t`
+fn balance(input: &str) -> Result<(f64, Token), ParseError> {
+    let (v, t) = input.split_once(':').ok_or_else(|| syntax("value:token", input))?;
+    Ok((number(v)?, parse_token(t)?))
+}
+
+fn wallet(input: &str) -> Result<Wallet, ParseError> {
+    let (user, rest) = input.split_once('[').ok_or_else(|| syntax("a wallet", input))?;
+    let rest = rest.strip_suffix(']').ok_or_else(|| syntax("]", input))?;
+    let mut w = Wallet::new(&User::new(&name(user)?));
+    for b in rest.split(',').filter(|b| !b.trim().is_empty()) {
+        let (value, token) = balance(b)?;
+        w.balances.push(Balance { token, value });
+    }
+    Ok(w)
+}
+
+// `{r0:t0 r1:t1}`, a constant-product pool with no fee
+fn amm(input: &str) -> Result<AMM, ParseError> {
+    let inner = input.strip_prefix('{').and_then(|i| i.strip_suffix('}')).ok_or_else(|| syntax("a pool", input))?;
+    let reserves: Vec<&str> = inner.split_whitespace().collect();
+    if reserves.len() != 2 {
+        return Err(syntax("two reserves", input));
+    }
+    let (r0, t0) = balance(reserves[0])?;
+    let (r1, t1) = balance(reserves[1])?;
+    if !matches!(t0, Token::Atomic(_)) || !matches!(t1, Token::Atomic(_)) || t0 >= t1 {
+        return Err(syntax("ordered atomic tokens", input));
+    }
+    Ok(AMM::new(r0, &t0, r1, &t1))
+}
+
+// `L{cash:t debt:debt}`, a market with no interest whose shares are
+// worth one token
+fn market(input: &str) -> Result<Market, ParseError> {
+    let inner = input.strip_prefix("L{").and_then(|i| i.strip_suffix('}')).ok_or_else(|| syntax("a market", input))?;
+    let parts: Vec<&str> = inner.split_whitespace().collect();
+    let debt = parts.get(1).and_then(|d| d.strip_suffix(":debt")).ok_or_else(|| syntax("debt", input))?;
+    let (cash, token) = balance(parts[0])?;
+    let debt = number(debt)?;
+    Ok(Market {
+        token,
+        rate: 0.0,
+        cash,
+        shares: cash + debt,
+        scaled_borrows: debt,
+        borrow_index: 1.0,
+        last_block: 0,
+    })
+}
+
+// `F{staked:t0+t1 rate:reward/block}`, a farm whose stakes are unknown
+fn farm(input: &str) -> Result<Farm, ParseError> {
+    let inner = input.strip_prefix("F{").and_then(|i| i.strip_suffix('}')).ok_or_else(|| syntax("a farm", input))?;
+    let parts: Vec<&str> = inner.split_whitespace().collect();
+    let rate = parts.get(1).and_then(|r| r.strip_suffix("/block")).ok_or_else(|| syntax("a rate", input))?;
+    let (staked, lp_token) = balance(parts[0])?;
+    let (rate, reward) = balance(rate)?;
+    if !matches!(lp_token, Token::Minted(_, _)) || !matches!(reward, Token::Atomic(_)) {
+        return Err(syntax("an LP token and an atomic reward", input));
+    }
+    Ok(Farm { lp_token, reward, rate, staked, acc_per_share: 0.0, last_block: 0 })
+}
+
+pub fn parse_state(input: &str) -> Result<State, ParseError> {
+    let mut s = State::new();
+    for part in input.split(" | ").map(str::trim).filter(|p| !p.is_empty()) {
+        if part.starts_with('{') {
+            s.amms.push(amm(part)?);
+        } else if part.starts_with("L{") {
+            s.lending.markets.push(market(part)?);
+        } else if part.starts_with("F{") {
+            s.rewards.farms.push(farm(part)?);
+        } else {
+            s.wallets.push(wallet(part)?);
+        }
+    }
+    Ok(s)
+}
+
+pub fn parse_transition(input: &str) -> Result<Box<dyn Transition>, ParseError> {
+    let input = input.trim();
+    let (head, args) = input.split_once('(').ok_or_else(|| syntax("a transition", input))?;
+    let args = args.strip_suffix(')').ok_or_else(|| syntax(")", input))?;
+    let args: Vec<&str> = args.split(',').map(str::trim).collect();
+    let (sender, op) = match head.split_once(':') {
+        Some((sender, op)) => (Some(sender), op),
+        None => (None, head),
+    };
+    let arity = |n: usize| if args.len() == n { Ok(()) } else { Err(syntax(&format!("{} arguments", n), input)) };
+    let user = || -> Result<User, ParseError> { Ok(User::new(&name(sender.ok_or_else(|| syntax("a sender", input))?)?)) };
+    let amount = |arg: &str| -> Result<(f64, Token), ParseError> {
+        match balance(arg)? {
+            (v, t) if v > 0.0 => Ok((v, t)),
+            _ => Err(syntax("a positive amount", arg)),
+        }
+    };
+    let atomic_amount = |arg: &str| -> Result<(f64, Token), ParseError> {
+        match balance(arg)? {
+            (v, Token::Atomic(t)) if v >= 0.0 => Ok((v, Token::Atomic(t))),
+            _ => Err(syntax("an amount of an atomic token", arg)),
+        }
+    };
+
+    let t: Box<dyn Transition> = match (sender, op) {
+        (Some(_), "swap") => {
+            arity(3)?;
+            Box::new(Swap::new(&user()?, &atomic(args[0])?, &atomic(args[1])?, number(args[2])?))
+        }
+        (Some(_), "dep") | (Some(_), "donate") => {
+            arity(2)?;
+            let (v0, t0) = atomic_amount(args[0])?;
+            let (v1, t1) = atomic_amount(args[1])?;
+            if t0 == t1 {
+                return Err(syntax("two tokens", input));
+            }
+            match op {
+                "dep" if v0 > 0.0 && v1 > 0.0 => Box::new(Deposit::new(&user()?, v0, &t0, v1, &t1)),
+                "donate" if v0 + v1 > 0.0 => Box::new(Donate::new(&user()?, v0, &t0, v1, &t1)),
+                _ => return Err(syntax("positive amounts", input)),
+            }
+        }
+        (Some(_), "rdm") => {
+            arity(1)?;
+            match amount(args[0])? {
+                (v, Token::Minted(t0, t1)) => Box::new(Redeem::new(&user()?, &Token::Atomic(t0), &Token::Atomic(t1), v)),
+                _ => return Err(syntax("an LP token", input)),
+            }
+        }
+        (Some(_), "stake" | "unstake" | "claim") => {
+            arity(1)?;
+            let (t0, t1) = match op {
+                "claim" => match parse_token(args[0])? {
+                    Token::Minted(t0, t1) => (t0, t1),
+                    _ => return Err(syntax("an LP token", input)),
+                },
+                _ => match amount(args[0])? {
+                    (_, Token::Minted(t0, t1)) => (t0, t1),
+                    _ => return Err(syntax("an LP token", input)),
+                },
+            };
+            let (t0, t1) = (Token::Atomic(t0), Token::Atomic(t1));
+            match op {
+                "stake" => Box::new(Stake::new(&user()?, &t0, &t1, amount(args[0])?.0)),
+                "unstake" => Box::new(Unstake::new(&user()?, &t0, &t1, amount(args[0])?.0)),
+                _ => Box::new(Claim::new(&user()?, &t0, &t1)),
+            }
+        }
+        (Some(_), "supply" | "withdraw" | "borrow" | "repay") => {
+            arity(1)?;
+            let (v, token) = amount(args[0])?;
+            match op {
+                "supply" => Box::new(Supply::new(&user()?, &token, v)),
+                "withdraw" => Box::new(Withdraw::new(&user()?, &token, v)),
+                "borrow" => Box::new(Borrow::new(&user()?, &token, v)),
+                _ => Box::new(Repay::new(&user()?, &token, v)),
+            }
+        }
+        (Some(_), "liquidate") => {
+            arity(3)?;
+            let (v, debt_token) = amount(args[1])?;
+            Box::new(Liquidate::new(&user()?, &User::new(&name(args[0])?), &debt_token, v, &parse_token(args[2])?))
+        }
+        (Some(_), "twamm") => {
+            arity(4)?;
+            let (x, blocks) = (number(args[2])?, args[3].parse().map_err(|_| syntax("a number of blocks", input))?);
+            if x <= 0.0 || blocks == 0 {
+                return Err(syntax("a positive amount and number of blocks", input));
+            }
+            Box::new(LongTermSwap::new(&user()?, &atomic(args[0])?, &atomic(args[1])?, x, blocks))
+        }
+        (Some(_), "cancel") => {
+            arity(1)?;
+            let id = args[0].parse().map_err(|_| syntax("an order id", input))?;
+            Box::new(CancelOrder::new(&user()?, id))
+        }
+        (Some(_), "update") => {
+            arity(2)?;
+            let price = number(args[1])?;
+            if price <= 0.0 {
+                return Err(syntax("a positive price", input));
+            }
+            Box::new(UpdatePrice::new(&user()?, &atomic(args[0])?, price))
+        }
+        (Some(_), "tip") => {
+            arity(2)?;
+            let (v, token) = amount(args[1])?;
+            Box::new(Tip::new(&user()?, &User::new(&name(args[0])?), &token, v))
+        }
+        (Some("gov"), "fee") => {
+            arity(5)?;
+            let mode = match args[4] {
+                "minted" => FeeMode::Minted,
+                "tokens" => FeeMode::Tokens,
+                _ => return Err(syntax("minted or tokens", input)),
+            };
+            let fraction = number(args[2])?;
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(syntax("a fraction", input));
+            }
+            Box::new(SetProtocolFee::new(&atomic(args[0])?, &atomic(args[1])?, fraction, &User::new(&name(args[3])?), mode))
+        }
+        (Some("gov"), "dynfee") => {
+            arity(6)?;
+            let (min, max, sensitivity) = (number(args[2])?, number(args[3])?, number(args[4])?);
+            let window: u64 = args[5].parse().map_err(|_| syntax("a number of blocks", input))?;
+            if !(0.0 <= min && min <= max && max < 1.0 && sensitivity >= 0.0 && window > 0) {
+                return Err(syntax("fee bounds", input));
+            }
+            Box::new(SetDynamicFee::new(&atomic(args[0])?, &atomic(args[1])?, min, max, sensitivity, window))
+        }
+        (None, "create") => {
+            arity(4)?;
+            let (t0, t1) = (atomic(args[0])?, atomic(args[1])?);
+            let curve = match args[2] {
+                "cp" => Curve::ConstantProduct,
+                "cs" => Curve::ConstantSum,
+                _ => return Err(syntax("cp or cs", input)),
+            };
+            let fee = number(args[3])?;
+            if t0 == t1 || !(0.0..1.0).contains(&fee) {
+                return Err(syntax("a pool", input));
+            }
+            Box::new(CreatePool::new(&t0, &t1, curve, fee))
+        }
+        (None, "tick") => {
+            arity(1)?;
+            let blocks: u64 = args[0].parse().map_err(|_| syntax("a number of blocks", input))?;
+            if blocks == 0 {
+                return Err(syntax("a positive number of blocks", input));
+            }
+            Box::new(AdvanceBlock::new(blocks))
+        }
+        _ => return Err(ParseError::UnknownTransition(String::from(input))),
+    };
+    Ok(t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn states_round_trip() {
+        let input = "O[0.0:t0,0.0:t1,200.0:t0+t1] | A[20.0:t0,0.0:t1] | M[] | {100.0:t0 100.0:t1} | L{50.0:t0 35.0:debt} | F{100.0:t0+t1 2.5:r/block}";
+        let s = parse_state(input).unwrap();
+        assert_eq!(s.to_string(), input);
+        assert_eq!(s.get_balance(&User::new("O"), &Token::Minted(String::from("t0"), String::from("t1"))), 200.0);
+        assert_eq!(s.lending.get_cash(&Token::Atomic(String::from("t0"))), 50.0);
+        assert_eq!(parse_state("O[5.0:t1+t0]").unwrap().get_balance(&User::new("O"), &Token::Minted(String::from("t0"), String::from("t1"))), 5.0);
+
+        // at full precision the parsed state is the printed one
+        let mut s = State::new();
+        s.set_balance(&User::new("B"), &Token::Atomic(String::from("t1")), 1.0 / 3.0);
+        let printed = format!("{:.17}", s);
+        assert_eq!(parse_state(&printed).unwrap().get_balance(&User::new("B"), &Token::Atomic(String::from("t1"))), 1.0 / 3.0);
+        assert!(parse_state("A[20.0:t0,x:t1]").is_err());
+        assert!(parse_state("{1.0:t1 1.0:t0}").is_err());
+    }
+
+    #[test]
+    fn transitions_round_trip() {
+        for input in [
+            "A:swap(t0,t1,20)", "O:dep(100:t0,100:t1)", "O:rdm(100:t0+t1)", "M:donate(1:t0,0:t1)",
+            "create(t0,t1,cp,0.003)", "tick(10)", "B:supply(50:t0)", "B:withdraw(5:t0)", "B:borrow(35:t1)",
+            "B:repay(10:t1)", "M:liquidate(B,17.5:t1,t0)", "gov:fee(t0,t1,0.1,T,tokens)",
+            "O:stake(50:t0+t1)", "O:unstake(10:t0+t1)", "O:claim(t0+t1)", "M:tip(P,2.5:t1)",
+            "A:twamm(t0,t1,100,10)", "A:cancel(0)", "gov:dynfee(t0,t1,0.001,0.01,0.5,10)", "R:update(t0,0.9)",
+        ] {
+            assert_eq!(parse_transition(input).unwrap().to_string(), input);
+        }
+        let t = parse_transition(" A:swap(t0, t1, 2.5) ").unwrap();
+        assert_eq!(t.downcast_ref::<Swap>().unwrap().x, 2.5);
+        assert!(matches!(parse_transition("A:mint(t0)"), Err(ParseError::UnknownTransition(_))));
+        assert!(matches!(parse_transition("A:swap(t0,t1)"), Err(ParseError::Syntax(_))));
+        assert!(matches!(parse_transition("A:rdm(10:t0)"), Err(ParseError::Syntax(_))));
+    }
+}
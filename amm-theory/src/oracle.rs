@@ -0,0 +1,80 @@
+use crate::{twamm, State, Token, TransitionError};
+
+// Price source used by protocols that need to value tokens on their own,
+// e.g. a lending market checking the health of its borrowers.
+#[derive(Clone)]
+pub enum Oracle {
+    // externally given prices, e.g. `price_oracle`
+    External(fn(&State, &Token) -> f64),
+    // AMM spot prices in units of a numeraire token. Since they are read
+    // from the pools, a swap moves them: this is the manipulable oracle.
+    Spot(Token),
+    // an external price feed that can be updated, e.g. by a simulation
+    // driving the market price of the tokens or by a reporter
+    Manual(Vec<(Token, f64)>),
+}
+
+impl Oracle {
+    pub fn price(&self, s: &State, t: &Token) -> f64 {
+        match self {
+            Oracle::External(f) => f(s, t),
+            // read the pools as settled up to the current block
+            Oracle::Spot(numeraire) => match twamm::settled_view(s) {
+                Some(settled) => spot_price(&settled, t, numeraire),
+                None => spot_price(s, t, numeraire),
+            },
+            Oracle::Manual(prices) => match t {
+                Token::Atomic(_) => prices.iter().find(|(pt, _)| pt == t).map_or(0.0, |(_, p)| *p),
+                Token::Minted(_, _) => minted_price(s, t, &|s, t| self.price(s, t)),
+            },
+        }
+    }
+
+    // Only a manual oracle can be updated
+    pub fn set_price(&mut self, t: &Token, p: f64) -> Result<(), TransitionError> {
+        let prices = match self {
+            Oracle::Manual(prices) => prices,
+            _ => return Err(TransitionError::UnknownFeed),
+        };
+        match prices.iter_mut().find(|(pt, _)| pt == t) {
+            Some(entry) => entry.1 = p,
+            None => prices.push((t.clone(), p)),
+        }
+        Ok(())
+    }
+}
+
+// A minted token is worth its share of the underlying reserves
+fn minted_price(s: &State, t: &Token, f: &dyn Fn(&State, &Token) -> f64) -> f64 {
+    if let Token::Minted(t0, t1) = t {
+        let token0 = Token::Atomic(t0.clone());
+        let token1 = Token::Atomic(t1.clone());
+        let supply = s.token_supply(t);
+        if supply == 0.0 {
+            return 0.0;
+        }
+        let r0 = s.get_reserves(&token0, &token1);
+        let r1 = s.get_reserves(&token1, &token0);
+        return (r0 * f(s, &token0) + r1 * f(s, &token1)) / supply;
+    }
+    panic!("not a minted token");
+}
+
+// Spot price of t in units of the numeraire, read from the AMM pairing t with
+// the numeraire.
+pub fn spot_price(s: &State, t: &Token, numeraire: &Token) -> f64 {
+    if t == numeraire {
+        return 1.0;
+    }
+    match t {
+        Token::Atomic(_) => {
+            let r = s.get_reserves(t, numeraire);
+            let rn = s.get_reserves(numeraire, t);
+            if r == 0.0 {
+                return 0.0;
+            }
+            rn / r
+        }
+        Token::Minted(_, _) => minted_price(s, t, &|s, t| spot_price(s, t, numeraire)),
+    }
+}
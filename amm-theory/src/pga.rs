@@ -0,0 +1,241 @@
+use std::fmt;
+
+use crate::agent::{simulate, Agent};
+use crate::oracle::Oracle;
+use crate::rng::Rng;
+use crate::{State, Token, Transition, TransitionError, User};
+
+// Priority gas auction. Searchers competing for the same opportunity bid
+// tips to the block producer in repeated rounds, each raising the best bid of
+// the others while it is still worth it, and the builder orders the bids by
+// tip. Bids are atomic, as bundles: a bid whose transactions fail, or leave
+// its searcher worse off once the tip is paid, is dropped and pays nothing.
+
+// Transfers v tokens from the sender to the receiver, e.g. a tip to the
+// block producer
+pub struct Tip {
+    sender: User,
+    receiver: User,
+    token: Token,
+    v: f64,
+}
+
+impl Tip {
+    pub fn new(sender: &User, receiver: &User, token: &Token, v: f64) -> Self {
+        assert!(v > 0.0);
+        Tip { sender: sender.clone(), receiver: receiver.clone(), token: token.clone(), v }
+    }
+}
+
+impl fmt::Display for Tip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:tip({},{}:{})", self.sender, self.receiver, self.v, self.token)
+    }
+}
+
+impl Transition for Tip {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        let balance = post.get_balance(&self.sender, &self.token);
+        if balance < self.v {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        post.set_balance(&self.sender, &self.token, balance - self.v);
+        let received = post.get_balance(&self.receiver, &self.token);
+        post.set_balance(&self.receiver, &self.token, received + self.v);
+        Ok(post)
+    }
+}
+
+// A searcher takes the transactions its agent would submit to an empty
+// block, and bids up to max_share of their value in increments
+pub struct Searcher {
+    agent: Box<dyn Agent>,
+    increment: f64,
+    max_share: f64,
+}
+
+impl Searcher {
+    pub fn new<A: Agent + 'static>(agent: A, increment: f64, max_share: f64) -> Self {
+        assert!(increment > 0.0 && (0.0..=1.0).contains(&max_share));
+        Searcher { agent: Box::new(agent), increment, max_share }
+    }
+
+    pub fn user(&self) -> &User {
+        self.agent.user()
+    }
+}
+
+pub struct Bid {
+    pub searcher: User,
+    pub tip: f64,
+    pub txs: Vec<Box<dyn Transition>>,
+}
+
+pub struct SearcherOutcome {
+    pub user: User,
+    // gain of its transactions alone, before the tip
+    pub value: f64,
+    pub tip: f64,
+    pub included: bool,
+    // change of its net wealth, tip paid
+    pub kept: f64,
+}
+
+pub struct Report {
+    // the bid of every searcher after each round, in units of the tip token
+    pub rounds: Vec<Vec<(User, f64)>>,
+    pub block: Vec<String>,
+    pub outcomes: Vec<SearcherOutcome>,
+    pub producer_revenue: f64,
+}
+
+// Runs the auction for at most max_rounds rounds, or until no searcher
+// raises, then builds and executes the block
+pub fn run(
+    s: &State,
+    searchers: &mut [Searcher],
+    producer: &User,
+    tip_token: &Token,
+    feed: &Oracle,
+    rng: &mut Rng,
+    max_rounds: usize,
+) -> (State, Report) {
+    let f = |s: &State, t: &Token| feed.price(s, t);
+    let tip_price = feed.price(s, tip_token);
+    let mut bids: Vec<Bid> = Vec::new();
+    let mut values = Vec::new();
+    for searcher in searchers.iter_mut() {
+        let txs: Vec<Box<dyn Transition>> = searcher.agent.act(s, &[], feed, rng).into_iter().map(|(_, t)| t).collect();
+        let user = searcher.user().clone();
+        let value = simulate(s, &txs).net_wealth_user(&user, &f) - s.net_wealth_user(&user, &f);
+        values.push(value);
+        bids.push(Bid { searcher: user, tip: 0.0, txs });
+    }
+
+    let mut rounds = Vec::new();
+    for _ in 0..max_rounds {
+        let mut raised = false;
+        for i in 0..bids.len() {
+            let best_other = bids.iter().enumerate()
+                .filter(|(j, _)| *j != i)
+                .map(|(_, b)| b.tip)
+                .fold(0.0, f64::max);
+            let limit = searchers[i].max_share * values[i] / tip_price;
+            let next = best_other + searchers[i].increment;
+            // the first bid of a searcher alone is one increment
+            if bids[i].tip <= best_other && next <= limit {
+                bids[i].tip = next;
+                raised = true;
+            }
+        }
+        rounds.push(bids.iter().map(|b| (b.searcher.clone(), b.tip)).collect());
+        if !raised {
+            break;
+        }
+    }
+
+    let (post, block) = build(s, &bids, producer, tip_token, &f);
+    let outcomes = bids.iter().zip(&values)
+        .map(|(b, value)| SearcherOutcome {
+            user: b.searcher.clone(),
+            value: *value,
+            tip: b.tip,
+            included: block.iter().any(|label| label.starts_with(&format!("{}:", b.searcher))),
+            kept: post.net_wealth_user(&b.searcher, &f) - s.net_wealth_user(&b.searcher, &f),
+        })
+        .collect();
+    let producer_revenue = (post.get_balance(producer, tip_token) - s.get_balance(producer, tip_token)) * tip_price;
+    (post, Report { rounds, block, outcomes, producer_revenue })
+}
+
+// Orders the bids by decreasing tip, ties by submission, and applies each
+// one atomically with its tip. Returns the state and the included transactions.
+pub fn build(s: &State, bids: &[Bid], producer: &User, tip_token: &Token, f: &dyn Fn(&State, &Token) -> f64)
+    -> (State, Vec<String>) {
+    let mut order: Vec<&Bid> = bids.iter().filter(|b| b.tip > 0.0).collect();
+    order.sort_by(|a, b| b.tip.total_cmp(&a.tip));
+    let mut s = s.clone();
+    let mut block = Vec::new();
+    for bid in order {
+        let tip = Tip::new(&bid.searcher, producer, tip_token, bid.tip);
+        let mut post = s.clone();
+        let applied = bid.txs.iter()
+            .map(|t| t.as_ref())
+            .chain(std::iter::once(&tip as &dyn Transition))
+            .try_for_each(|t| {
+                post = t.apply(&post)?;
+                Ok::<(), TransitionError>(())
+            });
+        if applied.is_ok() && post.net_wealth_user(&bid.searcher, f) >= s.net_wealth_user(&bid.searcher, f) {
+            block.extend(bid.txs.iter().map(|t| t.to_string()));
+            block.push(tip.to_string());
+            s = post;
+        }
+    }
+    (s, block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agent::Arbitrageur;
+    use crate::{CreatePool, Curve, Deposit};
+
+    fn setup() -> (State, Token, Token, Oracle) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        for name in ["M1", "M2", "M3"] {
+            s.set_balance(&User::new(name), &t0, 100.0);
+            s.set_balance(&User::new(name), &t1, 100.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        // t0 is worth 1.2 t1 outside the pool
+        let feed = Oracle::Manual(vec![(t0.clone(), 1.2), (t1.clone(), 1.0)]);
+        (s, t0, t1, feed)
+    }
+
+    fn searcher(name: &str, t0: &Token, t1: &Token, max_share: f64) -> Searcher {
+        Searcher::new(Arbitrageur::new(&User::new(name), t0, t1), 0.01, max_share)
+    }
+
+    #[test]
+    fn tips_are_transfers() {
+        let (s, _, t1, _) = setup();
+        let (m, p) = (User::new("M1"), User::new("P"));
+        let post = Tip::new(&m, &p, &t1, 2.5).apply(&s).unwrap();
+        assert_eq!(post.get_balance(&p, &t1), 2.5);
+        assert_eq!(post.token_supply(&t1), s.token_supply(&t1));
+        assert!(matches!(Tip::new(&m, &p, &t1, 101.0).apply(&s), Err(TransitionError::InsufficientBalance)));
+    }
+
+    #[test]
+    fn competition_hands_the_mev_to_the_producer() {
+        let (s, t0, t1, feed) = setup();
+        let p = User::new("P");
+        let mut alone = vec![searcher("M1", &t0, &t1, 0.9)];
+        let (_, report) = run(&s, &mut alone, &p, &t1, &feed, &mut Rng::new(0), 1000);
+        let m1 = &report.outcomes[0];
+        assert!(m1.included && (m1.tip - 0.01).abs() < 1e-12);
+        assert!((m1.kept - (m1.value - 0.01)).abs() < 1e-9);
+
+        let mut searchers = vec![searcher("M1", &t0, &t1, 0.9), searcher("M2", &t0, &t1, 0.95), searcher("M3", &t0, &t1, 0.5)];
+        let (post, report) = run(&s, &mut searchers, &p, &t1, &feed, &mut Rng::new(0), 1000);
+        assert!(report.rounds.len() > 2);
+        let winners: Vec<&SearcherOutcome> = report.outcomes.iter().filter(|o| o.included).collect();
+        assert_eq!(winners.len(), 1);
+        let (m2, value) = (winners[0], report.outcomes[0].value);
+        // M2 outbids M1 up to its limit, and keeps little
+        assert!(m2.user == User::new("M2"));
+        assert!(m2.tip > 0.9 * value - 1e-9 && m2.tip <= 0.95 * value);
+        assert!((report.producer_revenue - m2.tip).abs() < 1e-9 && m2.kept < 0.1 * value);
+        // the others bid and arbitraged nothing
+        assert!(report.outcomes.iter().filter(|o| !o.included).all(|o| o.kept == 0.0));
+        assert_eq!(post.get_balance(&p, &t1), m2.tip);
+    }
+}
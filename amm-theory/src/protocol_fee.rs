@@ -0,0 +1,179 @@
+use std::fmt;
+
+use crate::{State, Token, Transition, TransitionError, User, AMM};
+
+// How the protocol collects its share of the swap fees
+#[derive(Clone, PartialEq)]
+pub enum FeeMode {
+    // the fees accrue in the reserves, and the treasury is minted LP tokens
+    // for its share of the growth of sqrt(k) at the next deposit or redeem
+    // (Uniswap v2)
+    Minted,
+    // the treasury receives its share of the input token at every swap
+    Tokens,
+}
+
+#[derive(Clone)]
+pub struct ProtocolFee {
+    // fraction of the swap fee that goes to the treasury
+    pub fraction: f64,
+    pub treasury: User,
+    pub mode: FeeMode,
+    // r0*r1 after the last deposit or redeem
+    pub k_last: f64,
+}
+
+impl AMM {
+    // LP tokens owed to the treasury for the growth of sqrt(k) since the
+    // last liquidity event, out of a supply of lp_supply
+    fn accrued_protocol_fee(&self, lp_supply: f64) -> f64 {
+        match &self.protocol_fee {
+            Some(pf) if pf.mode == FeeMode::Minted && pf.fraction > 0.0 && pf.k_last > 0.0 => {
+                let root_k = (self.r0 * self.r1).sqrt();
+                let root_k_last = pf.k_last.sqrt();
+                if root_k <= root_k_last {
+                    return 0.0;
+                }
+                lp_supply * (root_k - root_k_last) / ((1.0 / pf.fraction - 1.0) * root_k + root_k_last)
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+impl State {
+    // Mints to the treasury the protocol fees accrued in the reserves.
+    // Called before every change of the liquidity of the pool.
+    pub fn mint_protocol_fee(&mut self, t0: &Token, t1: &Token) {
+        let amm = match self.get_amm(t0, t1) {
+            Some(amm) => amm,
+            None => return,
+        };
+        let lp_token = Token::mint(t0, t1);
+        let minted = amm.accrued_protocol_fee(self.token_supply(&lp_token));
+        if minted > 0.0 {
+            let treasury = amm.protocol_fee.as_ref().unwrap().treasury.clone();
+            let balance = self.get_balance(&treasury, &lp_token);
+            self.set_balance(&treasury, &lp_token, balance + minted);
+        }
+    }
+
+    // Records k after a change of the liquidity of the pool
+    pub fn update_k_last(&mut self, t0: &Token, t1: &Token) {
+        if let Some(amm) = self.get_amm_mut(t0, t1) {
+            let k = amm.r0 * amm.r1;
+            if let Some(pf) = amm.protocol_fee.as_mut() {
+                pf.k_last = k;
+            }
+        }
+    }
+
+    // The part of the input x of a swap that goes straight to the treasury
+    pub fn protocol_fee_cut(&self, tin: &Token, tout: &Token, x: f64) -> Option<(User, f64)> {
+        let amm = self.get_amm(tin, tout)?;
+        match &amm.protocol_fee {
+            Some(pf) if pf.mode == FeeMode::Tokens => Some((pf.treasury.clone(), x * amm.fee * pf.fraction)),
+            _ => None,
+        }
+    }
+}
+
+// Governance decision switching the protocol fee of a pool on (or off, with
+// a zero fraction), or changing its treasury. Fees accrued under the previous
+// setting are minted to the previous treasury first.
+pub struct SetProtocolFee {
+    t0: Token,
+    t1: Token,
+    fraction: f64,
+    treasury: User,
+    mode: FeeMode,
+}
+
+impl SetProtocolFee {
+    pub fn new(t0: &Token, t1: &Token, fraction: f64, treasury: &User, mode: FeeMode) -> Self {
+        assert!((0.0..=1.0).contains(&fraction));
+        SetProtocolFee { t0: t0.clone(), t1: t1.clone(), fraction, treasury: treasury.clone(), mode }
+    }
+}
+
+impl fmt::Display for SetProtocolFee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode {
+            FeeMode::Minted => "minted",
+            FeeMode::Tokens => "tokens",
+        };
+        write!(f, "gov:fee({},{},{},{},{})", self.t0, self.t1, self.fraction, self.treasury, mode)
+    }
+}
+
+impl Transition for SetProtocolFee {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        if post.get_amm(&self.t0, &self.t1).is_none() {
+            return Err(TransitionError::UnknownPool);
+        }
+        post.mint_protocol_fee(&self.t0, &self.t1);
+        let amm = post.get_amm_mut(&self.t0, &self.t1).unwrap();
+        amm.protocol_fee = if self.fraction > 0.0 {
+            Some(ProtocolFee {
+                fraction: self.fraction,
+                treasury: self.treasury.clone(),
+                mode: self.mode.clone(),
+                k_last: amm.r0 * amm.r1,
+            })
+        } else {
+            None
+        };
+        Ok(post)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_oracle, CreatePool, Curve, Deposit, Redeem, Swap};
+
+    fn setup(mode: FeeMode) -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let b = User::new("B");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        s.set_balance(&b, &t0, 100.0);
+        s.set_balance(&b, &t1, 100.0);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        let s = SetProtocolFee::new(&t0, &t1, 1.0 / 6.0, &User::new("T"), mode).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    #[test]
+    fn token_mode_pays_the_treasury_at_every_swap() {
+        let (s, t0, t1) = setup(FeeMode::Tokens);
+        let s = Swap::new(&User::new("B"), &t0, &t1, 60.0).apply(&s).unwrap();
+        assert!((s.get_balance(&User::new("T"), &t0) - 60.0 * 0.003 / 6.0).abs() < 1e-12);
+        assert!((s.token_supply(&t0) - 200.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn minted_mode_pays_the_treasury_at_the_next_liquidity_event() {
+        let (s, t0, t1) = setup(FeeMode::Minted);
+        let b = User::new("B");
+        let s = Swap::new(&b, &t0, &t1, 60.0).apply(&s).unwrap();
+        let s = Swap::new(&b, &t1, &t0, 30.0).apply(&s).unwrap();
+        let lp_token = Token::mint(&t0, &t1);
+        assert_eq!(s.get_balance(&User::new("T"), &lp_token), 0.0);
+
+        let wealth = s.net_wealth(&price_oracle);
+        let pool = (s.get_reserves(&t0, &t1) + s.get_reserves(&t1, &t0)) * 1000.0;
+        let root_k = (s.get_reserves(&t0, &t1) * s.get_reserves(&t1, &t0)).sqrt();
+        let s = Redeem::new(&User::new("O"), &t0, &t1, 100.0).apply(&s).unwrap();
+        // the treasury owns 1/6 of the growth of sqrt(k)
+        let treasury = s.net_wealth_user(&User::new("T"), &price_oracle);
+        assert!(treasury > 0.0);
+        assert!((treasury - pool * (root_k - 100.0) / root_k / 6.0).abs() < 1e-6);
+        assert!((s.net_wealth(&price_oracle) - wealth).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{twamm, Curve, FeeMode, State, Token, AMM};
+
+// Read-only quotes. Prices are marginal prices of a token in units of the
+// other token of the pool, excluding the fee.
+impl AMM {
+    fn other(&self, t: &Token) -> &Token {
+        if *t == self.t0 { &self.t1 } else { &self.t0 }
+    }
+
+    // Fraction of the input of a swap that stays in the reserves: the
+    // protocol may take its share of the fee out of the pool
+    fn retained(&self) -> f64 {
+        match &self.protocol_fee {
+            Some(pf) if pf.mode == FeeMode::Tokens => 1.0 - self.fee * pf.fraction,
+            _ => 1.0,
+        }
+    }
+
+    // None for an empty pool
+    pub fn spot_price(&self, t: &Token) -> Option<f64> {
+        if self.r0 <= 0.0 || self.r1 <= 0.0 {
+            return None;
+        }
+        Some(match self.curve {
+            Curve::ConstantProduct => self.get_reserves(self.other(t)) / self.get_reserves(t),
+            Curve::ConstantSum => 1.0,
+        })
+    }
+
+    // Input of tin needed to get y units of the other token, if the
+    // reserves allow it
+    pub fn amount_in(&self, tin: &Token, y: f64) -> Option<f64> {
+        let r_in = self.get_reserves(tin);
+        let r_out = self.get_reserves(self.other(tin));
+        if y >= r_out {
+            return None;
+        }
+        let x = match self.curve {
+            Curve::ConstantProduct => y * r_in / (r_out - y),
+            Curve::ConstantSum => y,
+        };
+        Some(x / (1.0 - self.fee))
+    }
+
+    // Reserves of tin and of the other token after a swap of x units of tin
+    fn reserves_after(&self, tin: &Token, x: f64) -> (f64, f64) {
+        let out = self.amount_out(tin, x);
+        (self.get_reserves(tin) + x * self.retained(), self.get_reserves(self.other(tin)) - out)
+    }
+
+    // Spot price of tin after a swap of x units of tin
+    pub fn price_after(&self, tin: &Token, x: f64) -> f64 {
+        let (r_in, r_out) = self.reserves_after(tin, x);
+        match self.curve {
+            Curve::ConstantProduct => r_out / r_in,
+            Curve::ConstantSum => 1.0,
+        }
+    }
+
+    // Percentage by which the execution price of a swap of x units of tin,
+    // fee included, falls short of the spot price. None for an empty pool or
+    // an empty swap.
+    pub fn price_impact(&self, tin: &Token, x: f64) -> Option<f64> {
+        if x <= 0.0 {
+            return None;
+        }
+        Some((1.0 - self.amount_out(tin, x) / x / self.spot_price(tin)?) * 100.0)
+    }
+
+    // The token to sell, and how much of it, to bring the spot price of t to
+    // target. With no fee, selling t0 gives price_mini_transaction.
+    pub fn amount_to_price(&self, t: &Token, target: f64) -> Option<(Token, f64)> {
+        if self.curve != Curve::ConstantProduct || target <= 0.0 {
+            return None;
+        }
+        let spot = self.spot_price(t)?;
+        let (tin, p) = if spot >= target { (t, target) } else { (self.other(t), 1.0 / target) };
+        let (r_in, r_out) = (self.get_reserves(tin), self.get_reserves(self.other(tin)));
+        // the price of tin after selling x is r_in*r_out/((r_in+g*x)*(r_in+c*x)),
+        // with g the share of x moving along the curve and c the share retained
+        let g = 1.0 - self.fee;
+        let c = self.retained();
+        let a = g * c;
+        let b = r_in * (g + c);
+        let k = r_in * r_in - r_in * r_out / p;
+        let x = (-b + (b * b - 4.0 * a * k).sqrt()) / (2.0 * a);
+        Some((tin.clone(), x.max(0.0)))
+    }
+}
+
+impl State {
+    // Quotes see the pool with the virtual orders executed up to the current
+    // block, as the next transaction on it would
+    fn quoted_amm(&self, t: &Token, other: &Token) -> Option<AMM> {
+        match twamm::settled_view(self) {
+            Some(settled) => settled.get_amm(t, other).cloned(),
+            None => self.get_amm(t, other).cloned(),
+        }
+    }
+
+    pub fn spot_price(&self, t: &Token, other: &Token) -> Option<f64> {
+        self.quoted_amm(t, other)?.spot_price(t)
+    }
+
+    pub fn quote_out(&self, tin: &Token, tout: &Token, x: f64) -> Option<f64> {
+        Some(self.quoted_amm(tin, tout)?.amount_out(tin, x))
+    }
+
+    pub fn quote_in(&self, tin: &Token, tout: &Token, y: f64) -> Option<f64> {
+        self.quoted_amm(tin, tout)?.amount_in(tin, y)
+    }
+
+    pub fn price_after(&self, tin: &Token, tout: &Token, x: f64) -> Option<f64> {
+        Some(self.quoted_amm(tin, tout)?.price_after(tin, x))
+    }
+
+    pub fn price_impact(&self, tin: &Token, tout: &Token, x: f64) -> Option<f64> {
+        self.quoted_amm(tin, tout)?.price_impact(tin, x)
+    }
+
+    pub fn amount_to_price(&self, t: &Token, other: &Token, target: f64) -> Option<(Token, f64)> {
+        self.quoted_amm(t, other)?.amount_to_price(t, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol_fee::SetProtocolFee;
+    use crate::{price_mini_transaction, CreatePool, Deposit, Swap, Transition, User};
+
+    fn setup(fee: f64) -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 200.0);
+        s.set_balance(&User::new("B"), &t0, 100.0);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, fee).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 200.0, &t1).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    #[test]
+    fn quotes_agree_with_swaps() {
+        let (s, t0, t1) = setup(0.003);
+        let b = User::new("B");
+        assert_eq!(s.spot_price(&t0, &t1), Some(2.0));
+        let out = s.quote_out(&t0, &t1, 25.0).unwrap();
+        assert!((s.quote_in(&t0, &t1, out).unwrap() - 25.0).abs() < 1e-9);
+        assert!(s.quote_in(&t0, &t1, 200.0).is_none());
+        let impact = s.price_impact(&t0, &t1, 25.0).unwrap();
+        assert!(impact > 0.3 && (impact - (1.0 - out / 25.0 / 2.0) * 100.0).abs() < 1e-9);
+
+        let after = s.price_after(&t0, &t1, 25.0).unwrap();
+        let post = Swap::new(&b, &t0, &t1, 25.0).apply(&s).unwrap();
+        assert!((post.get_balance(&b, &t1) - out).abs() < 1e-9);
+        assert!((post.spot_price(&t0, &t1).unwrap() - after).abs() < 1e-9);
+        // quoting never changes the state
+        assert_eq!(s.get_reserves(&t0, &t1), 100.0);
+
+        // no price without reserves, no impact without a swap
+        assert_eq!(s.price_impact(&t0, &t1, 0.0), None);
+        let empty = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&State::new()).unwrap();
+        assert_eq!(empty.spot_price(&t0, &t1), None);
+        assert_eq!(empty.price_impact(&t0, &t1, 1.0), None);
+    }
+
+    #[test]
+    fn amount_to_price_reaches_the_target() {
+        let (s, t0, t1) = setup(0.0);
+        let (tin, x) = s.amount_to_price(&t0, &t1, 1.0).unwrap();
+        assert!(tin == t0 && (x - price_mini_transaction(1.0, 1.0, 100.0, 200.0)).abs() < 1e-9);
+
+        let (s, t0, t1) = setup(0.003);
+        let s = SetProtocolFee::new(&t0, &t1, 1.0 / 6.0, &User::new("T"), FeeMode::Tokens).apply(&s).unwrap();
+        for target in [1.0, 3.0] {
+            let (tin, x) = s.amount_to_price(&t0, &t1, target).unwrap();
+            let tout = if tin == t0 { &t1 } else { &t0 };
+            let mut s = s.clone();
+            s.set_balance(&User::new("B"), &tin, x);
+            let post = Swap::new(&User::new("B"), &tin, tout, x).apply(&s).unwrap();
+            assert!((post.spot_price(&t0, &t1).unwrap() - target).abs() < 1e-9);
+        }
+    }
+}
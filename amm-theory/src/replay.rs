@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use crate::{AdvanceBlock, Deposit, Redeem, State, Swap, Token, Transition, TransitionError, User};
+
+// Replay of historical pool events, e.g. the Mint, Burn and Swap logs of a
+// pair exported to CSV or JSON. Every event has its block, its sender, the
+// amounts of t0 and t1 it moved in (positive) or out (negative) of the pool
+// and the reserves of the pool after it, as logged. The events are turned into
+// Deposit, Redeem and Swap and replayed on the state, and the reserves of the
+// model are compared with the logged ones.
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum LogError {
+    Syntax(String),
+    Missing(String),
+    Invalid(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum EventKind {
+    Mint,
+    Burn,
+    Swap,
+}
+
+#[derive(Clone)]
+pub struct Event {
+    pub block: u64,
+    pub kind: EventKind,
+    pub sender: User,
+    pub amount0: f64,
+    pub amount1: f64,
+    pub reserve0: f64,
+    pub reserve1: f64,
+}
+
+const FIELDS: [&str; 7] = ["block", "kind", "sender", "amount0", "amount1", "reserve0", "reserve1"];
+
+// An event from its fields by name, checking that its amounts go the way of
+// its kind: both in for a mint, both out for a burn, one in and one out for a
+// swap
+fn event(record: &HashMap<String, String>) -> Result<Event, LogError> {
+    let field = |name: &str| record.get(name).map(|v| v.trim()).ok_or(LogError::Missing(String::from(name)));
+    let number = |name: &str| -> Result<f64, LogError> {
+        let v = field(name)?;
+        v.parse().map_err(|_| LogError::Syntax(format!("expected a number for {}: {}", name, v)))
+    };
+    let block = field("block")?;
+    let block = block.parse().map_err(|_| LogError::Syntax(format!("expected a block number: {}", block)))?;
+    let kind = match field("kind")?.to_lowercase().as_str() {
+        "mint" => EventKind::Mint,
+        "burn" => EventKind::Burn,
+        "swap" => EventKind::Swap,
+        other => return Err(LogError::Invalid(format!("unknown event {}", other))),
+    };
+    let sender = field("sender")?;
+    if sender.is_empty() {
+        return Err(LogError::Missing(String::from("sender")));
+    }
+    let e = Event {
+        block,
+        kind,
+        sender: User::new(sender),
+        amount0: number("amount0")?,
+        amount1: number("amount1")?,
+        reserve0: number("reserve0")?,
+        reserve1: number("reserve1")?,
+    };
+    let valid = match kind {
+        EventKind::Mint => e.amount0 > 0.0 && e.amount1 > 0.0,
+        EventKind::Burn => e.amount0 < 0.0 && e.amount1 < 0.0,
+        EventKind::Swap => e.amount0 > 0.0 && e.amount1 < 0.0 || e.amount0 < 0.0 && e.amount1 > 0.0,
+    };
+    if !valid {
+        return Err(LogError::Invalid(format!("amounts {} and {} of a {:?} in block {}", e.amount0, e.amount1, kind, block)));
+    }
+    Ok(e)
+}
+
+// A CSV log with a header naming the fields, in any order, e.g.
+// `block,kind,sender,amount0,amount1,reserve0,reserve1`
+pub fn parse_csv(text: &str) -> Result<Vec<Event>, LogError> {
+    let mut lines = text.lines().filter(|l| !l.trim().is_empty());
+    let header: Vec<String> = match lines.next() {
+        Some(h) => h.split(',').map(|f| f.trim().to_lowercase()).collect(),
+        None => return Ok(Vec::new()),
+    };
+    if let Some(f) = FIELDS.iter().find(|f| !header.iter().any(|h| h == *f)) {
+        return Err(LogError::Missing(f.to_string()));
+    }
+    lines.map(|line| {
+        let values: Vec<&str> = line.split(',').collect();
+        if values.len() != header.len() {
+            return Err(LogError::Syntax(format!("expected {} fields: {}", header.len(), line)));
+        }
+        event(&header.iter().cloned().zip(values.iter().map(|v| v.trim().to_string())).collect())
+    }).collect()
+}
+
+struct Json<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Json<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), LogError> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(d) if d == c => Ok(()),
+            d => Err(LogError::Syntax(format!("expected {} in JSON, found {:?}", c, d))),
+        }
+    }
+
+    // Whether the next character is c, consuming it if so
+    fn next_is(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        self.chars.next_if_eq(&c).is_some()
+    }
+
+    fn string(&mut self) -> Result<String, LogError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some(c @ ('"' | '\\' | '/')) => s.push(c),
+                    c => return Err(LogError::Syntax(format!("unsupported escape in JSON: {:?}", c))),
+                },
+                Some(c) => s.push(c),
+                None => return Err(LogError::Syntax(String::from("unterminated string in JSON"))),
+            }
+        }
+    }
+
+    // A string, or a number or literal as written
+    fn value(&mut self) -> Result<String, LogError> {
+        self.skip_whitespace();
+        if self.chars.peek() == Some(&'"') {
+            return self.string();
+        }
+        let mut v = String::new();
+        while let Some(c) = self.chars.next_if(|c| !matches!(c, ',' | '}' | ']') && !c.is_whitespace()) {
+            if matches!(c, '{' | '[') {
+                return Err(LogError::Syntax(String::from("expected flat objects in JSON")));
+            }
+            v.push(c);
+        }
+        if v.is_empty() {
+            return Err(LogError::Syntax(String::from("expected a value in JSON")));
+        }
+        Ok(v)
+    }
+
+    fn object(&mut self) -> Result<HashMap<String, String>, LogError> {
+        self.expect('{')?;
+        let mut record = HashMap::new();
+        if self.next_is('}') {
+            return Ok(record);
+        }
+        loop {
+            let key = self.string()?;
+            self.expect(':')?;
+            record.insert(key.to_lowercase(), self.value()?);
+            if !self.next_is(',') {
+                self.expect('}')?;
+                return Ok(record);
+            }
+        }
+    }
+}
+
+// A JSON log: an array of objects with the fields of the events, numbers
+// possibly quoted, e.g. `[{"block": 1, "kind": "swap", ...}]`
+pub fn parse_json(text: &str) -> Result<Vec<Event>, LogError> {
+    let mut json = Json { chars: text.chars().peekable() };
+    json.expect('[')?;
+    let mut events = Vec::new();
+    if !json.next_is(']') {
+        loop {
+            events.push(event(&json.object()?)?);
+            if !json.next_is(',') {
+                json.expect(']')?;
+                break;
+            }
+        }
+    }
+    json.skip_whitespace();
+    if json.chars.peek().is_some() {
+        return Err(LogError::Syntax(String::from("trailing characters after JSON")));
+    }
+    Ok(events)
+}
+
+// The transition of the model corresponding to an event on the pool of t0 and
+// t1 in state s. A burn redeems the share of the LP supply that yields the
+// logged amount of t0 from the reserves of the model.
+pub fn transition(s: &State, e: &Event, t0: &Token, t1: &Token) -> Result<Box<dyn Transition>, TransitionError> {
+    Ok(match e.kind {
+        EventKind::Mint => Box::new(Deposit::new(&e.sender, e.amount0, t0, e.amount1, t1)),
+        EventKind::Burn => {
+            let r0 = s.get_reserves(t0, t1);
+            if r0 < -e.amount0 {
+                return Err(TransitionError::InsufficientReserves);
+            }
+            let v = s.token_supply(&Token::mint(t0, t1)) * -e.amount0 / r0;
+            Box::new(Redeem::new(&e.sender, t0, t1, v))
+        }
+        EventKind::Swap if e.amount0 > 0.0 => Box::new(Swap::new(&e.sender, t0, t1, e.amount0)),
+        EventKind::Swap => Box::new(Swap::new(&e.sender, t1, t0, e.amount1)),
+    })
+}
+
+pub struct Replayed {
+    pub block: u64,
+    // the transition of the event, if it could be built
+    pub label: String,
+    pub error: Option<TransitionError>,
+    // reserves of t0 and t1 after the event
+    pub simulated: (f64, f64),
+    pub logged: (f64, f64),
+}
+
+impl Replayed {
+    // Largest relative difference between simulated and logged reserves
+    pub fn divergence(&self) -> f64 {
+        let rel = |sim: f64, log: f64| if log == 0.0 { sim.abs() } else { ((sim - log) / log).abs() };
+        rel(self.simulated.0, self.logged.0).max(rel(self.simulated.1, self.logged.1))
+    }
+}
+
+pub struct Report {
+    pub steps: Vec<Replayed>,
+    // indices of the events after which the reserves diverge from the log
+    pub divergent: Vec<usize>,
+}
+
+// Replays the events on the pool of t0 and t1, advancing the state to the
+// block of each of them. Senders are credited beforehand with the tokens they
+// put in, so that only the pool is calibrated: their wallets do not match the
+// chain, and LP tokens transferred outside of the log may leave negative LP
+// balances. An event that fails leaves the state unchanged.
+pub fn replay(s0: &State, t0: &Token, t1: &Token, events: &[Event], tolerance: f64) -> (State, Report) {
+    let mut s = s0.clone();
+    let mut steps = Vec::new();
+    let mut divergent = Vec::new();
+    for (i, e) in events.iter().enumerate() {
+        if e.block > s.block {
+            s = AdvanceBlock::new(e.block - s.block).apply(&s).unwrap();
+        }
+        let credit = |s: &mut State, t: &Token, v: f64| {
+            let balance = s.get_balance(&e.sender, t);
+            if v > balance {
+                s.set_balance(&e.sender, t, v);
+            }
+        };
+        let mut funded = s.clone();
+        credit(&mut funded, t0, e.amount0);
+        credit(&mut funded, t1, e.amount1);
+        let result = transition(&funded, e, t0, t1).and_then(|t| Ok((t.to_string(), t.apply(&funded)?)));
+        let (label, error) = match result {
+            Ok((label, post)) => {
+                s = post;
+                (label, None)
+            }
+            Err(err) => (format!("{:?}", e.kind).to_lowercase(), Some(err)),
+        };
+        let step = Replayed {
+            block: e.block,
+            label,
+            error,
+            simulated: (s.get_reserves(t0, t1), s.get_reserves(t1, t0)),
+            logged: (e.reserve0, e.reserve1),
+        };
+        if step.divergence() > tolerance {
+            divergent.push(i);
+        }
+        steps.push(step);
+    }
+    (s, Report { steps, divergent })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CreatePool, Curve};
+
+    fn pool() -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&State::new()).unwrap();
+        (s, t0, t1)
+    }
+
+    // Mint of 100 t0 and 400 t1, swap of 10 t0 and burn of half of the LP
+    // supply, with the reserves of a 0.3% constant product pool
+    const CSV: &str = "block,kind,sender,amount0,amount1,reserve0,reserve1
+10,mint,0xaa,100,400,100,400
+12,swap,0xbb,10,-36.2644,110,363.7356
+12,burn,0xaa,-55,-181.8678,55,181.8678
+";
+
+    #[test]
+    fn csv_and_json_logs() {
+        let events = parse_csv(CSV).unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[1].kind, EventKind::Swap);
+        assert!(events[1].sender == User::new("0xbb"));
+        let json = r#"[
+            {"block": 10, "kind": "mint", "sender": "0xaa", "amount0": 100, "amount1": "400", "reserve0": 100, "reserve1": 400},
+            {"kind": "Swap", "block": "12", "sender": "0xbb", "amount0": 10, "amount1": -36.2644, "reserve0": 110, "reserve1": 363.7356}
+        ]"#;
+        let from_json = parse_json(json).unwrap();
+        assert_eq!(from_json.len(), 2);
+        assert_eq!(from_json[1].block, 12);
+        assert_eq!(from_json[0].amount1, 400.0);
+        assert!(matches!(parse_json("[]"), Ok(v) if v.is_empty()));
+        assert!(matches!(parse_json(r#"[{"block": 1}]"#), Err(LogError::Missing(_))));
+        assert!(matches!(parse_json(r#"[{"block": [1]}]"#), Err(LogError::Syntax(_))));
+        assert!(matches!(parse_csv("block,kind\n1,mint"), Err(LogError::Missing(_))));
+        assert!(matches!(parse_csv(&CSV.replace("10,-36", "10,36")), Err(LogError::Invalid(_))));
+    }
+
+    #[test]
+    fn replayed_reserves_match_the_log() {
+        let (s0, t0, t1) = pool();
+        let events = parse_csv(CSV).unwrap();
+        let (s, report) = replay(&s0, &t0, &t1, &events, 1e-5);
+        assert!(report.steps.iter().all(|r| r.error.is_none()));
+        assert!(report.divergent.is_empty());
+        assert_eq!(report.steps[1].label, "0xbb:swap(t0,t1,10)");
+        assert_eq!(s.block, 12);
+        assert!((s.get_reserves(&t0, &t1) - 55.0).abs() < 1e-9);
+        // the swapper was credited with its input only
+        assert_eq!(s.get_balance(&User::new("0xbb"), &t0), 0.0);
+    }
+
+    #[test]
+    fn divergences_are_flagged() {
+        let (s0, t0, t1) = pool();
+        // a swap returning more than the curve allows, e.g. with a lower fee
+        let mut events = parse_csv(CSV).unwrap();
+        events[1].amount1 = -36.3636;
+        events[1].reserve1 = 363.6364;
+        let (_, report) = replay(&s0, &t0, &t1, &events, 1e-5);
+        // the burn takes half of the pool either way
+        assert_eq!(report.divergent, vec![1]);
+        assert!(report.steps[1].divergence() > 1e-4);
+        // a burn larger than the pool fails and leaves it as it was
+        events[2].amount0 = -500.0;
+        let (_, report) = replay(&s0, &t0, &t1, &events, 1e-5);
+        assert!(matches!(report.steps[2].error, Some(TransitionError::InsufficientReserves)));
+        assert_eq!(report.steps[2].simulated, report.steps[1].simulated);
+    }
+}
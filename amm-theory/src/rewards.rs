@@ -0,0 +1,364 @@
+use std::fmt;
+
+use crate::{State, Token, Transition, TransitionError, User};
+
+// Liquidity mining. A farm emits `rate` reward tokens per block to the users
+// staking the LP token of a pool, in proportion to their stake over time.
+// As in staking contracts, the farm keeps the rewards accumulated per staked
+// token since its start, and a stake remembers the accumulator when it last
+// changed: the rewards of a stake are its amount times the growth since then.
+#[derive(Clone)]
+pub struct Farm {
+    pub lp_token: Token,
+    pub reward: Token,
+    pub rate: f64,
+    pub staked: f64,
+    pub acc_per_share: f64,
+    pub last_block: u64,
+}
+
+impl Farm {
+    fn new(lp_token: &Token, reward: &Token, rate: f64, block: u64) -> Self {
+        assert!(rate >= 0.0);
+        Farm {
+            lp_token: lp_token.clone(),
+            reward: reward.clone(),
+            rate,
+            staked: 0.0,
+            acc_per_share: 0.0,
+            last_block: block,
+        }
+    }
+
+    // Nothing is emitted while nothing is staked
+    pub fn acc_per_share_at(&self, block: u64) -> f64 {
+        if self.staked == 0.0 {
+            return self.acc_per_share;
+        }
+        let elapsed = block.saturating_sub(self.last_block);
+        self.acc_per_share + self.rate * elapsed as f64 / self.staked
+    }
+
+    fn accrue(&mut self, block: u64) {
+        self.acc_per_share = self.acc_per_share_at(block);
+        self.last_block = block;
+    }
+}
+
+impl fmt::Display for Farm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = f.precision().unwrap_or(1);
+        write!(f, "F{{{:.*}:{} {:.*}:{}/block}}", p, self.staked, self.lp_token, p, self.rate, self.reward)
+    }
+}
+
+#[derive(Clone)]
+pub struct StakePosition {
+    pub user: User,
+    pub lp_token: Token,
+    pub amount: f64,
+    // the accumulator of the farm when the stake last changed
+    pub acc_checkpoint: f64,
+    // rewards accrued before that, and not claimed yet
+    pub unclaimed: f64,
+}
+
+#[derive(Clone)]
+pub struct Rewards {
+    pub farms: Vec<Farm>,
+    pub stakes: Vec<StakePosition>,
+}
+
+impl Rewards {
+    pub fn new() -> Self {
+        Rewards { farms: Vec::new(), stakes: Vec::new() }
+    }
+
+    // Starts emitting at the given block
+    pub fn add_farm(&mut self, lp_token: &Token, reward: &Token, rate: f64, block: u64) {
+        assert!(matches!(lp_token, Token::Minted(_, _)) && matches!(reward, Token::Atomic(_)));
+        assert!(self.get_farm(lp_token).is_none());
+        self.farms.push(Farm::new(lp_token, reward, rate, block));
+    }
+
+    pub fn get_farm(&self, lp_token: &Token) -> Option<&Farm> {
+        self.farms.iter().find(|f| f.lp_token == *lp_token)
+    }
+
+    fn get_farm_mut(&mut self, lp_token: &Token) -> Option<&mut Farm> {
+        self.farms.iter_mut().find(|f| f.lp_token == *lp_token)
+    }
+
+    pub fn get_staked(&self, lp_token: &Token) -> f64 {
+        self.get_farm(lp_token).map_or(0.0, |f| f.staked)
+    }
+
+    fn get_stake(&self, user: &User, lp_token: &Token) -> Option<&StakePosition> {
+        self.stakes.iter().find(|p| p.user == *user && p.lp_token == *lp_token)
+    }
+
+    fn get_stake_mut(&mut self, user: &User, lp_token: &Token) -> &mut StakePosition {
+        let i = match self.stakes.iter().position(|p| p.user == *user && p.lp_token == *lp_token) {
+            Some(i) => i,
+            None => {
+                self.stakes.push(StakePosition {
+                    user: user.clone(),
+                    lp_token: lp_token.clone(),
+                    amount: 0.0,
+                    acc_checkpoint: 0.0,
+                    unclaimed: 0.0,
+                });
+                self.stakes.len() - 1
+            }
+        };
+        &mut self.stakes[i]
+    }
+
+    pub fn get_stake_amount(&self, user: &User, lp_token: &Token) -> f64 {
+        self.get_stake(user, lp_token).map_or(0.0, |p| p.amount)
+    }
+
+    // Rewards a user can claim at the given block
+    pub fn get_pending(&self, user: &User, lp_token: &Token, block: u64) -> f64 {
+        match (self.get_stake(user, lp_token), self.get_farm(lp_token)) {
+            (Some(p), Some(f)) => p.unclaimed + p.amount * (f.acc_per_share_at(block) - p.acc_checkpoint),
+            _ => 0.0,
+        }
+    }
+
+    // Accrues the farm and moves the rewards of the stake to unclaimed,
+    // before the stake changes
+    fn settle(&mut self, user: &User, lp_token: &Token, block: u64) -> Result<(), TransitionError> {
+        let farm = self.get_farm_mut(lp_token).ok_or(TransitionError::UnknownFarm)?;
+        farm.accrue(block);
+        let acc = farm.acc_per_share;
+        let stake = self.get_stake_mut(user, lp_token);
+        stake.unclaimed += stake.amount * (acc - stake.acc_checkpoint);
+        stake.acc_checkpoint = acc;
+        Ok(())
+    }
+}
+
+impl Default for Rewards {
+    fn default() -> Self {
+        Rewards::new()
+    }
+}
+
+impl State {
+    // Staked LP tokens and pending rewards, valued with the given price function
+    pub fn stake_value(&self, user: &User, f: &dyn Fn(&State, &Token) -> f64) -> f64 {
+        let r = &self.rewards;
+        r.stakes.iter().filter(|p| p.user == *user)
+            .map(|p| {
+                let reward = r.get_farm(&p.lp_token).map_or(0.0, |farm| f(self, &farm.reward));
+                p.amount * f(self, &p.lp_token) + r.get_pending(user, &p.lp_token, self.block) * reward
+            })
+            .sum()
+    }
+}
+
+// Stakes v LP tokens of the pool of t0 and t1 in its farm
+pub struct Stake {
+    sender: User,
+    lp_token: Token,
+    v: f64,
+}
+
+impl Stake {
+    pub fn new(sender: &User, t0: &Token, t1: &Token, v: f64) -> Self {
+        assert!(v > 0.0);
+        Stake { sender: sender.clone(), lp_token: Token::mint(t0, t1), v }
+    }
+}
+
+impl fmt::Display for Stake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:stake({}:{})", self.sender, self.v, self.lp_token)
+    }
+}
+
+impl Transition for Stake {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        let balance = post.get_balance(&self.sender, &self.lp_token);
+        if balance < self.v {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        post.rewards.settle(&self.sender, &self.lp_token, pre.block)?;
+        post.rewards.get_farm_mut(&self.lp_token).unwrap().staked += self.v;
+        post.rewards.get_stake_mut(&self.sender, &self.lp_token).amount += self.v;
+        post.set_balance(&self.sender, &self.lp_token, balance - self.v);
+        Ok(post)
+    }
+}
+
+// Takes back v staked LP tokens; their rewards stay to be claimed
+pub struct Unstake {
+    sender: User,
+    lp_token: Token,
+    v: f64,
+}
+
+impl Unstake {
+    pub fn new(sender: &User, t0: &Token, t1: &Token, v: f64) -> Self {
+        assert!(v > 0.0);
+        Unstake { sender: sender.clone(), lp_token: Token::mint(t0, t1), v }
+    }
+}
+
+impl fmt::Display for Unstake {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:unstake({}:{})", self.sender, self.v, self.lp_token)
+    }
+}
+
+impl Transition for Unstake {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        if post.rewards.get_stake_amount(&self.sender, &self.lp_token) < self.v {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        post.rewards.settle(&self.sender, &self.lp_token, pre.block)?;
+        post.rewards.get_farm_mut(&self.lp_token).unwrap().staked -= self.v;
+        post.rewards.get_stake_mut(&self.sender, &self.lp_token).amount -= self.v;
+        let balance = post.get_balance(&self.sender, &self.lp_token);
+        post.set_balance(&self.sender, &self.lp_token, balance + self.v);
+        Ok(post)
+    }
+}
+
+// Mints to the sender all its pending rewards from the farm of the pool of
+// t0 and t1
+pub struct Claim {
+    sender: User,
+    lp_token: Token,
+}
+
+impl Claim {
+    pub fn new(sender: &User, t0: &Token, t1: &Token) -> Self {
+        Claim { sender: sender.clone(), lp_token: Token::mint(t0, t1) }
+    }
+}
+
+impl fmt::Display for Claim {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:claim({})", self.sender, self.lp_token)
+    }
+}
+
+impl Transition for Claim {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        pre.rewards.get_farm(&self.lp_token).ok_or(TransitionError::UnknownFarm)?;
+        // without a stake there is nothing to claim, and no position to open
+        if pre.rewards.get_stake(&self.sender, &self.lp_token).is_none() {
+            return Ok(pre.clone());
+        }
+        let mut post = pre.clone();
+        post.rewards.settle(&self.sender, &self.lp_token, pre.block)?;
+        let stake = post.rewards.get_stake_mut(&self.sender, &self.lp_token);
+        let claimed = stake.unclaimed;
+        stake.unclaimed = 0.0;
+        let reward = post.rewards.get_farm(&self.lp_token).unwrap().reward.clone();
+        let balance = post.get_balance(&self.sender, &reward);
+        post.set_balance(&self.sender, &reward, balance + claimed);
+        Ok(post)
+    }
+}
+
+// What a naive program emitting `rate` tokens per block would pay instead,
+// splitting all the rewards of the elapsed blocks by the stakes held at the
+// snapshot block: the stake of a sniper counts as if held all along
+pub fn snapshot_rewards(s: &State, lp_token: &Token, from_block: u64) -> Vec<(User, f64)> {
+    let farm = match s.rewards.get_farm(lp_token) {
+        Some(farm) if farm.staked > 0.0 => farm,
+        _ => return Vec::new(),
+    };
+    let emitted = farm.rate * s.block.saturating_sub(from_block) as f64;
+    s.rewards.stakes.iter()
+        .filter(|p| p.lp_token == *lp_token && p.amount > 0.0)
+        .map(|p| (p.user.clone(), emitted * p.amount / farm.staked))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AdvanceBlock, CreatePool, Curve, Deposit};
+
+    fn setup() -> (State, Token, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let r = Token::Atomic(String::from("r"));
+        let mut s = State::new();
+        for (name, v) in [("O", 100.0), ("S", 300.0)] {
+            s.set_balance(&User::new(name), &t0, v);
+            s.set_balance(&User::new(name), &t1, v);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s).unwrap();
+        let mut s = Deposit::new(&User::new("O"), 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        s.rewards.add_farm(&Token::mint(&t0, &t1), &r, 10.0, 0);
+        (s, t0, t1, r)
+    }
+
+    #[test]
+    fn rewards_follow_the_stake_over_time() {
+        let (s, t0, t1, r) = setup();
+        let (o, b) = (User::new("O"), User::new("B"));
+        let lp = Token::mint(&t0, &t1);
+        let minted = s.get_balance(&o, &lp);
+        let mut s = Stake::new(&o, &t0, &t1, 100.0).apply(&s).unwrap();
+        s.set_balance(&b, &lp, 50.0);
+        let steps: Vec<Box<dyn Transition>> = vec![
+            Box::new(AdvanceBlock::new(10)),
+            Box::new(Stake::new(&b, &t0, &t1, 50.0)),
+            Box::new(AdvanceBlock::new(10)),
+            Box::new(Unstake::new(&o, &t0, &t1, 100.0)),
+            Box::new(AdvanceBlock::new(5)),
+            Box::new(Claim::new(&o, &t1, &t0)),
+        ];
+        for t in steps {
+            s = t.apply(&s).unwrap();
+        }
+        // O takes all of the first 10 blocks and 2/3 of the next 10, B the rest
+        assert!((s.get_balance(&o, &r) - (100.0 + 200.0 / 3.0)).abs() < 1e-9);
+        assert_eq!(s.rewards.get_pending(&o, &lp, s.block), 0.0);
+        assert!((s.rewards.get_pending(&b, &lp, s.block) - (100.0 / 3.0 + 50.0)).abs() < 1e-9);
+        assert_eq!(s.get_balance(&o, &lp), minted);
+        assert!(matches!(Unstake::new(&b, &t0, &t1, 60.0).apply(&s), Err(TransitionError::InsufficientBalance)));
+    }
+
+    #[test]
+    fn staked_tokens_keep_their_value() {
+        let (s, t0, t1, _) = setup();
+        let o = User::new("O");
+        let lp = Token::mint(&t0, &t1);
+        let post = Stake::new(&o, &t0, &t1, 60.0).apply(&s).unwrap();
+        assert_eq!(post.token_supply(&lp), s.token_supply(&lp));
+        let f = crate::price_oracle;
+        assert!((post.net_wealth_user(&o, &f) - s.net_wealth_user(&o, &f)).abs() < 1e-9);
+        assert!(matches!(Claim::new(&o, &t0, &Token::Atomic(String::from("t2"))).apply(&s), Err(TransitionError::UnknownFarm)));
+        // claiming without a stake opens no position
+        let claimed = Claim::new(&User::new("B"), &t0, &t1).apply(&post).unwrap();
+        assert_eq!(claimed.rewards.stakes.len(), post.rewards.stakes.len());
+    }
+
+    #[test]
+    fn sniping_the_snapshot_does_not_pay() {
+        let (s, t0, t1, r) = setup();
+        let (o, sniper) = (User::new("O"), User::new("S"));
+        let lp = Token::mint(&t0, &t1);
+        let s = Stake::new(&o, &t0, &t1, 100.0).apply(&s).unwrap();
+        let s = AdvanceBlock::new(99).apply(&s).unwrap();
+        // the sniper deposits and stakes three times the pool one block before
+        let s = Deposit::new(&sniper, 300.0, &t0, 300.0, &t1).apply(&s).unwrap();
+        let s = Stake::new(&sniper, &t0, &t1, 300.0).apply(&s).unwrap();
+        let s = AdvanceBlock::new(1).apply(&s).unwrap();
+
+        let naive = snapshot_rewards(&s, &lp, 0);
+        assert!((naive.iter().find(|(u, _)| *u == sniper).unwrap().1 - 750.0).abs() < 1e-9);
+        let s = Claim::new(&sniper, &t0, &t1).apply(&s).unwrap();
+        assert!((s.get_balance(&sniper, &r) - 7.5).abs() < 1e-9);
+        assert!((s.rewards.get_pending(&o, &lp, s.block) - 992.5).abs() < 1e-9);
+    }
+}
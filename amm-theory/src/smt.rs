@@ -0,0 +1,505 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{order_tokens, Curve, Deposit, FeeMode, Redeem, State, Swap, Token, Transition, TransitionError, User,
+    MINIMUM_LIQUIDITY};
+
+// An amount of a parametric transition: known, or left to the solver
+#[derive(Clone)]
+pub enum Amount {
+    Const(f64),
+    Var(String),
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Amount::Const(v) => write!(f, "{}", v),
+            Amount::Var(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+// A transition whose amounts may be symbolic
+#[allow(dead_code)]
+pub enum Symbolic {
+    Swap { sender: User, tin: Token, tout: Token, x: Amount },
+    Deposit { sender: User, v0: Amount, t0: Token, v1: Amount, t1: Token },
+    Redeem { sender: User, t0: Token, t1: Token, v: Amount },
+}
+
+impl fmt::Display for Symbolic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Symbolic::Swap { sender, tin, tout, x } => write!(f, "{}:swap({},{},{})", sender, tin, tout, x),
+            Symbolic::Deposit { sender, v0, t0, v1, t1 } => write!(f, "{}:dep({}:{},{}:{})", sender, v0, t0, v1, t1),
+            Symbolic::Redeem { sender, t0, t1, v } => write!(f, "{}:rdm({}:{})", sender, v, Token::mint(t0, t1)),
+        }
+    }
+}
+
+// SMT-LIB reals have no exponent and no negative literals
+fn real(v: f64) -> String {
+    if v < 0.0 {
+        return format!("(- {})", real(-v));
+    }
+    let s = format!("{}", v);
+    if s.contains('.') { s } else { s + ".0" }
+}
+
+// Symbolic state: the SMT term currently holding each balance, reserve and
+// LP supply touched so far. The others keep their value in the initial state.
+struct Encoder<'a> {
+    s0: &'a State,
+    terms: HashMap<String, String>,
+    vars: Vec<String>,
+    text: String,
+    step: usize,
+}
+
+impl<'a> Encoder<'a> {
+    fn balance(&self, u: &User, t: &Token) -> String {
+        let key = format!("{}.{}", u, t);
+        self.terms.get(&key).cloned().unwrap_or_else(|| real(self.s0.get_balance(u, t)))
+    }
+
+    fn reserve(&self, t: &Token, other: &Token) -> String {
+        let key = format!("{}.{}", Token::mint(t, other), t);
+        self.terms.get(&key).cloned().unwrap_or_else(|| real(self.s0.get_reserves(t, other)))
+    }
+
+    fn supply(&self, lp_token: &Token) -> String {
+        let key = format!("{}.supply", lp_token);
+        self.terms.get(&key).cloned().unwrap_or_else(|| real(self.s0.token_supply(lp_token)))
+    }
+
+    fn amount(&mut self, a: &Amount) -> String {
+        match a {
+            Amount::Const(v) => real(*v),
+            Amount::Var(x) => {
+                if !self.vars.contains(x) {
+                    self.vars.push(x.clone());
+                    self.text += &format!("(declare-const {} Real)\n(assert (> {} 0.0))\n", x, x);
+                }
+                x.clone()
+            }
+        }
+    }
+
+    // Binds key to a fresh constant equal to expr
+    fn define(&mut self, key: String, expr: String) -> String {
+        let name = format!("|{}@{}|", key, self.step);
+        self.text += &format!("(define-fun {} () Real {})\n", name, expr);
+        self.terms.insert(key, name.clone());
+        name
+    }
+
+    fn set_balance(&mut self, u: &User, t: &Token, expr: String) {
+        let name = self.define(format!("{}.{}", u, t), expr);
+        self.text += &format!("(assert (>= {} 0.0))\n", name);
+    }
+
+    fn set_reserve(&mut self, t: &Token, other: &Token, expr: String) {
+        self.define(format!("{}.{}", Token::mint(t, other), t), expr);
+    }
+
+    fn set_supply(&mut self, lp_token: &Token, expr: String) {
+        self.define(format!("{}.supply", lp_token), expr);
+    }
+
+    fn swap(&mut self, sender: &User, tin: &Token, tout: &Token, x: &Amount) -> Result<(), TransitionError> {
+        let amm = self.s0.get_amm(tin, tout).ok_or(TransitionError::UnknownPool)?;
+        let x = self.amount(x);
+        let (rin, rout) = (self.reserve(tin, tout), self.reserve(tout, tin));
+        let xg = format!("(* {} {})", x, real(1.0 - amm.fee));
+        let out = match amm.curve {
+            Curve::ConstantProduct => format!("(/ (* {} {}) (+ {} {}))", rout, xg, rin, xg),
+            Curve::ConstantSum => xg,
+        };
+        let out = self.define(format!("{}.out", sender), out);
+        self.text += &format!("(assert (< {} {}))\n", out, rout);
+        let (bin, bout) = (self.balance(sender, tin), self.balance(sender, tout));
+        self.set_balance(sender, tin, format!("(- {} {})", bin, x));
+        self.set_balance(sender, tout, format!("(+ {} {})", bout, out));
+        let mut post_rin = format!("(+ {} {})", rin, x);
+        if let Some(pf) = amm.protocol_fee.as_ref().filter(|pf| pf.mode == FeeMode::Tokens) {
+            let cut = format!("(* {} {})", x, real(amm.fee * pf.fraction));
+            let treasury = self.balance(&pf.treasury, tin);
+            self.set_balance(&pf.treasury, tin, format!("(+ {} {})", treasury, cut));
+            post_rin = format!("(- {} {})", post_rin, cut);
+        }
+        self.set_reserve(tin, tout, post_rin);
+        self.set_reserve(tout, tin, format!("(- {} {})", rout, out));
+        Ok(())
+    }
+
+    fn deposit(&mut self, sender: &User, v0: &Amount, t0: &Token, v1: &Amount, t1: &Token) -> Result<(), TransitionError> {
+        if self.s0.get_amm(t0, t1).is_none() {
+            return Err(TransitionError::UnknownPool);
+        }
+        let (v0, v1) = (self.amount(v0), self.amount(v1));
+        let lp_token = Token::mint(t0, t1);
+        let (r0, r1) = (self.reserve(t0, t1), self.reserve(t1, t0));
+        let supply = self.supply(&lp_token);
+        let minted = if supply == real(0.0) {
+            format!("(- (+ {} {}) {})", v0, v1, real(MINIMUM_LIQUIDITY))
+        } else {
+            let (s0, s1) = (format!("(/ {} {})", v0, r0), format!("(/ {} {})", v1, r1));
+            format!("(* {} (ite (< {} {}) {} {}))", supply, s0, s1, s0, s1)
+        };
+        let minted = self.define(format!("{}.minted", sender), minted);
+        self.text += &format!("(assert (> {} 0.0))\n", minted);
+        let supply = if supply == real(0.0) { real(MINIMUM_LIQUIDITY) } else { supply };
+        let (b0, b1, lp) = (self.balance(sender, t0), self.balance(sender, t1), self.balance(sender, &lp_token));
+        self.set_balance(sender, t0, format!("(- {} {})", b0, v0));
+        self.set_balance(sender, t1, format!("(- {} {})", b1, v1));
+        self.set_balance(sender, &lp_token, format!("(+ {} {})", lp, minted));
+        self.set_reserve(t0, t1, format!("(+ {} {})", r0, v0));
+        self.set_reserve(t1, t0, format!("(+ {} {})", r1, v1));
+        self.set_supply(&lp_token, format!("(+ {} {})", supply, minted));
+        Ok(())
+    }
+
+    fn redeem(&mut self, sender: &User, t0: &Token, t1: &Token, v: &Amount) -> Result<(), TransitionError> {
+        if self.s0.get_amm(t0, t1).is_none() {
+            return Err(TransitionError::UnknownPool);
+        }
+        let v = self.amount(v);
+        let lp_token = Token::mint(t0, t1);
+        let (r0, r1) = (self.reserve(t0, t1), self.reserve(t1, t0));
+        let supply = self.supply(&lp_token);
+        let (b0, b1, lp) = (self.balance(sender, t0), self.balance(sender, t1), self.balance(sender, &lp_token));
+        let out0 = format!("(/ (* {} {}) {})", r0, v, supply);
+        let out1 = format!("(/ (* {} {}) {})", r1, v, supply);
+        self.set_balance(sender, &lp_token, format!("(- {} {})", lp, v));
+        self.set_balance(sender, t0, format!("(+ {} {})", b0, out0));
+        self.set_balance(sender, t1, format!("(+ {} {})", b1, out1));
+        self.set_reserve(t0, t1, format!("(- {} {})", r0, out0));
+        self.set_reserve(t1, t0, format!("(- {} {})", r1, out1));
+        self.set_supply(&lp_token, format!("(- {} {})", supply, v));
+        Ok(())
+    }
+
+    // Value of the wallet of u in the current symbolic state: atomic tokens
+    // at their price in s0, LP tokens at the value of their share of the
+    // reserves
+    fn wealth(&self, u: &User, tokens: &[Token], f: &dyn Fn(&State, &Token) -> f64) -> String {
+        let mut terms = Vec::new();
+        for t in tokens {
+            match t {
+                Token::Atomic(_) => terms.push(format!("(* {} {})", self.balance(u, t), real(f(self.s0, t)))),
+                Token::Minted(d0, d1) => {
+                    let t0 = Token::Atomic(d0.clone());
+                    let t1 = Token::Atomic(d1.clone());
+                    let supply = self.supply(t);
+                    if supply == real(0.0) {
+                        continue;
+                    }
+                    let reserves = format!("(+ (* {} {}) (* {} {}))", self.reserve(&t0, &t1), real(f(self.s0, &t0)),
+                        self.reserve(&t1, &t0), real(f(self.s0, &t1)));
+                    terms.push(format!("(/ (* {} {}) {})", self.balance(u, t), reserves, supply));
+                }
+            }
+        }
+        if terms.is_empty() {
+            return real(0.0);
+        }
+        format!("(+ {} {})", real(0.0), terms.join(" "))
+    }
+}
+
+// Encodes the sequence of steps from s0 as a nonlinear real arithmetic
+// problem asking for a gain in net wealth of the attacker above bound, with
+// atomic tokens priced by f in s0. The query is plain SMT-LIB: the caller
+// finds the best gain by bisecting on bound. Amounts are real: LP rounding is
+// not modelled, nor the protocol fees minted as LP tokens.
+pub fn export(s0: &State, steps: &[Symbolic], attacker: &User, f: &dyn Fn(&State, &Token) -> f64, bound: f64)
+    -> Result<String, TransitionError> {
+    let mut e = Encoder { s0, terms: HashMap::new(), vars: Vec::new(), text: String::new(), step: 0 };
+    e.text += "(set-logic QF_NRA)\n";
+
+    let mut tokens: Vec<Token> = Vec::new();
+    if let Some(w) = s0.wallets.iter().find(|w| w.user == *attacker) {
+        tokens.extend(w.balances.iter().map(|b| b.token.clone()));
+    }
+    for step in steps {
+        let (t0, t1) = match step {
+            Symbolic::Swap { tin, tout, .. } => order_tokens(tin, tout),
+            Symbolic::Deposit { t0, t1, .. } | Symbolic::Redeem { t0, t1, .. } => order_tokens(t0, t1),
+        };
+        for t in [t0.clone(), t1.clone(), Token::mint(t0, t1)] {
+            if !tokens.contains(&t) {
+                tokens.push(t);
+            }
+        }
+    }
+    let initial = e.wealth(attacker, &tokens, f);
+
+    for (i, step) in steps.iter().enumerate() {
+        e.step = i + 1;
+        e.text += &format!("; {}\n", step);
+        match step {
+            Symbolic::Swap { sender, tin, tout, x } => e.swap(sender, tin, tout, x)?,
+            Symbolic::Deposit { sender, v0, t0, v1, t1 } => e.deposit(sender, v0, t0, v1, t1)?,
+            Symbolic::Redeem { sender, t0, t1, v } => e.redeem(sender, t0, t1, v)?,
+        }
+    }
+
+    let last = e.wealth(attacker, &tokens, f);
+    e.text += &format!("(define-fun gain () Real (- {} {}))\n", last, initial);
+    e.text += &format!("(assert (> gain {}))\n(check-sat)\n(get-model)\n", real(bound));
+    Ok(e.text)
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum ModelError {
+    Syntax(String),
+    Missing(String),
+}
+
+enum Sexp {
+    Atom(String),
+    List(Vec<Sexp>),
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' | ')' => tokens.push(c.to_string()),
+            ';' => {
+                while chars.next_if(|&c| c != '\n').is_some() {}
+            }
+            '|' => {
+                let mut atom = String::from("|");
+                for c in chars.by_ref() {
+                    atom.push(c);
+                    if c == '|' {
+                        break;
+                    }
+                }
+                tokens.push(atom);
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                let mut atom = c.to_string();
+                while let Some(c) = chars.next_if(|&c| !c.is_whitespace() && c != '(' && c != ')') {
+                    atom.push(c);
+                }
+                tokens.push(atom);
+            }
+        }
+    }
+    tokens
+}
+
+fn parse_sexp(tokens: &[String], pos: &mut usize) -> Result<Sexp, ModelError> {
+    let token = tokens.get(*pos).ok_or(ModelError::Syntax(String::from("unexpected end")))?;
+    *pos += 1;
+    match token.as_str() {
+        "(" => {
+            let mut items = Vec::new();
+            while tokens.get(*pos).map(|t| t.as_str()) != Some(")") {
+                items.push(parse_sexp(tokens, pos)?);
+            }
+            *pos += 1;
+            Ok(Sexp::List(items))
+        }
+        ")" => Err(ModelError::Syntax(String::from("unexpected )"))),
+        atom => Ok(Sexp::Atom(atom.to_string())),
+    }
+}
+
+fn parse_all(text: &str) -> Result<Vec<Sexp>, ModelError> {
+    let tokens = tokenize(text);
+    let mut pos = 0;
+    let mut all = Vec::new();
+    while pos < tokens.len() {
+        all.push(parse_sexp(&tokens, &mut pos)?);
+    }
+    Ok(all)
+}
+
+// Value of a real term, given the values of the constants it refers to.
+// Decimals approximated by the solver end with '?'.
+fn eval(e: &Sexp, env: &HashMap<String, f64>) -> Result<f64, ModelError> {
+    match e {
+        Sexp::Atom(a) => {
+            if let Some(v) = env.get(a) {
+                return Ok(*v);
+            }
+            a.trim_end_matches('?').parse().map_err(|_| ModelError::Missing(a.clone()))
+        }
+        Sexp::List(items) => {
+            let op = match items.first() {
+                Some(Sexp::Atom(op)) => op.as_str(),
+                _ => return Err(ModelError::Syntax(String::from("expected an operator"))),
+            };
+            if op == "ite" && items.len() == 4 {
+                return if eval_bool(&items[1], env)? { eval(&items[2], env) } else { eval(&items[3], env) };
+            }
+            let args = items[1..].iter().map(|a| eval(a, env)).collect::<Result<Vec<_>, _>>()?;
+            match (op, args.as_slice()) {
+                ("-", [a]) => Ok(-a),
+                ("-", [a, rest @ ..]) => Ok(a - rest.iter().sum::<f64>()),
+                ("+", _) => Ok(args.iter().sum()),
+                ("*", _) => Ok(args.iter().product()),
+                ("/", [a, b]) => Ok(a / b),
+                _ => Err(ModelError::Syntax(format!("unsupported operator {}", op))),
+            }
+        }
+    }
+}
+
+fn eval_bool(e: &Sexp, env: &HashMap<String, f64>) -> Result<bool, ModelError> {
+    if let Sexp::List(items) = e {
+        if let [Sexp::Atom(op), a, b] = items.as_slice() {
+            let (a, b) = (eval(a, env)?, eval(b, env)?);
+            match op.as_str() {
+                "<" => return Ok(a < b),
+                "<=" => return Ok(a <= b),
+                ">" => return Ok(a > b),
+                ">=" => return Ok(a >= b),
+                "=" => return Ok(a == b),
+                _ => {}
+            }
+        }
+    }
+    Err(ModelError::Syntax(String::from("unsupported condition")))
+}
+
+// Reads the real constants of a model (the output of get-model), or of any
+// text made of define-fun commands, each one possibly referring to the
+// previous ones
+pub fn parse_model(text: &str) -> Result<HashMap<String, f64>, ModelError> {
+    let mut model = HashMap::new();
+    let mut pending: Vec<Sexp> = parse_all(text)?;
+    pending.reverse();
+    while let Some(e) = pending.pop() {
+        if let Sexp::List(items) = e {
+            match items.as_slice() {
+                [Sexp::Atom(cmd), Sexp::Atom(name), Sexp::List(args), Sexp::Atom(sort), value]
+                    if cmd == "define-fun" && args.is_empty() && sort == "Real" => {
+                    let v = eval(value, &model)?;
+                    model.insert(name.clone(), v);
+                }
+                _ => pending.extend(items.into_iter().rev()),
+            }
+        }
+    }
+    Ok(model)
+}
+
+// The concrete transitions of the steps under the values of a model
+pub fn instantiate(steps: &[Symbolic], model: &HashMap<String, f64>) -> Result<Vec<Box<dyn Transition>>, ModelError> {
+    let value = |a: &Amount| match a {
+        Amount::Const(v) => Ok(*v),
+        Amount::Var(x) => model.get(x).copied().ok_or(ModelError::Missing(x.clone())),
+    };
+    steps.iter()
+        .map(|step| -> Result<Box<dyn Transition>, ModelError> {
+            Ok(match step {
+                Symbolic::Swap { sender, tin, tout, x } => Box::new(Swap::new(sender, tin, tout, value(x)?)),
+                Symbolic::Deposit { sender, v0, t0, v1, t1 } => Box::new(Deposit::new(sender, value(v0)?, t0, value(v1)?, t1)),
+                Symbolic::Redeem { sender, t0, t1, v } => Box::new(Redeem::new(sender, t0, t1, value(v)?)),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_oracle, CreatePool};
+
+    fn setup() -> (State, Vec<Symbolic>, User) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let a = User::new("A");
+        let m = User::new("M");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        s.set_balance(&a, &t0, 20.0);
+        s.set_balance(&m, &t0, 50.0);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        let s = Deposit::new(&o, 100.0, &t0, 100.0, &t1).apply(&s).unwrap();
+        let steps = vec![
+            Symbolic::Swap { sender: m.clone(), tin: t0.clone(), tout: t1.clone(), x: Amount::Var(String::from("x0")) },
+            Symbolic::Swap { sender: a, tin: t0.clone(), tout: t1.clone(), x: Amount::Const(20.0) },
+            Symbolic::Swap { sender: m.clone(), tin: t1, tout: t0, x: Amount::Var(String::from("x1")) },
+        ];
+        (s, steps, m)
+    }
+
+    #[test]
+    fn sandwich_is_encoded() {
+        let (s0, steps, m) = setup();
+        let text = export(&s0, &steps, &m, &price_oracle, 0.5).unwrap();
+        assert!(text.starts_with("(set-logic QF_NRA)\n"));
+        assert!(text.contains("(declare-const x0 Real)\n(assert (> x0 0.0))\n"));
+        assert!(text.contains("; A:swap(t0,t1,20)\n"));
+        assert!(text.contains("(define-fun |A.out@2| () Real (/ (* |t0+t1.t1@1| (* 20.0 0.997)) (+ |t0+t1.t0@1| (* 20.0 0.997))))"));
+        assert!(text.contains("(assert (>= |M.t1@3| 0.0))"));
+        assert!(text.ends_with("(assert (> gain 0.5))\n(check-sat)\n(get-model)\n"));
+        assert!(!text.contains("set-option") && !text.contains("maximize"));
+    }
+
+    #[test]
+    fn encoding_agrees_with_the_transitions() {
+        let (s0, steps, m) = setup();
+        let text = export(&s0, &steps, &m, &price_oracle, 0.0).unwrap();
+        // a model as printed by a solver, then the definitions of the problem under it
+        let model = "sat\n(\n  (define-fun x1 () Real\n    (/ 31.0 2.0))\n  (define-fun x0 () Real\n    12.5?)\n)\n";
+        let values = parse_model(model).unwrap();
+        assert_eq!(values["x0"], 12.5);
+        assert_eq!(values["x1"], 15.5);
+        let text = text.replace("(declare-const x0 Real)", "(define-fun x0 () Real 12.5)")
+            .replace("(declare-const x1 Real)", "(define-fun x1 () Real 15.5)");
+        let gain = parse_model(&text).unwrap()["gain"];
+
+        let mut s = s0.clone();
+        for t in instantiate(&steps, &values).unwrap() {
+            s = t.apply(&s).unwrap();
+        }
+        let expected = s.net_wealth_user(&m, &price_oracle) - s0.net_wealth_user(&m, &price_oracle);
+        assert!((gain - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn liquidity_events_are_encoded() {
+        let (s0, _, m) = setup();
+        let (t0, t1) = (Token::Atomic(String::from("t0")), Token::Atomic(String::from("t1")));
+        let mut s0 = s0;
+        s0.set_balance(&m, &t1, 50.0);
+        let steps = vec![
+            Symbolic::Deposit { sender: m.clone(), v0: Amount::Var(String::from("x0")), t0: t0.clone(),
+                v1: Amount::Const(10.0), t1: t1.clone() },
+            Symbolic::Swap { sender: User::new("A"), tin: t0.clone(), tout: t1.clone(), x: Amount::Const(20.0) },
+            Symbolic::Redeem { sender: m.clone(), t0, t1, v: Amount::Const(15.0) },
+        ];
+        let text = export(&s0, &steps, &m, &price_oracle, 0.0).unwrap()
+            .replace("(declare-const x0 Real)", "(define-fun x0 () Real 12.0)");
+        let gain = parse_model(&text).unwrap()["gain"];
+
+        let values = parse_model("((define-fun x0 () Real 12.0))").unwrap();
+        let mut s = s0.clone();
+        for t in instantiate(&steps, &values).unwrap() {
+            s = t.apply(&s).unwrap();
+        }
+        let expected = s.net_wealth_user(&m, &price_oracle) - s0.net_wealth_user(&m, &price_oracle);
+        // up to the LP rounding, which is not encoded
+        assert!((gain - expected).abs() < 1e-3);
+    }
+
+    #[test]
+    fn missing_variables_are_reported() {
+        let (_, steps, _) = setup();
+        let values = parse_model("((define-fun x0 () Real (- 1.0)))").unwrap();
+        assert_eq!(values["x0"], -1.0);
+        assert!(matches!(instantiate(&steps, &values), Err(ModelError::Missing(x)) if x == "x1"));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::{State, Token, User};
+
+// Charts as standalone SVG documents. Coordinates are printed with two
+// decimals and colors come from a fixed palette, so that the same input
+// always gives the same bytes.
+
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 400.0;
+const MARGIN: f64 = 50.0;
+const PALETTE: &[&str] = &["#1f77b4", "#d62728", "#2ca02c", "#ff7f0e", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f"];
+
+// Linear map of the data bounds to the plot area, y growing upwards
+struct Frame {
+    x: (f64, f64),
+    y: (f64, f64),
+}
+
+impl Frame {
+    fn new(x: (f64, f64), y: (f64, f64)) -> Self {
+        let widen = |(lo, hi): (f64, f64)| if hi > lo { (lo, hi) } else { (lo - 1.0, hi + 1.0) };
+        Frame { x: widen(x), y: widen(y) }
+    }
+
+    fn point(&self, x: f64, y: f64) -> (f64, f64) {
+        let px = MARGIN + (x - self.x.0) / (self.x.1 - self.x.0) * (WIDTH - 2.0 * MARGIN);
+        let py = HEIGHT - MARGIN - (y - self.y.0) / (self.y.1 - self.y.0) * (HEIGHT - 2.0 * MARGIN);
+        (px, py)
+    }
+
+    fn polyline(&self, points: &[(f64, f64)], color: &str) -> String {
+        let points: Vec<String> = points.iter()
+            .map(|(x, y)| {
+                let (px, py) = self.point(*x, *y);
+                format!("{:.2},{:.2}", px, py)
+            })
+            .collect();
+        format!("<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\"/>\n", points.join(" "), color)
+    }
+
+    // Axes with their labels and the bounds of the data
+    fn axes(&self, x_label: &str, y_label: &str) -> String {
+        let (left, bottom) = (MARGIN, HEIGHT - MARGIN);
+        let (right, top) = (WIDTH - MARGIN, MARGIN);
+        let mut out = format!(
+            "<line x1=\"{l:.2}\" y1=\"{b:.2}\" x2=\"{r:.2}\" y2=\"{b:.2}\" stroke=\"black\"/>\n\
+             <line x1=\"{l:.2}\" y1=\"{b:.2}\" x2=\"{l:.2}\" y2=\"{t:.2}\" stroke=\"black\"/>\n",
+            l = left, b = bottom, r = right, t = top,
+        );
+        out += &text(left, bottom + 15.0, "middle", &format!("{:.1}", self.x.0));
+        out += &text(right, bottom + 15.0, "middle", &format!("{:.1}", self.x.1));
+        out += &text(left - 5.0, bottom, "end", &format!("{:.1}", self.y.0));
+        out += &text(left - 5.0, top, "end", &format!("{:.1}", self.y.1));
+        out += &text((left + right) / 2.0, HEIGHT - 10.0, "middle", x_label);
+        out += &text(10.0, top - 15.0, "start", y_label);
+        out
+    }
+}
+
+fn text(x: f64, y: f64, anchor: &str, content: &str) -> String {
+    let escaped = content.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    format!("<text x=\"{:.2}\" y=\"{:.2}\" text-anchor=\"{}\" font-family=\"monospace\" font-size=\"11\">{}</text>\n",
+        x, y, anchor, escaped)
+}
+
+fn document(body: &str) -> String {
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\">\n\
+         <rect width=\"{w}\" height=\"{h}\" fill=\"white\"/>\n{body}</svg>\n",
+        w = WIDTH, h = HEIGHT, body = body,
+    )
+}
+
+fn bounds(values: impl Iterator<Item = f64>) -> (f64, f64) {
+    values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)))
+}
+
+// The reserves of the pool of t0 and t1 in every state of a trace where it
+// exists, with the constant-product curve through each distinct product of
+// the reserves (deposits and redeems move the pool to another curve)
+pub fn reserves_chart(states: &[State], t0: &Token, t1: &Token) -> String {
+    let points: Vec<(usize, f64, f64)> = states.iter().enumerate()
+        .filter_map(|(i, s)| s.get_amm(t0, t1).map(|_| (i, s.get_reserves(t0, t1), s.get_reserves(t1, t0))))
+        .filter(|(_, r0, r1)| *r0 > 0.0 && *r1 > 0.0)
+        .collect();
+    if points.is_empty() {
+        return document(&text(WIDTH / 2.0, HEIGHT / 2.0, "middle", "no reserves"));
+    }
+    let (x0, x1) = bounds(points.iter().map(|p| p.1));
+    let (y0, y1) = bounds(points.iter().map(|p| p.2));
+    // leave room around the trajectory to show the curves
+    let frame = Frame::new((x0 * 0.8, x1 * 1.2), (y0 * 0.8, y1 * 1.2));
+
+    let mut products: Vec<f64> = Vec::new();
+    for (_, r0, r1) in &points {
+        let k = r0 * r1;
+        if !products.iter().any(|p| (p - k).abs() <= 1e-9 * k) {
+            products.push(k);
+        }
+    }
+    let mut body = frame.axes(&format!("reserve of {}", t0), &format!("reserve of {}", t1));
+    for k in &products {
+        // sampled where the curve is inside the frame
+        let (lo, hi) = (frame.x.0.max(k / frame.y.1), frame.x.1.min(k / frame.y.0));
+        let samples: Vec<(f64, f64)> = (0..=64)
+            .map(|i| lo + (hi - lo) * i as f64 / 64.0)
+            .map(|x| (x, k / x))
+            .collect();
+        body += &frame.polyline(&samples, "#bbbbbb");
+    }
+    let path: Vec<(f64, f64)> = points.iter().map(|(_, r0, r1)| (*r0, *r1)).collect();
+    body += &frame.polyline(&path, PALETTE[0]);
+    for (i, r0, r1) in &points {
+        let (px, py) = frame.point(*r0, *r1);
+        body += &format!("<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"3\" fill=\"{}\"/>\n", px, py, PALETTE[1]);
+        body += &text(px + 5.0, py - 5.0, "start", &i.to_string());
+    }
+    document(&body)
+}
+
+// The net wealth of every user over the steps of a trace
+pub fn wealth_series(states: &[State], f: &dyn Fn(&State, &Token) -> f64) -> Vec<(User, Vec<f64>)> {
+    let mut users: Vec<User> = Vec::new();
+    for s in states {
+        for w in &s.wallets {
+            if !users.contains(&w.user) {
+                users.push(w.user.clone());
+            }
+        }
+    }
+    users.into_iter()
+        .map(|u| {
+            let curve = states.iter().map(|s| s.net_wealth_user(&u, f)).collect();
+            (u, curve)
+        })
+        .collect()
+}
+
+// One line per user, with a legend in the order of the series
+pub fn wealth_chart(series: &[(User, Vec<f64>)]) -> String {
+    let steps = series.iter().map(|(_, c)| c.len()).max().unwrap_or(0);
+    if steps == 0 {
+        return document(&text(WIDTH / 2.0, HEIGHT / 2.0, "middle", "no data"));
+    }
+    let frame = Frame::new((0.0, (steps - 1) as f64), bounds(series.iter().flat_map(|(_, c)| c.iter().copied())));
+    let mut body = frame.axes("step", "net wealth");
+    for (i, (user, curve)) in series.iter().enumerate() {
+        let color = PALETTE[i % PALETTE.len()];
+        let points: Vec<(f64, f64)> = curve.iter().enumerate().map(|(j, w)| (j as f64, *w)).collect();
+        body += &frame.polyline(&points, color);
+        let y = MARGIN + 15.0 * i as f64;
+        body += &format!("<rect x=\"{:.2}\" y=\"{:.2}\" width=\"10\" height=\"10\" fill=\"{}\"/>\n", WIDTH - MARGIN + 5.0, y - 9.0, color);
+        body += &text(WIDTH - MARGIN + 18.0, y, "start", &user.to_string());
+    }
+    document(&body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_oracle, CreatePool, Curve, Deposit, Swap, Transition};
+
+    fn trace() -> (Vec<State>, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let a = User::new("A");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 100.0);
+        s.set_balance(&o, &t1, 100.0);
+        s.set_balance(&a, &t0, 50.0);
+        s.set_balance(&a, &t1, 50.0);
+        let mut states = vec![CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s).unwrap()];
+        let v: Vec<Box<dyn Transition>> = vec![
+            Box::new(Deposit::new(&o, 100.0, &t0, 100.0, &t1)),
+            Box::new(Swap::new(&a, &t0, &t1, 20.0)),
+            Box::new(Swap::new(&a, &t1, &t0, 30.0)),
+            Box::new(Deposit::new(&a, 10.0, &t0, 10.0, &t1)),
+        ];
+        for t in v {
+            let next = t.apply(states.last().unwrap()).unwrap();
+            states.push(next);
+        }
+        (states, t0, t1)
+    }
+
+    #[test]
+    fn reserves_chart_draws_a_curve_per_product() {
+        let (states, t0, t1) = trace();
+        let svg = reserves_chart(&states, &t0, &t1);
+        assert_eq!(svg, reserves_chart(&states, &t0, &t1));
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\"") && svg.ends_with("</svg>\n"));
+        // the empty pool is left out, swaps stay on the curve of the deposit
+        assert_eq!(svg.matches("<circle").count(), 4);
+        assert_eq!(svg.matches("stroke=\"#bbbbbb\"").count(), 2);
+        assert!(svg.contains(">reserve of t0</text>"));
+    }
+
+    #[test]
+    fn wealth_chart_snapshot() {
+        let series = vec![(User::new("A"), vec![0.0, 10.0]), (User::new("B"), vec![10.0, 5.0])];
+        let expected = "\
+<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"640\" height=\"400\" viewBox=\"0 0 640 400\">
+<rect width=\"640\" height=\"400\" fill=\"white\"/>
+<line x1=\"50.00\" y1=\"350.00\" x2=\"590.00\" y2=\"350.00\" stroke=\"black\"/>
+<line x1=\"50.00\" y1=\"350.00\" x2=\"50.00\" y2=\"50.00\" stroke=\"black\"/>
+<text x=\"50.00\" y=\"365.00\" text-anchor=\"middle\" font-family=\"monospace\" font-size=\"11\">0.0</text>
+<text x=\"590.00\" y=\"365.00\" text-anchor=\"middle\" font-family=\"monospace\" font-size=\"11\">1.0</text>
+<text x=\"45.00\" y=\"350.00\" text-anchor=\"end\" font-family=\"monospace\" font-size=\"11\">0.0</text>
+<text x=\"45.00\" y=\"50.00\" text-anchor=\"end\" font-family=\"monospace\" font-size=\"11\">10.0</text>
+<text x=\"320.00\" y=\"390.00\" text-anchor=\"middle\" font-family=\"monospace\" font-size=\"11\">step</text>
+<text x=\"10.00\" y=\"35.00\" text-anchor=\"start\" font-family=\"monospace\" font-size=\"11\">net wealth</text>
+<polyline points=\"50.00,350.00 590.00,50.00\" fill=\"none\" stroke=\"#1f77b4\" stroke-width=\"1.5\"/>
+<rect x=\"595.00\" y=\"41.00\" width=\"10\" height=\"10\" fill=\"#1f77b4\"/>
+<text x=\"608.00\" y=\"50.00\" text-anchor=\"start\" font-family=\"monospace\" font-size=\"11\">A</text>
+<polyline points=\"50.00,50.00 590.00,200.00\" fill=\"none\" stroke=\"#d62728\" stroke-width=\"1.5\"/>
+<rect x=\"595.00\" y=\"56.00\" width=\"10\" height=\"10\" fill=\"#d62728\"/>
+<text x=\"608.00\" y=\"65.00\" text-anchor=\"start\" font-family=\"monospace\" font-size=\"11\">B</text>
+</svg>
+";
+        assert_eq!(wealth_chart(&series), expected);
+
+        let (states, _, _) = trace();
+        let series = wealth_series(&states, &price_oracle);
+        assert_eq!(series.len(), 2);
+        assert_eq!(series[0].1.len(), states.len());
+    }
+}
@@ -0,0 +1,206 @@
+use std::thread;
+
+use crate::TransitionError;
+
+// Sensitivity analysis: a scenario is run on every combination of the values
+// of its named parameters, and the metrics it returns are tabulated
+
+pub struct Range {
+    pub name: String,
+    pub values: Vec<f64>,
+}
+
+impl Range {
+    pub fn new(name: &str, values: &[f64]) -> Self {
+        Range { name: String::from(name), values: values.to_vec() }
+    }
+
+    // steps evenly spaced values from first to last, both included
+    pub fn linear(name: &str, first: f64, last: f64, steps: usize) -> Self {
+        let values = (0..steps)
+            .map(|i| if steps == 1 { first } else { first + (last - first) * i as f64 / (steps - 1) as f64 })
+            .collect();
+        Range { name: String::from(name), values }
+    }
+}
+
+// The values of the parameters a scenario is run with
+pub struct Point(pub Vec<(String, f64)>);
+
+impl Point {
+    pub fn get(&self, name: &str) -> f64 {
+        match self.0.iter().find(|(n, _)| n == name) {
+            Some((_, v)) => *v,
+            None => panic!("unknown parameter {}", name),
+        }
+    }
+}
+
+pub type Metrics = Vec<(&'static str, f64)>;
+
+pub struct Row {
+    pub point: Vec<f64>,
+    // None when a transition of the scenario failed
+    pub metrics: Option<Vec<f64>>,
+}
+
+pub struct Table {
+    pub params: Vec<Range>,
+    pub metrics: Vec<&'static str>,
+    pub rows: Vec<Row>,
+}
+
+// Runs the scenario on the cartesian product of the ranges, the last one
+// varying fastest, on the given number of threads
+pub fn run(
+    ranges: Vec<Range>,
+    scenario: &(dyn Fn(&Point) -> Result<Metrics, TransitionError> + Sync),
+    threads: usize,
+) -> Table {
+    let mut points: Vec<Vec<f64>> = vec![Vec::new()];
+    for range in &ranges {
+        points = points.iter()
+            .flat_map(|p| range.values.iter().map(move |v| [p.as_slice(), &[*v]].concat()))
+            .collect();
+    }
+
+    let names: Vec<String> = ranges.iter().map(|r| r.name.clone()).collect();
+    let names = &names;
+    let chunk = points.len().div_ceil(threads.max(1)).max(1);
+    let results: Vec<Result<Metrics, TransitionError>> = thread::scope(|scope| {
+        let handles: Vec<_> = points.chunks(chunk)
+            .map(|part| scope.spawn(move || {
+                part.iter()
+                    .map(|p| scenario(&Point(names.iter().cloned().zip(p.iter().copied()).collect())))
+                    .collect::<Vec<_>>()
+            }))
+            .collect();
+        handles.into_iter().flat_map(|h| h.join().unwrap()).collect()
+    });
+
+    let metrics = results.iter()
+        .find_map(|r| r.as_ref().ok())
+        .map_or(Vec::new(), |m| m.iter().map(|(name, _)| *name).collect());
+    let rows = points.into_iter().zip(results)
+        .map(|(point, r)| Row { point, metrics: r.ok().map(|m| m.iter().map(|(_, v)| *v).collect()) })
+        .collect();
+    Table { params: ranges, metrics, rows }
+}
+
+impl Table {
+    fn param_index(&self, name: &str) -> usize {
+        self.params.iter().position(|r| r.name == name).unwrap_or_else(|| panic!("unknown parameter {}", name))
+    }
+
+    fn metric_index(&self, name: &str) -> usize {
+        self.metrics.iter().position(|m| *m == name).unwrap_or_else(|| panic!("unknown metric {}", name))
+    }
+
+    // One line per point; the metrics of failed points are left empty
+    pub fn csv(&self) -> String {
+        let header: Vec<&str> = self.params.iter().map(|r| r.name.as_str()).chain(self.metrics.iter().copied()).collect();
+        let mut csv = header.join(",") + "\n";
+        for row in &self.rows {
+            let metrics = match &row.metrics {
+                Some(m) => m.clone(),
+                None => Vec::new(),
+            };
+            let mut fields: Vec<String> = row.point.iter().chain(metrics.iter()).map(|v| v.to_string()).collect();
+            fields.resize(self.params.len() + self.metrics.len(), String::new());
+            csv += &(fields.join(",") + "\n");
+        }
+        csv
+    }
+
+    // Mean of the metric at every value of x and y, over the other parameters
+    // and the points that did not fail
+    pub fn grid(&self, x: &str, y: &str, metric: &str) -> Vec<Vec<Option<f64>>> {
+        let (xi, yi, mi) = (self.param_index(x), self.param_index(y), self.metric_index(metric));
+        self.params[yi].values.iter()
+            .map(|vy| {
+                self.params[xi].values.iter()
+                    .map(|vx| {
+                        let values: Vec<f64> = self.rows.iter()
+                            .filter(|r| r.point[xi] == *vx && r.point[yi] == *vy)
+                            .filter_map(|r| r.metrics.as_ref().map(|m| m[mi]))
+                            .collect();
+                        if values.is_empty() { None } else { Some(values.iter().sum::<f64>() / values.len() as f64) }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    // The grid of the metric shaded from its minimum to its maximum, x along
+    // the columns and y along the rows; failed cells are marked with ?
+    pub fn heatmap(&self, x: &str, y: &str, metric: &str) -> String {
+        const SHADES: &[char] = &[' ', '.', ':', '-', '=', '+', '*', '#', '%', '@'];
+        let grid = self.grid(x, y, metric);
+        let values: Vec<f64> = grid.iter().flatten().flatten().copied().collect();
+        let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let shade = |v: f64| {
+            let level = if max > min { ((v - min) / (max - min) * (SHADES.len() - 1) as f64).round() as usize } else { 0 };
+            SHADES[level]
+        };
+
+        let (xs, ys) = (&self.params[self.param_index(x)].values, &self.params[self.param_index(y)].values);
+        let mut out = format!("{} ({} across, {} down)\n{:>10}", metric, x, y, "");
+        for vx in xs {
+            out += &format!(" {:>6.1}", vx);
+        }
+        out += "\n";
+        for (vy, cells) in ys.iter().zip(&grid) {
+            out += &format!("{:>10.1}", vy);
+            for cell in cells {
+                let c = cell.map_or('?', shade);
+                out += &format!(" {}", c.to_string().repeat(6));
+            }
+            out += "\n";
+        }
+        out += &format!("'{}' = {:.1} ... '{}' = {:.1}\n", SHADES[0], min, SHADES[SHADES.len() - 1], max);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn product(p: &Point) -> Result<Metrics, TransitionError> {
+        if p.get("x") < 0.0 {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        Ok(vec![("product", p.get("x") * p.get("y")), ("sum", p.get("x") + p.get("y"))])
+    }
+
+    #[test]
+    fn runs_the_cartesian_product_in_order() {
+        let table = run(vec![Range::linear("x", -1.0, 1.0, 3), Range::new("y", &[2.0, 3.0])], &product, 4);
+        assert_eq!(table.rows.len(), 6);
+        assert_eq!(table.metrics, vec!["product", "sum"]);
+        assert_eq!(table.rows[3].point, vec![0.0, 3.0]);
+        assert!(table.rows[0].metrics.is_none());
+        assert_eq!(table.rows[5].metrics, Some(vec![3.0, 4.0]));
+        let csv = table.csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "x,y,product,sum");
+        assert_eq!(lines[1], "-1,2,,");
+        assert_eq!(lines[6], "1,3,3,4");
+    }
+
+    #[test]
+    fn heatmap_shades_from_min_to_max() {
+        let table = run(vec![Range::linear("x", -1.0, 2.0, 4), Range::new("y", &[1.0, 2.0]), Range::new("z", &[0.0, 1.0])],
+            &product, 3);
+        let grid = table.grid("x", "y", "sum");
+        assert_eq!(grid.len(), 2);
+        assert_eq!(grid[0], vec![None, Some(1.0), Some(2.0), Some(3.0)]);
+        let map = table.heatmap("x", "y", "product");
+        let rows: Vec<&str> = map.lines().collect();
+        assert_eq!(rows.len(), 5);
+        assert!(rows[2].contains("??????") && rows[2].contains("      "));
+        assert!(rows[3].ends_with("@@@@@@"));
+        assert!(rows[4].ends_with("'@' = 4.0"));
+    }
+}
@@ -0,0 +1,201 @@
+use crate::{State, Token, Transition, TransitionError, User};
+
+// Traces as tables for papers and reviews: one row per step, with the
+// transaction in the notation of the paper, the state it leads to and the
+// net wealth of every user, the initial state first. Numbers are printed
+// with the given precision, in the labels of the transactions as well, and
+// the rows of the attacker's transactions can be highlighted.
+
+pub struct Options {
+    pub precision: usize,
+    // the user whose transactions are highlighted
+    pub attacker: Option<User>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { precision: 1, attacker: None }
+    }
+}
+
+// The transactions applied in sequence, with the state after each of them
+pub fn trace(s0: &State, txs: &[Box<dyn Transition>]) -> Result<Vec<(String, State)>, TransitionError> {
+    let mut s = s0.clone();
+    let mut steps = Vec::new();
+    for t in txs {
+        s = t.apply(&s)?;
+        steps.push((t.to_string(), s.clone()));
+    }
+    Ok(steps)
+}
+
+// Rounds the decimal numbers of a label, leaving e.g. token names alone
+fn round_numbers(label: &str, precision: usize) -> String {
+    let chars: Vec<char> = label.chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let starts_number = chars[i].is_ascii_digit() && (i == 0 || !chars[i - 1].is_alphanumeric());
+        if !starts_number {
+            out.push(chars[i]);
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.' || chars[i] == 'e' || chars[i] == '-' && chars[i - 1] == 'e') {
+            i += 1;
+        }
+        let number: String = chars[start..i].iter().collect();
+        match number.parse::<f64>() {
+            Ok(v) if number.contains(['.', 'e']) => out.push_str(&format!("{:.*}", precision, v)),
+            _ => out.push_str(&number),
+        }
+    }
+    out
+}
+
+struct Row {
+    step: String,
+    label: String,
+    state: String,
+    wealth: Vec<String>,
+    highlight: bool,
+}
+
+fn rows(s0: &State, steps: &[(String, State)], f: &dyn Fn(&State, &Token) -> f64, options: &Options) -> (Vec<User>, Vec<Row>) {
+    let mut users: Vec<User> = Vec::new();
+    for s in std::iter::once(s0).chain(steps.iter().map(|(_, s)| s)) {
+        for w in &s.wallets {
+            if !users.contains(&w.user) {
+                users.push(w.user.clone());
+            }
+        }
+    }
+    let p = options.precision;
+    let row = |i: usize, label: &str, s: &State| Row {
+        step: i.to_string(),
+        label: round_numbers(label, p),
+        state: format!("{:.*}", p, s),
+        wealth: users.iter().map(|u| format!("{:.*}", p, s.net_wealth_user(u, f))).collect(),
+        highlight: options.attacker.as_ref().is_some_and(|a| label.starts_with(&format!("{}:", a))),
+    };
+    let mut rows = vec![row(0, "", s0)];
+    rows.extend(steps.iter().enumerate().map(|(i, (label, s))| row(i + 1, label, s)));
+    (users, rows)
+}
+
+fn escape_latex(text: &str) -> String {
+    let mut out = String::new();
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '{' | '}' | '_' | '&' | '%' | '$' | '#' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '~' => out.push_str("\\textasciitilde{}"),
+            '^' => out.push_str("\\textasciicircum{}"),
+            '|' => out.push_str("\\textbar{}"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+// A LaTeX tabular; highlighted rows use \rowcolor, from the xcolor package
+// loaded with the table option
+pub fn latex(s0: &State, steps: &[(String, State)], f: &dyn Fn(&State, &Token) -> f64, options: &Options) -> String {
+    let (users, rows) = rows(s0, steps, f, options);
+    let tt = |text: &str| if text.is_empty() { String::new() } else { format!("\\texttt{{{}}}", escape_latex(text)) };
+    let mut out = format!("\\begin{{tabular}}{{rll{}}}\n\\hline\n", "r".repeat(users.len()));
+    let header: Vec<String> = ["", "Transaction", "State"].iter().map(|h| h.to_string())
+        .chain(users.iter().map(|u| format!("$W_{{{}}}$", escape_latex(&u.to_string()))))
+        .collect();
+    out.push_str(&format!("{} \\\\\n\\hline\n", header.join(" & ")));
+    for r in rows {
+        let cells: Vec<String> = [r.step, tt(&r.label), tt(&r.state)].into_iter().chain(r.wealth).collect();
+        let color = if r.highlight { "\\rowcolor{gray!25} " } else { "" };
+        out.push_str(&format!("{}{} \\\\\n", color, cells.join(" & ")));
+    }
+    out.push_str("\\hline\n\\end{tabular}\n");
+    out
+}
+
+// A Markdown table; highlighted rows are in bold
+pub fn markdown(s0: &State, steps: &[(String, State)], f: &dyn Fn(&State, &Token) -> f64, options: &Options) -> String {
+    let (users, rows) = rows(s0, steps, f, options);
+    let code = |text: &str| if text.is_empty() { String::new() } else { format!("`{}`", text.replace('|', "\\|")) };
+    let header: Vec<String> = ["#", "Transaction", "State"].iter().map(|h| h.to_string())
+        .chain(users.iter().map(|u| format!("W({})", u)))
+        .collect();
+    let mut out = format!("| {} |\n|---:|---|---|{}\n", header.join(" | "), "---:|".repeat(users.len()));
+    for r in rows {
+        let cells: Vec<String> = [r.step, code(&r.label), code(&r.state)].into_iter()
+            .chain(r.wealth)
+            .map(|c| if r.highlight && !c.is_empty() { format!("**{}**", c) } else { c })
+            .collect();
+        out.push_str(&format!("| {} |\n", cells.join(" | ")));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{price_oracle, CreatePool, Curve, Deposit, Swap};
+
+    fn sandwich() -> (State, Vec<(String, State)>) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let (o, a, m) = (User::new("O"), User::new("A"), User::new("M"));
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 10.0);
+        s.set_balance(&o, &t1, 10.0);
+        s.set_balance(&a, &t0, 2.0);
+        s.set_balance(&m, &t0, 1.0);
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, 0.0).apply(&s).unwrap();
+        let txs: Vec<Box<dyn Transition>> = vec![
+            Box::new(Deposit::new(&o, 10.0, &t0, 10.0, &t1)),
+            Box::new(Swap::new(&m, &t0, &t1, 1.0)),
+            Box::new(Swap::new(&a, &t0, &t1, 2.0)),
+        ];
+        let steps = trace(&s, &txs).unwrap();
+        (s, steps)
+    }
+
+    #[test]
+    fn numbers_in_labels_are_rounded() {
+        assert_eq!(round_numbers("M:swap(t0,t1,9.478927)", 2), "M:swap(t0,t1,9.48)");
+        assert_eq!(round_numbers("O:dep(100:t0,1e-7:t1)", 1), "O:dep(100:t0,0.0:t1)");
+        assert_eq!(round_numbers("tick(10)", 3), "tick(10)");
+    }
+
+    #[test]
+    fn markdown_table() {
+        let (s0, steps) = sandwich();
+        let options = Options { precision: 1, attacker: Some(User::new("M")) };
+        let table = markdown(&s0, &steps, &|_, _| 1.0, &options);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[0], "| # | Transaction | State | W(O) | W(A) | W(M) |");
+        assert_eq!(lines[1], "|---:|---|---|---:|---:|---:|");
+        assert_eq!(lines[2], "| 0 |  | `O[10.0:t0,10.0:t1] \\| A[2.0:t0] \\| M[1.0:t0] \\| {0.0:t0 0.0:t1}` | 20.0 | 2.0 | 1.0 |");
+        assert!(lines[4].starts_with("| **2** | **`M:swap(t0,t1,1)`** |"));
+        assert!(!lines[5].contains("**"));
+    }
+
+    #[test]
+    fn latex_tabular() {
+        let (s0, steps) = sandwich();
+        let options = Options { precision: 2, attacker: Some(User::new("M")) };
+        let table = latex(&s0, &steps, &price_oracle, &options);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "\\begin{tabular}{rllrrr}");
+        assert_eq!(lines[2], " & Transaction & State & $W_{O}$ & $W_{A}$ & $W_{M}$ \\\\");
+        assert!(lines[6].starts_with("\\rowcolor{gray!25} 2 & \\texttt{M:swap(t0,t1,1)} & \\texttt{O[0.00:t0"));
+        assert!(lines[6].ends_with("& 20090.91 & 2000.00 & 909.09 \\\\"));
+        assert!(lines[6].contains("\\{11.00:t0 9.09:t1\\}"));
+        assert_eq!(lines.iter().filter(|l| l.contains("rowcolor")).count(), 1);
+        assert_eq!(*lines.last().unwrap(), "\\end{tabular}");
+    }
+}
@@ -0,0 +1,395 @@
+use std::fmt;
+
+use crate::{order_tokens, Curve, State, Token, Transition, TransitionError, User};
+
+// Long-term orders (TWAMM). An order sells x units of a token evenly over a
+// number of blocks, as virtual orders executed against the pool at every
+// block. Virtual orders are settled lazily: the blocks elapsed since the last
+// settlement are executed one after the other, with the reserves of that
+// time, right before the next transition touching the pool. In each block the
+// orders selling opposite tokens are first matched with each other at the
+// pool price, and only the rest trades against the curve; every input pays
+// the swap fee (the protocol fee is not taken on virtual orders).
+#[derive(Clone)]
+pub struct Order {
+    pub id: u64,
+    pub owner: User,
+    pub tin: Token,
+    pub tout: Token,
+    // units of tin sold at every block from start (included) to end (excluded)
+    pub rate: f64,
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Clone)]
+pub struct Twamm {
+    pub orders: Vec<Order>,
+    // the block up to which the orders of each pool were executed
+    pub settled: Vec<(Token, Token, u64)>,
+    pub next_id: u64,
+}
+
+impl Twamm {
+    pub fn new() -> Self {
+        Twamm { orders: Vec::new(), settled: Vec::new(), next_id: 0 }
+    }
+
+    pub fn get_order(&self, id: u64) -> Option<&Order> {
+        self.orders.iter().find(|o| o.id == id)
+    }
+
+    fn settled_block(&self, t0: &Token, t1: &Token) -> Option<u64> {
+        self.settled.iter().find(|(a, b, _)| a == t0 && b == t1).map(|(_, _, block)| *block)
+    }
+
+    fn set_settled(&mut self, t0: &Token, t1: &Token, block: u64) {
+        match self.settled.iter_mut().find(|(a, b, _)| a == t0 && b == t1) {
+            Some(entry) => entry.2 = block,
+            None => self.settled.push((t0.clone(), t1.clone(), block)),
+        }
+    }
+
+    // Units of the order still to be sold, after the last settlement
+    pub fn unsold(&self, order: &Order) -> f64 {
+        let (t0, t1) = order_tokens(&order.tin, &order.tout);
+        let settled = self.settled_block(t0, t1).unwrap_or(order.start).max(order.start);
+        order.rate * order.end.saturating_sub(settled) as f64
+    }
+
+    // Tokens held by the orders, not sold yet
+    pub fn get_escrow(&self, token: &Token) -> f64 {
+        self.orders.iter().filter(|o| o.tin == *token).map(|o| self.unsold(o)).sum()
+    }
+}
+
+impl Default for Twamm {
+    fn default() -> Self {
+        Twamm::new()
+    }
+}
+
+impl State {
+    // Executes the virtual orders of the pool of t0 and t1 up to the current
+    // block. Called before every transition touching the pool.
+    pub fn execute_virtual_orders(&mut self, t0: &Token, t1: &Token) {
+        let (t0, t1) = order_tokens(t0, t1);
+        let (t0, t1) = (t0.clone(), t1.clone());
+        // pools without orders have nothing to settle
+        let from = match self.twamm.settled_block(&t0, &t1) {
+            Some(block) => block,
+            None => return,
+        };
+        for block in from..self.block {
+            let active: Vec<Order> = self.twamm.orders.iter()
+                .filter(|o| order_tokens(&o.tin, &o.tout) == (&t0, &t1) && o.start <= block && block < o.end)
+                .cloned()
+                .collect();
+            if active.is_empty() {
+                continue;
+            }
+            self.execute_block(&t0, &t1, &active, block);
+            self.record_price_move_at(&t0, &t1, block);
+        }
+        self.twamm.set_settled(&t0, &t1, self.block);
+        // the fee of the current block counts the moves of the executed blocks
+        let block = self.block;
+        let amm = self.get_amm_mut(&t0, &t1).unwrap();
+        if let Some(df) = &amm.dynamic_fee {
+            amm.fee = df.fee_at(block);
+        }
+        self.twamm.orders.retain(|o| !(order_tokens(&o.tin, &o.tout) == (&t0, &t1) && o.end <= block));
+    }
+
+    // One block of the active orders of the pool: opposite flows are matched
+    // at the pool price, the net flow trades along the curve, paying the fee
+    // of that block
+    fn execute_block(&mut self, t0: &Token, t1: &Token, active: &[Order], block: u64) {
+        let amm = self.get_amm(t0, t1).unwrap();
+        let (r0, r1) = (amm.r0, amm.r1);
+        if r0 <= 0.0 || r1 <= 0.0 {
+            return;
+        }
+        let fee = amm.dynamic_fee.as_ref().map_or(amm.fee, |df| df.fee_at(block));
+        let g = 1.0 - fee;
+        let curve = amm.curve;
+        let total0: f64 = active.iter().filter(|o| o.tin == *t0).map(|o| o.rate).sum();
+        let total1: f64 = active.iter().filter(|o| o.tin == *t1).map(|o| o.rate).sum();
+        let (x, y) = (total0 * g, total1 * g);
+        let price = match curve {
+            Curve::ConstantProduct => r1 / r0,
+            Curve::ConstantSum => 1.0,
+        };
+        let along = |r_in: f64, r_out: f64, d: f64| match curve {
+            Curve::ConstantProduct => r_out * d / (r_in + d),
+            Curve::ConstantSum => d.min(r_out),
+        };
+        // what the sellers of t0 and of t1 receive in total
+        let (out1, out0) = if x * price >= y {
+            let d = x - y / price;
+            (y + along(r0, r1, d), y / price)
+        } else {
+            let d = y - x * price;
+            (x * price, x + along(r1, r0, d))
+        };
+        self.set_reserve(t0, r0 + total0 - out0, t1, r1 + total1 - out1);
+        for o in active {
+            let share = if o.tin == *t0 { out1 * o.rate / total0 } else { out0 * o.rate / total1 };
+            let balance = self.get_balance(&o.owner, &o.tout);
+            self.set_balance(&o.owner, &o.tout, balance + share);
+        }
+    }
+
+    // The state with the virtual orders of every pool executed
+    pub fn with_virtual_orders_executed(&self) -> State {
+        let mut s = self.clone();
+        let pools: Vec<(Token, Token)> = s.amms.iter().map(|a| (a.t0.clone(), a.t1.clone())).collect();
+        for (t0, t1) in pools {
+            s.execute_virtual_orders(&t0, &t1);
+        }
+        s
+    }
+
+    // Unsold tokens of the orders of a user, valued with the given price function
+    pub fn order_value(&self, user: &User, f: &dyn Fn(&State, &Token) -> f64) -> f64 {
+        self.twamm.orders.iter().filter(|o| o.owner == *user).map(|o| self.twamm.unsold(o) * f(self, &o.tin)).sum()
+    }
+
+    fn has_unexecuted_orders(&self) -> bool {
+        self.twamm.orders.iter().any(|o| {
+            let (t0, t1) = order_tokens(&o.tin, &o.tout);
+            self.twamm.settled_block(t0, t1).is_some_and(|b| b < self.block && b < o.end)
+        })
+    }
+}
+
+// Net wealth counts the proceeds of the virtual orders up to the current block
+pub(crate) fn settled_view(s: &State) -> Option<State> {
+    if s.has_unexecuted_orders() { Some(s.with_virtual_orders_executed()) } else { None }
+}
+
+// Sells x units of tin for tout evenly over the given number of blocks,
+// starting from the current one
+pub struct LongTermSwap {
+    sender: User,
+    tin: Token,
+    tout: Token,
+    x: f64,
+    blocks: u64,
+}
+
+impl LongTermSwap {
+    pub fn new(sender: &User, tin: &Token, tout: &Token, x: f64, blocks: u64) -> Self {
+        assert!(x > 0.0 && blocks > 0);
+        LongTermSwap { sender: sender.clone(), tin: tin.clone(), tout: tout.clone(), x, blocks }
+    }
+}
+
+impl fmt::Display for LongTermSwap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:twamm({},{},{},{})", self.sender, self.tin, self.tout, self.x, self.blocks)
+    }
+}
+
+impl Transition for LongTermSwap {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        let amm = post.get_amm(&self.tin, &self.tout).ok_or(TransitionError::UnknownPool)?;
+        // an empty pool could not execute the order, which would keep the escrow
+        if amm.r0 <= 0.0 || amm.r1 <= 0.0 {
+            return Err(TransitionError::InsufficientReserves);
+        }
+        let balance = post.get_balance(&self.sender, &self.tin);
+        if balance < self.x {
+            return Err(TransitionError::InsufficientBalance);
+        }
+        post.execute_virtual_orders(&self.tin, &self.tout);
+        let (t0, t1) = order_tokens(&self.tin, &self.tout);
+        post.twamm.set_settled(t0, t1, post.block);
+        post.set_balance(&self.sender, &self.tin, balance - self.x);
+        let id = post.twamm.next_id;
+        post.twamm.next_id += 1;
+        post.twamm.orders.push(Order {
+            id,
+            owner: self.sender.clone(),
+            tin: self.tin.clone(),
+            tout: self.tout.clone(),
+            rate: self.x / self.blocks as f64,
+            start: post.block,
+            end: post.block + self.blocks,
+        });
+        Ok(post)
+    }
+}
+
+// Cancels an order of the sender, refunding the part not sold yet
+pub struct CancelOrder {
+    sender: User,
+    id: u64,
+}
+
+impl CancelOrder {
+    pub fn new(sender: &User, id: u64) -> Self {
+        CancelOrder { sender: sender.clone(), id }
+    }
+}
+
+impl fmt::Display for CancelOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:cancel({})", self.sender, self.id)
+    }
+}
+
+impl Transition for CancelOrder {
+    fn apply(&self, pre: &State) -> Result<State, TransitionError> {
+        let mut post = pre.clone();
+        let order = match pre.twamm.get_order(self.id) {
+            Some(o) if o.owner == self.sender => o.clone(),
+            _ => return Err(TransitionError::UnknownOrder),
+        };
+        post.execute_virtual_orders(&order.tin, &order.tout);
+        let refund = match post.twamm.get_order(self.id) {
+            Some(o) => post.twamm.unsold(o),
+            None => 0.0,
+        };
+        post.twamm.orders.retain(|o| o.id != self.id);
+        let balance = post.get_balance(&self.sender, &order.tin);
+        post.set_balance(&self.sender, &order.tin, balance + refund);
+        Ok(post)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dynamic_fee::SetDynamicFee;
+    use crate::{price_oracle, AdvanceBlock, CreatePool, Deposit, Swap};
+
+    fn setup(fee: f64) -> (State, Token, Token) {
+        let t0 = Token::Atomic(String::from("t0"));
+        let t1 = Token::Atomic(String::from("t1"));
+        let o = User::new("O");
+        let mut s = State::new();
+        s.set_balance(&o, &t0, 1000.0);
+        s.set_balance(&o, &t1, 1000.0);
+        for name in ["A", "B", "M"] {
+            s.set_balance(&User::new(name), &t0, 100.0);
+            s.set_balance(&User::new(name), &t1, 100.0);
+        }
+        let s = CreatePool::new(&t0, &t1, Curve::ConstantProduct, fee).apply(&s).unwrap();
+        let s = Deposit::new(&o, 1000.0, &t0, 1000.0, &t1).apply(&s).unwrap();
+        (s, t0, t1)
+    }
+
+    #[test]
+    fn orders_execute_lazily_over_the_blocks() {
+        let (s, t0, t1) = setup(0.0);
+        let a = User::new("A");
+        let post = LongTermSwap::new(&a, &t0, &t1, 100.0, 10).apply(&s).unwrap();
+        assert_eq!(post.token_supply(&t0), s.token_supply(&t0));
+        let post = AdvanceBlock::new(4).apply(&post).unwrap();
+        // nothing moved until the pool is touched, but wealth counts the proceeds
+        assert_eq!(post.get_reserves(&t0, &t1), 1000.0);
+        let touched = post.with_virtual_orders_executed();
+        assert!((touched.get_reserves(&t0, &t1) - 1040.0).abs() < 1e-9);
+        assert!((post.net_wealth_user(&a, &price_oracle) - touched.net_wealth_user(&a, &price_oracle)).abs() < 1e-9);
+        // and so do the quotes
+        assert_eq!(post.spot_price(&t0, &t1), touched.spot_price(&t0, &t1));
+        assert_eq!(post.quote_out(&t1, &t0, 10.0), touched.quote_out(&t1, &t0, 10.0));
+
+        // with no fee, the pieces add up to one swap of the whole amount
+        let post = AdvanceBlock::new(10).apply(&post).unwrap();
+        let post = Swap::new(&User::new("B"), &t1, &t0, 1.0).apply(&post).unwrap();
+        let whole = Swap::new(&a, &t0, &t1, 100.0).apply(&s).unwrap();
+        let b = Swap::new(&User::new("B"), &t1, &t0, 1.0).apply(&whole).unwrap();
+        assert!((post.get_balance(&a, &t1) - b.get_balance(&a, &t1)).abs() < 1e-9);
+        assert!(post.twamm.orders.is_empty());
+    }
+
+    #[test]
+    fn lazy_execution_moves_the_dynamic_fee_as_eager_execution() {
+        let (s, t0, t1) = setup(0.003);
+        let a = User::new("A");
+        let s = SetDynamicFee::new(&t0, &t1, 0.001, 0.05, 1.0, 10).apply(&s).unwrap();
+        let s = LongTermSwap::new(&a, &t0, &t1, 100.0, 10).apply(&s).unwrap();
+        let lazy = AdvanceBlock::new(10).apply(&s).unwrap().with_virtual_orders_executed();
+        let mut eager = s.clone();
+        for _ in 0..10 {
+            eager = AdvanceBlock::new(1).apply(&eager).unwrap().with_virtual_orders_executed();
+        }
+        assert!((lazy.get_balance(&a, &t1) - eager.get_balance(&a, &t1)).abs() < 1e-9);
+        // every executed block left its bar, and the fee rose with the moves
+        let (amm, eager_amm) = (lazy.get_amm(&t0, &t1).unwrap(), eager.get_amm(&t0, &t1).unwrap());
+        let bars: Vec<u64> = amm.dynamic_fee.as_ref().unwrap().history.iter().map(|b| b.block).collect();
+        assert_eq!(bars, (s.block..s.block + 10).collect::<Vec<u64>>());
+        assert!(amm.fee > 0.001 && (amm.fee - eager_amm.fee).abs() < 1e-12);
+    }
+
+    #[test]
+    fn orders_conserve_the_tokens() {
+        let (s, t0, t1) = setup(0.003);
+        let a = User::new("A");
+        let post = LongTermSwap::new(&a, &t0, &t1, 100.0, 10).apply(&s).unwrap();
+        let post = AdvanceBlock::new(4).apply(&post).unwrap();
+        for t in [&t0, &t1] {
+            assert!((post.token_supply(t) - s.token_supply(t)).abs() < 1e-9);
+            let settled = post.with_virtual_orders_executed();
+            assert!((settled.token_supply(t) - s.token_supply(t)).abs() < 1e-9);
+        }
+        // no order on a pool that has no liquidity to trade against
+        let t2 = Token::Atomic(String::from("t2"));
+        let empty = CreatePool::new(&t0, &t2, Curve::ConstantProduct, 0.003).apply(&s).unwrap();
+        assert!(matches!(LongTermSwap::new(&a, &t0, &t2, 10.0, 10).apply(&empty), Err(TransitionError::InsufficientReserves)));
+    }
+
+    #[test]
+    fn opposite_orders_are_matched_and_cancellation_refunds() {
+        let (s, t0, t1) = setup(0.003);
+        let (a, b) = (User::new("A"), User::new("B"));
+        let s = LongTermSwap::new(&a, &t0, &t1, 50.0, 10).apply(&s).unwrap();
+        let s = LongTermSwap::new(&b, &t1, &t0, 50.0, 10).apply(&s).unwrap();
+        let s = AdvanceBlock::new(5).apply(&s).unwrap();
+        let s = CancelOrder::new(&a, 0).apply(&s).unwrap();
+        // half was sold, half refunded
+        assert!((s.get_balance(&a, &t0) - 75.0).abs() < 1e-9);
+        // matched at the pool price, the flows only left the fees in the pool
+        assert!((s.get_balance(&a, &t1) - (100.0 + 25.0 * 0.997)).abs() < 1e-9);
+        assert!((s.get_reserves(&t0, &t1) - (1000.0 + 25.0 * 0.003)).abs() < 1e-9);
+        assert!(matches!(CancelOrder::new(&a, 1).apply(&s), Err(TransitionError::UnknownOrder)));
+        assert!(matches!(CancelOrder::new(&a, 0).apply(&s), Err(TransitionError::UnknownOrder)));
+    }
+
+    #[test]
+    fn sandwiching_a_long_term_order_pays_less() {
+        let (s, t0, t1) = setup(0.003);
+        let (a, b, m) = (User::new("A"), User::new("B"), User::new("M"));
+        let gain = |post: &State| post.net_wealth_user(&m, &price_oracle) - s.net_wealth_user(&m, &price_oracle);
+        // M front-runs with 50 t0 and sells back what it got
+        let front = |s: &State| -> (State, f64) {
+            let out = s.quote_out(&t0, &t1, 50.0).unwrap();
+            (Swap::new(&m, &t0, &t1, 50.0).apply(s).unwrap(), out)
+        };
+        // one big swap
+        let (post, out) = front(&s);
+        let post = Swap::new(&a, &t0, &t1, 100.0).apply(&post).unwrap();
+        let big = gain(&Swap::new(&m, &t1, &t0, out).apply(&post).unwrap());
+        // the same attack around every block of an order over 10 blocks: the
+        // front-run ends a block and the back-run starts the next one
+        let order = LongTermSwap::new(&a, &t0, &t1, 100.0, 10).apply(&s).unwrap();
+        let (mut held, mut raced) = (order.clone(), order);
+        for _ in 0..10 {
+            let (post, out) = front(&held);
+            let post = AdvanceBlock::new(1).apply(&post).unwrap();
+            held = Swap::new(&m, &t1, &t0, out).apply(&post).unwrap();
+            // unless an arbitrageur is first in the next block
+            let (post, out) = front(&raced);
+            let post = AdvanceBlock::new(1).apply(&post).unwrap();
+            let (tin, x) = post.amount_to_price(&t0, &t1, 1.0).unwrap();
+            let tout = if tin == t0 { &t1 } else { &t0 };
+            let post = Swap::new(&b, &tin, tout, x).apply(&post).unwrap();
+            raced = Swap::new(&m, &t1, &t0, out).apply(&post).unwrap();
+        }
+        assert!(big > 0.0 && gain(&held) < 0.7 * big);
+        assert!(gain(&raced) < 0.0);
+    }
+}